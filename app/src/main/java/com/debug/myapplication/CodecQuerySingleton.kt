@@ -8,6 +8,11 @@ import android.media.MediaCodecList
 import android.os.Build
 import android.util.Log
 
+// Must match `DecoderPreference` on the native side
+const val DECODER_PREFER_HARDWARE = 0
+const val DECODER_PREFER_SOFTWARE = 1
+const val DECODER_FORCE_SOFTWARE = 2
+
 object CodecQuerySingleton {
     private val softwareDecoderPattern = "OMX.(google|SEC)".toRegex()
 
@@ -72,7 +77,7 @@ object CodecQuerySingleton {
         }
     }
 
-    fun chooseDecoderForType(mimeType: String): String? {
+    fun chooseDecoderForType(mimeType: String, decoderPreference: Int): String? {
         val preference: MutableMap<Int, Int> = when (mimeType) {
             "video/av01" -> av1ProfilePreference
             "video/hevc" -> hevcProfilePreference
@@ -90,6 +95,9 @@ object CodecQuerySingleton {
             } else {
                 !softwareDecoderPattern.containsMatchIn(decoderInfo.name)
             }
+            if (decoderPreference == DECODER_FORCE_SOFTWARE && isHardwareAccelerated) {
+                continue
+            }
             val isLowLatency = Build.VERSION.SDK_INT >= 30 && capabilities.isFeatureSupported(
                 FEATURE_LowLatency
             )
@@ -103,16 +111,29 @@ object CodecQuerySingleton {
             }
         }
 
-        // Prefer decoders with low latency and is hardware accel., and sort by profile pref.
-        entries.sortWith(
-            compareBy({ !it.isLowLatency },
-                { !it.isHardwareAccelerated },
-                { preference[it.profile] ?: Int.MAX_VALUE })
-        )
+        if (decoderPreference == DECODER_PREFER_HARDWARE) {
+            // Prefer decoders with low latency and is hardware accel., and sort by profile pref.
+            entries.sortWith(
+                compareBy({ !it.isLowLatency },
+                    { !it.isHardwareAccelerated },
+                    { preference[it.profile] ?: Int.MAX_VALUE })
+            )
+        } else {
+            // Software decoders first, then the same ordering as above
+            entries.sortWith(
+                compareBy({ it.isHardwareAccelerated },
+                    { !it.isLowLatency },
+                    { preference[it.profile] ?: Int.MAX_VALUE })
+            )
+        }
 
         return entries.firstOrNull()?.name
     }
 
+    fun decoderSupportsType(decoderName: String, mimeType: String): Boolean {
+        return listDecodersForType(mimeType).any { it.name == decoderName }
+    }
+
     fun listProfilesForDecoder(decoderName: String, mimeType: String): List<Int>? {
         val preference: MutableMap<Int, Int> = when (mimeType) {
             "video/av01" -> av1ProfilePreference
@@ -136,7 +157,7 @@ object CodecQuerySingleton {
         val mimeTypes = listOf("video/av01", "video/hevc", "video/avc")
 
         for (mimeType in mimeTypes) {
-            val decoderName = chooseDecoderForType(mimeType)
+            val decoderName = chooseDecoderForType(mimeType, DECODER_PREFER_HARDWARE)
             Log.i("client-android", "$mimeType $decoderName")
         }
     }
@@ -12,12 +12,20 @@ import androidx.preference.PreferenceManager
 import com.debug.myapplication.databinding.ActivityMainBinding
 
 const val FIRST_RUN = "first_run"
+const val DECODER_PREFERENCE = "decoder_preference"
+const val KEYFRAME_STRATEGY = "keyframe_strategy"
+const val RENDER_POLICY = "render_policy"
+const val SIGNALING_TLS = "signaling_tls"
+const val PINNED_CERTIFICATE = "pinned_certificate"
+const val STRIP_SEI = "strip_sei"
+const val DIAGNOSTICS_LOGGING = "diagnostics_logging"
 
 class MainActivity : AppCompatActivity() {
     private lateinit var binding: ActivityMainBinding
 
     override fun onCreate(savedInstanceState: Bundle?) {
         super.onCreate(savedInstanceState)
+        NativeLibSingleton.create()
 
         binding = ActivityMainBinding.inflate(layoutInflater)
         setContentView(binding.root)
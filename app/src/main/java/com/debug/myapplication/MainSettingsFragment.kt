@@ -1,10 +1,32 @@
 package com.debug.myapplication
 
 import android.os.Bundle
+import android.widget.Toast
+import androidx.preference.EditTextPreference
 import androidx.preference.PreferenceFragmentCompat
 
 class MainSettingsFragment : PreferenceFragmentCompat() {
     override fun onCreatePreferences(savedInstanceState: Bundle?, rootKey: String?) {
         setPreferencesFromResource(R.xml.preferences, rootKey)
+
+        findPreference<EditTextPreference>(PINNED_CERTIFICATE)
+            ?.setOnPreferenceChangeListener { _, newValue ->
+                val valid = isValidFingerprint(newValue as String)
+                if (!valid) {
+                    Toast.makeText(
+                        context,
+                        "Expected a SHA-256 fingerprint of 64 hex digits",
+                        Toast.LENGTH_SHORT
+                    ).show()
+                }
+                valid
+            }
+    }
+
+    // Empty clears the pin, colons are allowed like `openssl` and `keytool` print them
+    private fun isValidFingerprint(value: String): Boolean {
+        val digits = value.trim().replace(":", "")
+        return digits.isEmpty() ||
+            (digits.length == 64 && digits.all { it in '0'..'9' || it.lowercaseChar() in 'a'..'f' })
     }
 }
@@ -1,15 +1,49 @@
 package com.debug.myapplication
 
+import android.app.PictureInPictureParams
+import android.content.ClipData
+import android.content.ClipboardManager
+import android.content.res.Configuration
+import android.graphics.Bitmap
+import android.os.Build
 import android.os.Bundle
+import android.util.Rational
+import android.view.InputDevice
+import android.view.KeyEvent
+import android.view.MotionEvent
 import android.view.SurfaceHolder
+import android.view.View
+import android.widget.Toast
 import androidx.appcompat.app.AppCompatActivity
 import androidx.constraintlayout.widget.ConstraintSet
+import androidx.preference.PreferenceManager
 import com.debug.myapplication.databinding.ActivityStreamingBinding
 
+// Limits of `PictureInPictureParams.Builder.setAspectRatio`
+private const val MIN_PIP_ASPECT_RATIO = 1f / 2.39f
+private const val MAX_PIP_ASPECT_RATIO = 2.39f
+
 class MediaPlayerActivity : AppCompatActivity() {
 
     private lateinit var binding: ActivityStreamingBinding
     private val layoutConstraints: ConstraintSet = ConstraintSet()
+    private lateinit var clipboard: ClipboardManager
+    // Text last received from the host, kept so that it is not echoed back
+    private var remoteClipboardText: String? = null
+    private var videoAspectRatio: Rational? = null
+    // Hotspot of the remote cursor image in pixels and its position as a fraction of the video
+    private var cursorHotspotX = 0
+    private var cursorHotspotY = 0
+    private var cursorX = 0f
+    private var cursorY = 0f
+
+    private val clipboardListener = ClipboardManager.OnPrimaryClipChangedListener {
+        val text = clipboard.primaryClip?.takeIf { it.itemCount > 0 }
+            ?.getItemAt(0)?.coerceToText(this)?.toString()
+        if (text != null && text != remoteClipboardText) {
+            NativeLibSingleton.sendClipboard(text)
+        }
+    }
 
     override fun onCreate(savedInstanceState: Bundle?) {
         super.onCreate(savedInstanceState)
@@ -19,6 +53,10 @@ class MediaPlayerActivity : AppCompatActivity() {
         layoutConstraints.clone(binding.root)
 
         binding.surfaceView.keepScreenOn = true;
+        // The cursor position is relative to the video, which is resized along with the window
+        binding.surfaceView.addOnLayoutChangeListener { _, _, _, _, _, _, _, _, _ ->
+            placeRemoteCursor()
+        }
 
         binding.surfaceView.holder.addCallback(object: SurfaceHolder.Callback {
             override fun surfaceCreated(holder: SurfaceHolder) {
@@ -32,18 +70,169 @@ class MediaPlayerActivity : AppCompatActivity() {
             }
         })
 
+        clipboard = getSystemService(CLIPBOARD_SERVICE) as ClipboardManager
+        clipboard.addPrimaryClipChangedListener(clipboardListener)
+
+        val sharedPreferences = PreferenceManager.getDefaultSharedPreferences(this)
+        val decoderPreference = sharedPreferences.getString(DECODER_PREFERENCE, null)
+            ?.toIntOrNull() ?: DECODER_PREFER_HARDWARE
+        NativeLibSingleton.setDecoderPreference(decoderPreference)
+        val keyframeStrategy = sharedPreferences.getString(KEYFRAME_STRATEGY, null)
+            ?.toIntOrNull() ?: KEYFRAME_REQUEST_PLI_THEN_FIR
+        NativeLibSingleton.setKeyframeStrategy(keyframeStrategy)
+        val renderPolicy = sharedPreferences.getString(RENDER_POLICY, null)
+            ?.toIntOrNull() ?: RENDER_POLICY_LOW_LATENCY
+        NativeLibSingleton.setRenderPolicy(renderPolicy)
+        NativeLibSingleton.setSignalingSecurity(
+            sharedPreferences.getBoolean(SIGNALING_TLS, false),
+            sharedPreferences.getString(PINNED_CERTIFICATE, null)
+        )
+        NativeLibSingleton.setStripSei(sharedPreferences.getBoolean(STRIP_SEI, false))
+        NativeLibSingleton.setDiagnosticsLogging(
+            sharedPreferences.getBoolean(DIAGNOSTICS_LOGGING, false)
+        )
+
         NativeLibSingleton.mediaPlayerCreated(this)
     }
 
     override fun onDestroy() {
         super.onDestroy()
+        clipboard.removePrimaryClipChangedListener(clipboardListener)
         NativeLibSingleton.mediaPlayerDestroyed()
     }
 
+    override fun onWindowFocusChanged(hasFocus: Boolean) {
+        super.onWindowFocusChanged(hasFocus)
+        // The picture-in-picture window never has focus but is still meant to be watched
+        NativeLibSingleton.setWindowFocus(hasFocus || isInPipMode())
+    }
+
+    override fun onUserLeaveHint() {
+        super.onUserLeaveHint()
+        if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.O) {
+            val params = PictureInPictureParams.Builder()
+            // Ratios outside of what the system allows make `enterPictureInPictureMode` throw
+            videoAspectRatio?.takeIf { it.toFloat() in MIN_PIP_ASPECT_RATIO..MAX_PIP_ASPECT_RATIO }
+                ?.let { params.setAspectRatio(it) }
+            enterPictureInPictureMode(params.build())
+        }
+    }
+
+    override fun onPictureInPictureModeChanged(
+        isInPictureInPictureMode: Boolean,
+        newConfig: Configuration
+    ) {
+        super.onPictureInPictureModeChanged(isInPictureInPictureMode, newConfig)
+        // Too small to be of use
+        binding.cursorView.visibility = if (isInPictureInPictureMode) View.GONE else View.VISIBLE
+        if (isInPictureInPictureMode) {
+            NativeLibSingleton.setWindowFocus(true)
+        }
+
+        // Send the resized surface so that the decoder switches to it and reapplies the aspect
+        // ratio to the new window
+        val surface = binding.surfaceView.holder.surface
+        if (surface.isValid) {
+            NativeLibSingleton.mediaPlayerSurfaceCreated(surface)
+        }
+    }
+
+    override fun onGenericMotionEvent(event: MotionEvent): Boolean {
+        if (event.isFromSource(InputDevice.SOURCE_JOYSTICK) &&
+            event.action == MotionEvent.ACTION_MOVE) {
+            NativeLibSingleton.sendGamepadAxes(floatArrayOf(
+                event.getAxisValue(MotionEvent.AXIS_X),
+                event.getAxisValue(MotionEvent.AXIS_Y),
+                event.getAxisValue(MotionEvent.AXIS_Z),
+                event.getAxisValue(MotionEvent.AXIS_RZ),
+                // Some controllers report the triggers as brake and gas instead
+                maxOf(event.getAxisValue(MotionEvent.AXIS_LTRIGGER),
+                    event.getAxisValue(MotionEvent.AXIS_BRAKE)),
+                maxOf(event.getAxisValue(MotionEvent.AXIS_RTRIGGER),
+                    event.getAxisValue(MotionEvent.AXIS_GAS)),
+                event.getAxisValue(MotionEvent.AXIS_HAT_X),
+                event.getAxisValue(MotionEvent.AXIS_HAT_Y)
+            ))
+            return true
+        }
+        return super.onGenericMotionEvent(event)
+    }
+
+    override fun onKeyDown(keyCode: Int, event: KeyEvent): Boolean {
+        if (isGamepadKey(keyCode, event)) {
+            if (event.repeatCount == 0) {
+                NativeLibSingleton.sendGamepadButton(keyCode, true)
+            }
+            return true
+        }
+        return super.onKeyDown(keyCode, event)
+    }
+
+    override fun onKeyUp(keyCode: Int, event: KeyEvent): Boolean {
+        if (isGamepadKey(keyCode, event)) {
+            NativeLibSingleton.sendGamepadButton(keyCode, false)
+            return true
+        }
+        return super.onKeyUp(keyCode, event)
+    }
+
+    private fun isGamepadKey(keyCode: Int, event: KeyEvent): Boolean {
+        val fromGamepad = event.isFromSource(InputDevice.SOURCE_GAMEPAD) ||
+            event.isFromSource(InputDevice.SOURCE_JOYSTICK)
+        val isDpad = keyCode == KeyEvent.KEYCODE_DPAD_UP ||
+            keyCode == KeyEvent.KEYCODE_DPAD_DOWN ||
+            keyCode == KeyEvent.KEYCODE_DPAD_LEFT ||
+            keyCode == KeyEvent.KEYCODE_DPAD_RIGHT
+        return fromGamepad && (KeyEvent.isGamepadButton(keyCode) || isDpad)
+    }
+
+    private fun isInPipMode(): Boolean {
+        return Build.VERSION.SDK_INT >= Build.VERSION_CODES.N && isInPictureInPictureMode
+    }
+
     fun setSurfaceViewAspectRatio(width: Int, height: Int) {
         this@MediaPlayerActivity.runOnUiThread {
+            videoAspectRatio = Rational(width, height)
             layoutConstraints.setDimensionRatio(binding.surfaceView.id, "$width:$height")
             layoutConstraints.applyTo(binding.root)
         }
     }
+
+    fun setRemoteCursor(bitmap: Bitmap, hotspotX: Int, hotspotY: Int) {
+        this@MediaPlayerActivity.runOnUiThread {
+            cursorHotspotX = hotspotX
+            cursorHotspotY = hotspotY
+            placeRemoteCursor()
+            binding.cursorView.setImageBitmap(bitmap)
+            binding.cursorView.visibility = View.VISIBLE
+        }
+    }
+
+    fun setRemoteCursorPosition(x: Float, y: Float) {
+        this@MediaPlayerActivity.runOnUiThread {
+            cursorX = x
+            cursorY = y
+            placeRemoteCursor()
+        }
+    }
+
+    private fun placeRemoteCursor() {
+        // Offset the image so that the hotspot sits on the cursor position
+        binding.cursorView.translationX = cursorX * binding.surfaceView.width - cursorHotspotX
+        binding.cursorView.translationY = cursorY * binding.surfaceView.height - cursorHotspotY
+    }
+
+    fun setClipboardText(text: String) {
+        this@MediaPlayerActivity.runOnUiThread {
+            remoteClipboardText = text
+            clipboard.setPrimaryClip(ClipData.newPlainText("Remote clipboard", text))
+        }
+    }
+
+    fun showConnectionError(message: String) {
+        this@MediaPlayerActivity.runOnUiThread {
+            Toast.makeText(this, message, Toast.LENGTH_LONG).show()
+            finish()
+        }
+    }
 }
\ No newline at end of file
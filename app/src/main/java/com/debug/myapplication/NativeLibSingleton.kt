@@ -1,7 +1,18 @@
 package com.debug.myapplication
 
+import android.graphics.Bitmap
 import android.os.Build
 import android.view.Surface
+import java.nio.ByteBuffer
+
+// Match `KeyframeStrategy` in the native code
+const val KEYFRAME_REQUEST_PLI = 0
+const val KEYFRAME_REQUEST_FIR = 1
+const val KEYFRAME_REQUEST_PLI_THEN_FIR = 2
+
+// Match `RenderPolicy` in the native code
+const val RENDER_POLICY_LOW_LATENCY = 0
+const val RENDER_POLICY_SMOOTH = 1
 
 object NativeLibSingleton {
     private var nativeInstance: Long = 0
@@ -17,17 +28,76 @@ object NativeLibSingleton {
     @JvmName("destroySurface")
     private external fun destroySurface(nativeInstance: Long)
 
+    @JvmName("setVideoRotation")
+    private external fun setVideoRotation(nativeInstance: Long, degrees: Int)
+
+    @JvmName("setWindowFocus")
+    private external fun setWindowFocus(nativeInstance: Long, hasFocus: Boolean)
+
+    @JvmName("setDecoderPreference")
+    private external fun setDecoderPreference(nativeInstance: Long, preference: Int)
+
+    @JvmName("setKeyframeStrategy")
+    private external fun setKeyframeStrategy(nativeInstance: Long, strategy: Int)
+
+    @JvmName("setRenderPolicy")
+    private external fun setRenderPolicy(nativeInstance: Long, policy: Int)
+
+    @JvmName("setSignalingSecurity")
+    private external fun setSignalingSecurity(
+        nativeInstance: Long,
+        useTls: Boolean,
+        pinnedSha256: String?
+    )
+
+    @JvmName("setStripSei")
+    private external fun setStripSei(nativeInstance: Long, strip: Boolean)
+
+    @JvmName("setDiagnosticsLogging")
+    private external fun setDiagnosticsLogging(nativeInstance: Long, enabled: Boolean)
+
+    @JvmName("sendClipboard")
+    private external fun sendClipboard(nativeInstance: Long, text: String)
+
+    @JvmName("sendGamepadButton")
+    private external fun sendGamepadButton(nativeInstance: Long, keyCode: Int, pressed: Boolean)
+
+    @JvmName("sendGamepadAxes")
+    private external fun sendGamepadAxes(nativeInstance: Long, axes: FloatArray)
+
+    @JvmName("getRenderedFrameCount")
+    private external fun getRenderedFrameCount(nativeInstance: Long): Long
+
+    @JvmName("getDecoderName")
+    private external fun getDecoderName(nativeInstance: Long): String?
+
+    @JvmName("getNegotiatedCodec")
+    private external fun getNegotiatedCodec(nativeInstance: Long): String?
+
+    @JvmName("getConnectionQuality")
+    private external fun getConnectionQuality(nativeInstance: Long): Int
+
     @JvmName("startMediaPlayer")
     private external fun startMediaPlayer(nativeInstance: Long)
+    @JvmName("restartMediaPlayer")
+    private external fun restartMediaPlayer(nativeInstance: Long)
 
     init {
         System.loadLibrary("client_android")
         nativeInstance = createNativeInstance()
     }
 
+    // The object outlives the activity, so a recreated activity needs a new native instance
+    fun create() {
+        if (nativeInstance == 0L) {
+            nativeInstance = createNativeInstance()
+        }
+    }
+
     fun destroy() {
         if (nativeInstance != 0L) {
             destroyNativeInstance(nativeInstance)
+            nativeInstance = 0
         }
     }
 
@@ -36,10 +106,15 @@ object NativeLibSingleton {
         startMediaPlayer(nativeInstance)
     }
 
+    fun restartMediaPlayer() {
+        restartMediaPlayer(nativeInstance)
+    }
+
     fun mediaPlayerDestroyed() {
         mediaPlayerActivity = null
     }
 
+    // Any Surface works, including one created from a SurfaceTexture for compositing
     fun mediaPlayerSurfaceCreated(surface: Surface) {
         sendSurface(nativeInstance, surface)
     }
@@ -48,13 +123,101 @@ object NativeLibSingleton {
         destroySurface(nativeInstance)
     }
 
+    fun setVideoRotation(degrees: Int) {
+        setVideoRotation(nativeInstance, degrees)
+    }
+
+    fun setWindowFocus(hasFocus: Boolean) {
+        setWindowFocus(nativeInstance, hasFocus)
+    }
+
+    fun setDecoderPreference(preference: Int) {
+        setDecoderPreference(nativeInstance, preference)
+    }
+
+    fun setKeyframeStrategy(strategy: Int) {
+        setKeyframeStrategy(nativeInstance, strategy)
+    }
+
+    fun setRenderPolicy(policy: Int) {
+        setRenderPolicy(nativeInstance, policy)
+    }
+
+    fun setSignalingSecurity(useTls: Boolean, pinnedSha256: String?) {
+        setSignalingSecurity(nativeInstance, useTls, pinnedSha256)
+    }
+
+    fun setStripSei(strip: Boolean) {
+        setStripSei(nativeInstance, strip)
+    }
+
+    fun setDiagnosticsLogging(enabled: Boolean) {
+        setDiagnosticsLogging(nativeInstance, enabled)
+    }
+
+    fun sendClipboard(text: String) {
+        sendClipboard(nativeInstance, text)
+    }
+
+    fun sendGamepadButton(keyCode: Int, pressed: Boolean) {
+        sendGamepadButton(nativeInstance, keyCode, pressed)
+    }
+
+    // Left stick x and y, right stick x and y, left and right trigger, hat x and y
+    fun sendGamepadAxes(axes: FloatArray) {
+        sendGamepadAxes(nativeInstance, axes)
+    }
+
+    fun getRenderedFrameCount(): Long {
+        return getRenderedFrameCount(nativeInstance)
+    }
+
+    fun getDecoderName(): String? {
+        return getDecoderName(nativeInstance)
+    }
+
+    fun getNegotiatedCodec(): String? {
+        return getNegotiatedCodec(nativeInstance)
+    }
+
+    fun getConnectionQuality(): Int {
+        return getConnectionQuality(nativeInstance)
+    }
+
     // Called by native code
     private fun setMediaPlayerAspectRatio(width: Int, height: Int) {
         mediaPlayerActivity?.setSurfaceViewAspectRatio(width, height)
     }
 
-    fun chooseDecoderForType(mimeType: String): String? {
-        return CodecQuerySingleton.chooseDecoderForType(mimeType)
+    // Called by native code
+    private fun setRemoteCursor(hotspotX: Int, hotspotY: Int, width: Int, height: Int, rgba: ByteArray) {
+        // `ARGB_8888` is stored as RGBA in memory
+        val bitmap = Bitmap.createBitmap(width, height, Bitmap.Config.ARGB_8888)
+        bitmap.copyPixelsFromBuffer(ByteBuffer.wrap(rgba))
+        mediaPlayerActivity?.setRemoteCursor(bitmap, hotspotX, hotspotY)
+    }
+
+    // Called by native code
+    private fun setRemoteCursorPosition(x: Float, y: Float) {
+        mediaPlayerActivity?.setRemoteCursorPosition(x, y)
+    }
+
+    // Called by native code
+    private fun setClipboard(text: String) {
+        mediaPlayerActivity?.setClipboardText(text)
+    }
+
+    // Called by native code
+    private fun reportConnectionError(message: String) {
+        mediaPlayerActivity?.showConnectionError(message)
+    }
+
+    fun chooseDecoderForType(mimeType: String, decoderPreference: Int): String? {
+        return CodecQuerySingleton.chooseDecoderForType(mimeType, decoderPreference)
+    }
+
+    fun decoderSupportsType(decoderName: String, mimeType: String): Boolean {
+        return CodecQuerySingleton.decoderSupportsType(decoderName, mimeType)
     }
 
     fun listProfilesForDecoder(decoderName: String, mimeType: String): IntArray? {
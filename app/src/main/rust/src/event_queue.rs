@@ -0,0 +1,228 @@
+use crate::MediaPlayerEvent;
+use std::{
+    mem::Discriminant,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{
+    self,
+    error::{TryRecvError, TrySendError},
+};
+
+/// Fans `MediaPlayerEvent`s out to every subscriber. Each one has its own bounded queue, events
+/// that don't fit are coalesced into an overflow that keeps only the latest event of each kind, so
+/// a burst from the Kotlin side can't grow memory without limit.
+pub struct EventSender {
+    capacity: usize,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventSender {
+    pub fn new(capacity: usize) -> EventSender {
+        EventSender {
+            capacity,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Receive the events sent from now on. The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> EventReceiver {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let overflow = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Subscriber {
+            sender,
+            overflow: overflow.clone(),
+        };
+
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(lock_guard) => lock_guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        subscribers.push(subscriber);
+        EventReceiver { receiver, overflow }
+    }
+
+    /// Queue an event for every subscriber without blocking. Subscribers whose receiver has been
+    /// dropped are forgotten.
+    pub fn send(&self, event: MediaPlayerEvent) {
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(lock_guard) => lock_guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+struct Subscriber {
+    sender: mpsc::Sender<MediaPlayerEvent>,
+    overflow: Arc<Mutex<Vec<MediaPlayerEvent>>>,
+}
+
+impl Subscriber {
+    /// Fails only if the receiver has been dropped.
+    fn send(&self, event: MediaPlayerEvent) -> Result<(), TrySendError<MediaPlayerEvent>> {
+        // Nothing reads the overflow anymore
+        if self.sender.is_closed() {
+            return Err(TrySendError::Closed(event));
+        }
+
+        let mut overflow = match self.overflow.lock() {
+            Ok(lock_guard) => lock_guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // Keep going through the overflow until it is drained so that events stay in order
+        if !overflow.is_empty() {
+            coalesce(&mut overflow, event);
+            return Ok(());
+        }
+
+        match self.sender.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(event)) => {
+                log::warn!("Event queue is full, coalescing {event:?}");
+                coalesce(&mut overflow, event);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub struct EventReceiver {
+    receiver: mpsc::Receiver<MediaPlayerEvent>,
+    overflow: Arc<Mutex<Vec<MediaPlayerEvent>>>,
+}
+
+impl EventReceiver {
+    /// Receive the next event, waiting for one if there is none.
+    pub async fn recv(&mut self) -> Option<MediaPlayerEvent> {
+        match self.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => self.receiver.recv().await,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Receive the next event if there is one.
+    pub fn try_recv(&mut self) -> Result<MediaPlayerEvent, TryRecvError> {
+        match self.receiver.try_recv() {
+            // Only filled while the channel is full, so it comes after everything in the channel
+            Err(e) => {
+                let mut overflow = match self.overflow.lock() {
+                    Ok(lock_guard) => lock_guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                if overflow.is_empty() {
+                    Err(e)
+                } else {
+                    Ok(overflow.remove(0))
+                }
+            }
+            res => res,
+        }
+    }
+}
+
+/// Replace the queued event of the same kind, if any, with the newer one.
+fn coalesce(overflow: &mut Vec<MediaPlayerEvent>, event: MediaPlayerEvent) {
+    let kind = event_kind(&event);
+    overflow.retain(|queued| event_kind(queued) != kind);
+    overflow.push(event);
+}
+
+/// Surface events replace each other since only the latest surface state matters.
+fn event_kind(event: &MediaPlayerEvent) -> Discriminant<MediaPlayerEvent> {
+    match event {
+        MediaPlayerEvent::SurfaceCreated(_) | MediaPlayerEvent::SurfaceDestroyed => {
+            std::mem::discriminant(&MediaPlayerEvent::SurfaceDestroyed)
+        }
+        event => std::mem::discriminant(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(receiver: &mut EventReceiver) -> Option<i32> {
+        match receiver.try_recv() {
+            Ok(MediaPlayerEvent::RotationChanged(degrees)) => Some(degrees),
+            Ok(event) => panic!("Unexpected {event:?}"),
+            Err(_) => None,
+        }
+    }
+
+    #[test]
+    fn delivers_to_every_subscriber() {
+        let sender = EventSender::new(4);
+        let mut first = sender.subscribe();
+        let mut second = sender.subscribe();
+        sender.send(MediaPlayerEvent::RotationChanged(90));
+        assert_eq!(rotation(&mut first), Some(90));
+        assert_eq!(rotation(&mut second), Some(90));
+        assert_eq!(rotation(&mut first), None);
+    }
+
+    #[test]
+    fn overflow_keeps_the_latest_of_each_kind() {
+        let sender = EventSender::new(1);
+        let mut receiver = sender.subscribe();
+        sender.send(MediaPlayerEvent::RotationChanged(0));
+        // The channel is full from here on
+        sender.send(MediaPlayerEvent::FocusChanged(false));
+        sender.send(MediaPlayerEvent::RotationChanged(90));
+        sender.send(MediaPlayerEvent::FocusChanged(true));
+        sender.send(MediaPlayerEvent::RotationChanged(180));
+
+        assert_eq!(rotation(&mut receiver), Some(0));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(MediaPlayerEvent::FocusChanged(true))
+        ));
+        assert_eq!(rotation(&mut receiver), Some(180));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn surface_events_replace_each_other() {
+        let sender = EventSender::new(1);
+        let mut receiver = sender.subscribe();
+        sender.send(MediaPlayerEvent::RotationChanged(0));
+        sender.send(MediaPlayerEvent::SurfaceDestroyed);
+        sender.send(MediaPlayerEvent::RotationChanged(90));
+        sender.send(MediaPlayerEvent::SurfaceDestroyed);
+
+        assert_eq!(rotation(&mut receiver), Some(0));
+        assert_eq!(rotation(&mut receiver), Some(90));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(MediaPlayerEvent::SurfaceDestroyed)
+        ));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn stays_in_order_while_the_overflow_drains() {
+        let sender = EventSender::new(1);
+        let mut receiver = sender.subscribe();
+        sender.send(MediaPlayerEvent::RotationChanged(0));
+        sender.send(MediaPlayerEvent::RotationChanged(90));
+        assert_eq!(rotation(&mut receiver), Some(0));
+        // The channel has room again but the overflow goes first
+        sender.send(MediaPlayerEvent::FocusChanged(false));
+        assert_eq!(rotation(&mut receiver), Some(90));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(MediaPlayerEvent::FocusChanged(false))
+        ));
+    }
+
+    #[test]
+    fn forgets_dropped_subscribers() {
+        let sender = EventSender::new(1);
+        let receiver = sender.subscribe();
+        drop(receiver);
+        sender.send(MediaPlayerEvent::MainActivityDestroyed);
+        assert!(sender.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+/// Number of values in `GamepadState::axes`: left stick x and y, right stick x and y, left and
+/// right trigger.
+pub const NUM_AXES: usize = 6;
+/// Number of values the Kotlin side passes per motion event: `NUM_AXES` then the hat x and y.
+pub const NUM_RAW_AXES: usize = NUM_AXES + 2;
+
+/// Sticks rarely rest at exactly zero, anything closer to the center than this is centered.
+const STICK_DEADZONE: f32 = 0.1;
+const TRIGGER_DEADZONE: f32 = 0.05;
+/// The hat reports -1, 0 or 1 but some controllers are off slightly.
+const HAT_THRESHOLD: f32 = 0.5;
+
+/// Bits of `GamepadState::buttons`.
+pub mod button {
+    pub const A: u32 = 1 << 0;
+    pub const B: u32 = 1 << 1;
+    pub const X: u32 = 1 << 2;
+    pub const Y: u32 = 1 << 3;
+    pub const LEFT_SHOULDER: u32 = 1 << 4;
+    pub const RIGHT_SHOULDER: u32 = 1 << 5;
+    pub const LEFT_THUMB: u32 = 1 << 6;
+    pub const RIGHT_THUMB: u32 = 1 << 7;
+    pub const START: u32 = 1 << 8;
+    pub const BACK: u32 = 1 << 9;
+    pub const GUIDE: u32 = 1 << 10;
+    pub const DPAD_UP: u32 = 1 << 11;
+    pub const DPAD_DOWN: u32 = 1 << 12;
+    pub const DPAD_LEFT: u32 = 1 << 13;
+    pub const DPAD_RIGHT: u32 = 1 << 14;
+}
+
+/// Buttons and normalized axes of the controller. Sticks range from -1 to 1 with y pointing
+/// down like Android reports it, triggers from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GamepadState {
+    // Pressed through key events
+    key_buttons: u32,
+    // D-pad directions reported through the hat axes, which some controllers use instead of keys
+    hat_buttons: u32,
+    axes: [f32; NUM_AXES],
+}
+
+impl GamepadState {
+    /// The pressed buttons as bits from `button`, whether they came from keys or the hat.
+    pub fn buttons(&self) -> u32 {
+        self.key_buttons | self.hat_buttons
+    }
+
+    /// The sticks and triggers in the order described by `NUM_AXES`, with the deadzones applied.
+    pub fn axes(&self) -> [f32; NUM_AXES] {
+        self.axes
+    }
+
+    /// Apply a key event. Keys that aren't gamepad buttons are ignored.
+    pub fn set_button(&mut self, keycode: i32, pressed: bool) {
+        let Some(button) = button_from_keycode(keycode) else {
+            return;
+        };
+        if pressed {
+            self.key_buttons |= button;
+        } else {
+            self.key_buttons &= !button;
+        }
+    }
+
+    /// Apply the axes of a motion event, ordered as described by `NUM_RAW_AXES`.
+    pub fn set_axes(&mut self, raw: &[f32; NUM_RAW_AXES]) {
+        let (left_x, left_y) = stick(raw[0], raw[1]);
+        let (right_x, right_y) = stick(raw[2], raw[3]);
+        self.axes = [
+            left_x,
+            left_y,
+            right_x,
+            right_y,
+            trigger(raw[4]),
+            trigger(raw[5]),
+        ];
+
+        let (hat_x, hat_y) = (raw[6], raw[7]);
+        self.hat_buttons = 0;
+        if hat_x <= -HAT_THRESHOLD {
+            self.hat_buttons |= button::DPAD_LEFT;
+        } else if hat_x >= HAT_THRESHOLD {
+            self.hat_buttons |= button::DPAD_RIGHT;
+        }
+        if hat_y <= -HAT_THRESHOLD {
+            self.hat_buttons |= button::DPAD_UP;
+        } else if hat_y >= HAT_THRESHOLD {
+            self.hat_buttons |= button::DPAD_DOWN;
+        }
+    }
+}
+
+/// Radial deadzone, rescaled so that the output still starts from zero at its edge.
+fn stick(x: f32, y: f32) -> (f32, f32) {
+    let (x, y) = (sanitize(x), sanitize(y));
+    let magnitude = x.hypot(y);
+    if magnitude <= STICK_DEADZONE {
+        return (0.0, 0.0);
+    }
+    let scale = ((magnitude - STICK_DEADZONE) / (1.0 - STICK_DEADZONE)).min(1.0) / magnitude;
+    (x * scale, y * scale)
+}
+
+fn trigger(value: f32) -> f32 {
+    let value = sanitize(value).max(0.0);
+    if value <= TRIGGER_DEADZONE {
+        0.0
+    } else {
+        ((value - TRIGGER_DEADZONE) / (1.0 - TRIGGER_DEADZONE)).min(1.0)
+    }
+}
+
+/// Missing axes can come through as NaN.
+fn sanitize(value: f32) -> f32 {
+    if value.is_finite() {
+        value.clamp(-1.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+// Values of the `KeyEvent.KEYCODE_*` constants
+fn button_from_keycode(keycode: i32) -> Option<u32> {
+    let button = match keycode {
+        19 => button::DPAD_UP,
+        20 => button::DPAD_DOWN,
+        21 => button::DPAD_LEFT,
+        22 => button::DPAD_RIGHT,
+        96 => button::A,
+        97 => button::B,
+        99 => button::X,
+        100 => button::Y,
+        102 => button::LEFT_SHOULDER,
+        103 => button::RIGHT_SHOULDER,
+        106 => button::LEFT_THUMB,
+        107 => button::RIGHT_THUMB,
+        108 => button::START,
+        109 => button::BACK,
+        110 => button::GUIDE,
+        _ => return None,
+    };
+    Some(button)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-6 && (actual.1 - expected.1).abs() < 1e-6,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn stick_deadzone_is_radial() {
+        assert_eq!(stick(0.0, 0.0), (0.0, 0.0));
+        assert_eq!(stick(STICK_DEADZONE, 0.0), (0.0, 0.0));
+        // Each axis is inside the deadzone but together they're outside it
+        let (x, y) = stick(0.08, 0.08);
+        assert!(x > 0.0 && y > 0.0);
+    }
+
+    #[test]
+    fn stick_is_rescaled_from_the_deadzone() {
+        assert_close(stick(1.0, 0.0), (1.0, 0.0));
+        assert_close(stick(0.0, -1.0), (0.0, -1.0));
+        assert_close(stick(0.55, 0.0), (0.5, 0.0));
+        // The direction is kept
+        assert_close(stick(0.6, 0.8), (0.6, 0.8));
+        // Corners of square gates are scaled back onto the circle
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert_close(stick(1.0, 1.0), (diagonal, diagonal));
+    }
+
+    #[test]
+    fn stick_ignores_bad_values() {
+        assert_eq!(stick(f32::NAN, f32::NAN), (0.0, 0.0));
+        assert_close(stick(f32::NAN, 1.0), (0.0, 1.0));
+        assert_close(stick(2.0, 0.0), (1.0, 0.0));
+        assert_eq!(stick(f32::INFINITY, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn trigger_deadzone_and_range() {
+        assert_eq!(trigger(0.0), 0.0);
+        assert_eq!(trigger(TRIGGER_DEADZONE), 0.0);
+        assert_eq!(trigger(-1.0), 0.0);
+        assert!((trigger(0.525) - 0.5).abs() < 1e-6);
+        assert_eq!(trigger(1.0), 1.0);
+        assert_eq!(trigger(1.5), 1.0);
+        assert_eq!(trigger(f32::NAN), 0.0);
+    }
+
+    #[test]
+    fn keycodes_map_to_distinct_buttons() {
+        let keycodes = [
+            19, 20, 21, 22, 96, 97, 99, 100, 102, 103, 106, 107, 108, 109, 110,
+        ];
+        let mut all = 0;
+        for keycode in keycodes {
+            let button = button_from_keycode(keycode).unwrap();
+            assert_eq!(button.count_ones(), 1, "keycode {keycode}");
+            assert_eq!(all & button, 0, "keycode {keycode}");
+            all |= button;
+        }
+        assert_eq!(all, (1 << 15) - 1);
+
+        assert_eq!(button_from_keycode(96), Some(button::A));
+        assert_eq!(button_from_keycode(108), Some(button::START));
+        assert_eq!(button_from_keycode(19), Some(button::DPAD_UP));
+    }
+
+    #[test]
+    fn other_keycodes_are_not_buttons() {
+        // KEYCODE_UNKNOWN, KEYCODE_BACK, KEYCODE_BUTTON_C and KEYCODE_BUTTON_Z
+        for keycode in [0, 4, 98, 101] {
+            assert_eq!(button_from_keycode(keycode), None, "keycode {keycode}");
+        }
+    }
+
+    #[test]
+    fn hat_and_keys_press_the_same_dpad() {
+        let mut state = GamepadState::default();
+        state.set_button(19, true);
+        state.set_axes(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, -1.0]);
+        assert_eq!(state.buttons(), button::DPAD_UP | button::DPAD_RIGHT);
+
+        // Releasing the key leaves the hat's up pressed
+        state.set_button(19, false);
+        assert_eq!(state.buttons(), button::DPAD_UP | button::DPAD_RIGHT);
+        state.set_axes(&[0.0; NUM_RAW_AXES]);
+        assert_eq!(state.buttons(), 0);
+    }
+}
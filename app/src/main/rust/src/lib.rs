@@ -1,5 +1,8 @@
 // mod debug;
+mod event_queue;
+mod gamepad;
 mod media;
+mod stats;
 mod util;
 mod webrtc;
 mod window;
@@ -8,29 +11,51 @@ mod window;
 // C:\Users\Rafael\AppData\Local\Android\Sdk\emulator\emulator -avd Pixel_3_XL_API_31
 // gradlew installX86_64Debug
 
-use self::media::MimeType;
+use self::{
+    event_queue::{EventReceiver, EventSender},
+    gamepad::{GamepadState, NUM_RAW_AXES},
+    media::MimeType,
+    stats::DecoderStats,
+    webrtc::SignalingSecurity,
+};
 use jni::{
     objects::{GlobalRef, JObject, JString, JValue, ReleaseMode},
     JNIEnv, JavaVM,
 };
 use std::{
     future::Future,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use tokio::{
     runtime::{self, Runtime},
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    sync::watch,
 };
 
 pub const RUNTIME_WORKER_THREADS: usize = 2;
 const LOG_TAG: &str = "client-android";
+// Room for a few of each event, anything beyond that is coalesced
+const EVENT_QUEUE_CAPACITY: usize = 16;
+/// Time the tasks get to exit on their own before they are cancelled.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pointer of the `NativeLibSingleton` last handed to Kotlin, or 0 if there is none. Android can
+/// recreate the activity and call `create_native_instance` again, so the previous instance is
+/// tracked here to avoid leaking it along with its runtime.
+static LIVE_INSTANCE: Mutex<jni::sys::jlong> = Mutex::new(0);
 
 /// Events that are of interest to the media player.
 #[derive(Clone)]
 pub enum MediaPlayerEvent {
     MainActivityDestroyed,
+    /// An `android.view.Surface` to render to, not necessarily from a `SurfaceView`.
     SurfaceCreated(GlobalRef),
     SurfaceDestroyed,
+    RotationChanged(i32),
+    FocusChanged(bool),
 }
 
 impl std::fmt::Debug for MediaPlayerEvent {
@@ -39,6 +64,77 @@ impl std::fmt::Debug for MediaPlayerEvent {
             Self::MainActivityDestroyed => write!(f, "MainActivityDestroyed"),
             Self::SurfaceCreated(_) => write!(f, "SurfaceCreated"),
             Self::SurfaceDestroyed => write!(f, "SurfaceDestroyed"),
+            Self::RotationChanged(degrees) => write!(f, "RotationChanged({degrees})"),
+            Self::FocusChanged(focused) => write!(f, "FocusChanged({focused})"),
+        }
+    }
+}
+
+/// Which kind of decoder to pick when discovering decoders. The values match the constants in
+/// `CodecQuerySingleton.kt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum DecoderPreference {
+    PreferHardware = 0,
+    PreferSoftware = 1,
+    ForceSoftware = 2,
+}
+
+impl DecoderPreference {
+    /// Convert from the value passed through JNI.
+    pub fn from_i32(value: i32) -> Option<DecoderPreference> {
+        match value {
+            0 => Some(DecoderPreference::PreferHardware),
+            1 => Some(DecoderPreference::PreferSoftware),
+            2 => Some(DecoderPreference::ForceSoftware),
+            _ => None,
+        }
+    }
+}
+
+/// How the decoder asks the sender for a keyframe. The values match the constants in
+/// `NativeLibSingleton.kt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum KeyframeStrategy {
+    /// Picture Loss Indication only.
+    Pli = 0,
+    /// Full Intra Request only, for senders that ignore PLIs.
+    Fir = 1,
+    /// PLIs, escalating to FIRs if no keyframe arrives after a while.
+    PliThenFir = 2,
+}
+
+impl KeyframeStrategy {
+    /// Convert from the value passed through JNI.
+    pub fn from_i32(value: i32) -> Option<KeyframeStrategy> {
+        match value {
+            0 => Some(KeyframeStrategy::Pli),
+            1 => Some(KeyframeStrategy::Fir),
+            2 => Some(KeyframeStrategy::PliThenFir),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the render loop favors latency or smooth motion. Values must match the constants in
+/// `NativeLibSingleton.kt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum RenderPolicy {
+    /// Render each frame as soon as it is decoded.
+    LowLatency = 0,
+    /// Hold frames briefly and render them spaced like they arrived, evening out decoding jitter.
+    Smooth = 1,
+}
+
+impl RenderPolicy {
+    /// Convert from the value passed through JNI.
+    pub fn from_i32(value: i32) -> Option<RenderPolicy> {
+        match value {
+            0 => Some(RenderPolicy::LowLatency),
+            1 => Some(RenderPolicy::Smooth),
+            _ => None,
         }
     }
 }
@@ -51,9 +147,26 @@ pub struct NativeLibSingleton {
     vm: JavaVM,
     singleton: GlobalRef,
     api_level: i32,
-    runtime: Runtime,
-    sender: UnboundedSender<MediaPlayerEvent>,
-    receiver: Mutex<Option<UnboundedReceiver<MediaPlayerEvent>>>,
+    video_rotation: AtomicI32,
+    decoder_preference: AtomicI32,
+    keyframe_strategy: AtomicI32,
+    render_policy: AtomicI32,
+    // `None` if TLS was asked for without a usable certificate pin
+    signaling_security: Mutex<Option<SignalingSecurity>>,
+    strip_sei: AtomicBool,
+    diagnostics: AtomicBool,
+    stats: DecoderStats,
+    rendered_frames: AtomicU64,
+    // Taken out on shutdown. The tasks hold on to the singleton so it can outlive the runtime.
+    runtime: Mutex<Option<Runtime>>,
+    events: EventSender,
+    session: watch::Sender<u64>,
+    surface: Mutex<Option<GlobalRef>>,
+    aspect_ratio: Mutex<Option<(i32, i32)>>,
+    clipboard: watch::Sender<Option<String>>,
+    b_frames: watch::Sender<()>,
+    target_bitrate: watch::Sender<Option<u32>>,
+    gamepad: watch::Sender<GamepadState>,
 }
 
 impl NativeLibSingleton {
@@ -67,23 +180,46 @@ impl NativeLibSingleton {
             .enable_all()
             .worker_threads(RUNTIME_WORKER_THREADS)
             .build()?;
-        let (sender, receiver) = unbounded_channel();
+        let (session, _) = watch::channel(0);
+        let (clipboard, _) = watch::channel(None);
+        let (b_frames, _) = watch::channel(());
+        let (target_bitrate, _) = watch::channel(None);
+        let (gamepad, _) = watch::channel(GamepadState::default());
 
         Ok(NativeLibSingleton {
             vm,
             singleton,
             api_level,
-            runtime,
-            sender,
-            receiver: Mutex::new(Some(receiver)),
+            video_rotation: AtomicI32::new(0),
+            decoder_preference: AtomicI32::new(DecoderPreference::PreferHardware as i32),
+            keyframe_strategy: AtomicI32::new(KeyframeStrategy::PliThenFir as i32),
+            render_policy: AtomicI32::new(RenderPolicy::LowLatency as i32),
+            signaling_security: Mutex::new(Some(SignalingSecurity::Plain)),
+            strip_sei: AtomicBool::new(false),
+            diagnostics: AtomicBool::new(false),
+            stats: DecoderStats::default(),
+            rendered_frames: AtomicU64::new(0),
+            runtime: Mutex::new(Some(runtime)),
+            events: EventSender::new(EVENT_QUEUE_CAPACITY),
+            session,
+            surface: Mutex::new(None),
+            aspect_ratio: Mutex::new(None),
+            clipboard,
+            b_frames,
+            target_bitrate,
+            gamepad,
         })
     }
 
     /// Signal an `ActivityEvent`.
     pub fn signal_event(&self, event: MediaPlayerEvent) {
-        if let Err(e) = self.sender.send(event) {
-            log::error!("{e}");
+        // Remember the surface so that a restarted session can render to it
+        match &event {
+            MediaPlayerEvent::SurfaceCreated(surface) => self.set_surface(Some(surface.clone())),
+            MediaPlayerEvent::SurfaceDestroyed => self.set_surface(None),
+            _ => (),
         }
+        self.events.send(event);
     }
 
     /// Reinterpret a [Arc] as a 64-bit integer which can be stored in Kotlin/Java.
@@ -98,14 +234,47 @@ impl NativeLibSingleton {
         Arc::from_raw(instance as usize as *mut NativeLibSingleton)
     }
 
+    /// Stop everything running on the instance before it is dropped.
+    ///
+    /// The tasks are told to exit, then given `RUNTIME_SHUTDOWN_TIMEOUT` to do so on a separate
+    /// thread, so the caller, usually the UI thread, doesn't block. Whatever is still running after
+    /// that is cancelled. The runtime is never dropped by the tasks' own clone of the singleton,
+    /// which would panic inside the runtime.
+    fn shutdown(&self) {
+        self.signal_event(MediaPlayerEvent::MainActivityDestroyed);
+        self.stop_session();
+
+        let runtime = match self.runtime.lock() {
+            Ok(mut lock_guard) => lock_guard.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        let Some(runtime) = runtime else {
+            return;
+        };
+        let result = std::thread::Builder::new()
+            .name("runtime-shutdown".to_owned())
+            .spawn(move || {
+                runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+                log::info!("Runtime shut down");
+            });
+        if let Err(e) = result {
+            log::error!("Failed to spawn the runtime shutdown thread: {e}");
+        }
+    }
+
     /// Reinterpret an integer as a reference to a `NativeLibManager` without taking ownership.
     pub unsafe fn as_ref<'a>(instance: jni::sys::jlong) -> &'a Self {
         &*(instance as usize as *const NativeLibSingleton)
     }
 
-    /// Returns the process-global Java VM.
-    pub fn global_vm(&self) -> &JavaVM {
-        &self.vm
+    /// Runs `f` with the `JNIEnv` of the current thread. A thread that was not attached to the
+    /// Java VM is attached only for the duration of `f`, so no attachment outlives the call.
+    pub fn with_env<F, R>(&self, f: F) -> Result<R, jni::errors::Error>
+    where
+        F: FnOnce(&JNIEnv) -> Result<R, jni::errors::Error>,
+    {
+        let env = self.vm.attach_current_thread()?;
+        f(&env)
     }
 
     /// Returns the API level of the device that this is currently running on.
@@ -113,6 +282,103 @@ impl NativeLibSingleton {
         self.api_level
     }
 
+    /// Returns the statistics of the current decoder.
+    pub fn stats(&self) -> &DecoderStats {
+        &self.stats
+    }
+
+    /// Returns the kind of decoder that decoder discovery should pick.
+    pub fn decoder_preference(&self) -> DecoderPreference {
+        DecoderPreference::from_i32(self.decoder_preference.load(Ordering::Acquire))
+            .unwrap_or(DecoderPreference::PreferHardware)
+    }
+
+    /// Sets the kind of decoder to pick. Applies to sessions started afterwards.
+    pub fn set_decoder_preference(&self, preference: DecoderPreference) {
+        self.decoder_preference
+            .store(preference as i32, Ordering::Release);
+    }
+
+    /// Returns how keyframes should be requested from the sender.
+    pub fn keyframe_strategy(&self) -> KeyframeStrategy {
+        KeyframeStrategy::from_i32(self.keyframe_strategy.load(Ordering::Acquire))
+            .unwrap_or(KeyframeStrategy::PliThenFir)
+    }
+
+    /// Sets how keyframes are requested. Applies to sessions started afterwards.
+    pub fn set_keyframe_strategy(&self, strategy: KeyframeStrategy) {
+        self.keyframe_strategy
+            .store(strategy as i32, Ordering::Release);
+    }
+
+    /// How decoded frames are rendered.
+    pub fn render_policy(&self) -> RenderPolicy {
+        RenderPolicy::from_i32(self.render_policy.load(Ordering::Acquire))
+            .unwrap_or(RenderPolicy::LowLatency)
+    }
+
+    /// Sets how decoded frames are rendered. Applies to sessions started afterwards.
+    pub fn set_render_policy(&self, policy: RenderPolicy) {
+        self.render_policy.store(policy as i32, Ordering::Release);
+    }
+
+    /// How the signaling connection is secured, `None` if it was misconfigured.
+    pub fn signaling_security(&self) -> Option<SignalingSecurity> {
+        match self.signaling_security.lock() {
+            Ok(lock_guard) => lock_guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Sets how the signaling connection is secured, `None` to refuse to connect. Applies to
+    /// sessions started afterwards.
+    pub fn set_signaling_security(&self, security: Option<SignalingSecurity>) {
+        if let Ok(mut lock_guard) = self.signaling_security.lock() {
+            *lock_guard = security;
+        }
+    }
+
+    /// Whether SEI NALUs are dropped instead of being passed to the decoder.
+    pub fn strip_sei(&self) -> bool {
+        self.strip_sei.load(Ordering::Acquire)
+    }
+
+    /// Sets whether SEI NALUs are dropped. Applies to sessions started afterwards.
+    pub fn set_strip_sei(&self, strip: bool) {
+        self.strip_sei.store(strip, Ordering::Release);
+    }
+
+    /// Whether a diagnostic line should be logged periodically, see `DiagnosticsLog`.
+    pub fn diagnostics_enabled(&self) -> bool {
+        self.diagnostics.load(Ordering::Acquire)
+    }
+
+    /// Turns the periodic diagnostic line on or off. Applies immediately.
+    pub fn set_diagnostics_enabled(&self, enabled: bool) {
+        self.diagnostics.store(enabled, Ordering::Release);
+    }
+
+    /// Total number of frames rendered to a surface since this instance was created.
+    pub fn rendered_frames(&self) -> u64 {
+        self.rendered_frames.load(Ordering::Acquire)
+    }
+
+    /// Record that a frame was rendered to the surface.
+    pub fn frame_rendered(&self) {
+        self.rendered_frames.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Returns the rotation in degrees to be applied to the decoded video.
+    pub fn video_rotation(&self) -> i32 {
+        self.video_rotation.load(Ordering::Acquire)
+    }
+
+    /// Sets the rotation to be applied to the decoded video and notifies the decoder.
+    pub fn set_video_rotation(&self, degrees: i32) {
+        self.video_rotation.store(degrees, Ordering::Release);
+        self.signal_event(MediaPlayerEvent::RotationChanged(degrees));
+    }
+
     /// Spawn an async function on the runtime.
     pub fn spawn<T, F>(self: &Arc<NativeLibSingleton>, func: T)
     where
@@ -120,13 +386,47 @@ impl NativeLibSingleton {
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
-        self.runtime.spawn(func(self.clone()));
+        match self.runtime.lock() {
+            Ok(lock_guard) => match lock_guard.as_ref() {
+                Some(runtime) => {
+                    runtime.spawn(func(self.clone()));
+                }
+                None => log::warn!("Not spawning a task after shutdown"),
+            },
+            Err(_) => log::error!("Runtime lock poisoned"),
+        }
+    }
+
+    /// Returns a receiver for the `MediaPlayerEvent`s signaled from now on. Events from before
+    /// are not replayed, the current surface is available from `current_surface`.
+    pub fn subscribe_events(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
+
+    /// Returns the `android.view.Surface` that is currently available for rendering.
+    pub fn current_surface(&self) -> Option<GlobalRef> {
+        self.surface.lock().ok()?.clone()
+    }
+
+    fn set_surface(&self, surface: Option<GlobalRef>) {
+        if let Ok(mut lock_guard) = self.surface.lock() {
+            *lock_guard = surface;
+        }
+    }
+
+    /// Returns the ID of the current streaming session.
+    pub fn current_session(&self) -> u64 {
+        *self.session.borrow()
+    }
+
+    /// Returns a receiver that is notified when the current session is stopped.
+    pub fn watch_session(&self) -> watch::Receiver<u64> {
+        self.session.subscribe()
     }
 
-    /// Get the receiver part of the `MediaPlayerEvent` channel.
-    pub fn get_event_receiver(&self) -> Option<UnboundedReceiver<MediaPlayerEvent>> {
-        let mut lock_guard = self.receiver.lock().ok()?;
-        lock_guard.take()
+    /// Stop the current streaming session. Its tasks exit once they notice the session ID changed.
+    pub fn stop_session(&self) {
+        self.session.send_modify(|id| *id += 1);
     }
 
     /// Call the singleton method to set the aspect ratio of the player.
@@ -136,11 +436,10 @@ impl NativeLibSingleton {
         width: i32,
         height: i32,
     ) -> Result<(), jni::errors::Error> {
-        // Reduce the given width:height ratio to its lowest terms.
-        let (width, height) = {
-            let divisor = crate::util::gcd(width, height);
-            (width / divisor, height / divisor)
-        };
+        let (width, height) = reduce_ratio(width, height);
+        if let Ok(mut lock_guard) = self.aspect_ratio.lock() {
+            *lock_guard = Some((width, height));
+        }
 
         env.call_method(
             self.singleton.as_obj(),
@@ -151,18 +450,162 @@ impl NativeLibSingleton {
         Ok(())
     }
 
+    /// Like `set_media_player_aspect_ratio` but does nothing if the ratio is already the one set.
+    /// Returns whether the ratio changed.
+    pub fn update_media_player_aspect_ratio(
+        &self,
+        env: &JNIEnv,
+        width: i32,
+        height: i32,
+    ) -> Result<bool, jni::errors::Error> {
+        let current = self.aspect_ratio.lock().ok().and_then(|ratio| *ratio);
+        if current == Some(reduce_ratio(width, height)) {
+            return Ok(false);
+        }
+        self.set_media_player_aspect_ratio(env, width, height)?;
+        Ok(true)
+    }
+
+    /// Set the aspect ratio of the player again, e.g. after it moved to a picture-in-picture
+    /// window whose layout does not have it yet.
+    pub fn reapply_media_player_aspect_ratio(
+        &self,
+        env: &JNIEnv,
+    ) -> Result<(), jni::errors::Error> {
+        let aspect_ratio = self.aspect_ratio.lock().ok().and_then(|ratio| *ratio);
+        match aspect_ratio {
+            Some((width, height)) => self.set_media_player_aspect_ratio(env, width, height),
+            None => Ok(()),
+        }
+    }
+
+    /// Pass the remote cursor's shape to the Kotlin side so it can be drawn over the video.
+    pub fn set_remote_cursor(
+        &self,
+        env: &JNIEnv,
+        hotspot_x: i32,
+        hotspot_y: i32,
+        width: i32,
+        height: i32,
+        rgba: &[u8],
+    ) -> Result<(), jni::errors::Error> {
+        let rgba = env.byte_array_from_slice(rgba)?;
+        env.call_method(
+            self.singleton.as_obj(),
+            "setRemoteCursor",
+            "(IIII[B)V",
+            &[
+                hotspot_x.into(),
+                hotspot_y.into(),
+                width.into(),
+                height.into(),
+                unsafe { JObject::from_raw(rgba) }.into(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Move the remote cursor drawn over the video, `x` and `y` are fractions of its size.
+    pub fn set_remote_cursor_position(
+        &self,
+        env: &JNIEnv,
+        x: f32,
+        y: f32,
+    ) -> Result<(), jni::errors::Error> {
+        env.call_method(
+            self.singleton.as_obj(),
+            "setRemoteCursorPosition",
+            "(FF)V",
+            &[x.into(), y.into()],
+        )?;
+        Ok(())
+    }
+
+    /// Record new text copied on the device so that it can be sent to the host.
+    pub fn set_local_clipboard(&self, text: String) {
+        self.clipboard.send_replace(Some(text));
+    }
+
+    /// Returns a receiver that is notified when text is copied on the device.
+    pub fn watch_clipboard(&self) -> watch::Receiver<Option<String>> {
+        self.clipboard.subscribe()
+    }
+
+    /// Signal that the stream contains B-frames so that the host can be asked to stop sending them.
+    pub fn report_b_frames(&self) {
+        self.b_frames.send_replace(());
+    }
+
+    /// Returns a receiver that is notified when the decoder sees B-frames.
+    pub fn watch_b_frames(&self) -> watch::Receiver<()> {
+        self.b_frames.subscribe()
+    }
+
+    /// Signal the bitrate, in bits per second, that the host's encoder should aim for.
+    pub fn report_target_bitrate(&self, bps: u32) {
+        self.target_bitrate.send_replace(Some(bps));
+    }
+
+    /// Returns a receiver that is notified when the target bitrate changes.
+    pub fn watch_target_bitrate(&self) -> watch::Receiver<Option<u32>> {
+        self.target_bitrate.subscribe()
+    }
+
+    /// Apply a gamepad event. Receivers are only notified if the state changed.
+    pub fn update_gamepad(&self, update: impl FnOnce(&mut GamepadState)) {
+        self.gamepad.send_if_modified(|state| {
+            let previous = *state;
+            update(state);
+            *state != previous
+        });
+    }
+
+    /// Returns a receiver that is notified when the gamepad state changes.
+    pub fn watch_gamepad(&self) -> watch::Receiver<GamepadState> {
+        self.gamepad.subscribe()
+    }
+
+    /// Put text copied on the host into the Android clipboard.
+    pub fn set_clipboard(&self, env: &JNIEnv, text: &str) -> Result<(), jni::errors::Error> {
+        let text = env.new_string(text)?;
+        env.call_method(
+            self.singleton.as_obj(),
+            "setClipboard",
+            "(Ljava/lang/String;)V",
+            &[text.into()],
+        )?;
+        Ok(())
+    }
+
+    /// Notify the Kotlin side that the connection to the host could not be established.
+    pub fn report_connection_error(
+        &self,
+        env: &JNIEnv,
+        message: &str,
+    ) -> Result<(), jni::errors::Error> {
+        let message = env.new_string(message)?;
+        env.call_method(
+            self.singleton.as_obj(),
+            "reportConnectionError",
+            "(Ljava/lang/String;)V",
+            &[message.into()],
+        )?;
+        Ok(())
+    }
+
     /// Choose a decoder for the given MIME type. The logic is handled on the Kotlin side.
     pub fn choose_decoder_for_type(
         &self,
         env: &JNIEnv,
         mime_type: MimeType,
+        preference: DecoderPreference,
     ) -> Result<Option<String>, jni::errors::Error> {
         let mime_type = env.new_string(mime_type.to_android_str())?;
         let method_output = env.call_method(
             self.singleton.as_obj(),
             "chooseDecoderForType",
-            "(Ljava/lang/String;)Ljava/lang/String;",
-            &[mime_type.into()],
+            "(Ljava/lang/String;I)Ljava/lang/String;",
+            &[mime_type.into(), JValue::Int(preference as i32)],
         )?;
 
         let obj = method_output.l()?;
@@ -175,7 +618,25 @@ impl NativeLibSingleton {
         let s = java_str
             .to_str()
             .map_err(|_| jni::errors::Error::JavaException)?;
-        Ok(Some(s.to_owned()))
+        Ok(non_blank_decoder_name(s))
+    }
+
+    /// Whether the named decoder handles the MIME type. Asked of Kotlin's `MediaCodecList`.
+    pub fn decoder_supports_type(
+        &self,
+        env: &JNIEnv,
+        decoder_name: &str,
+        mime_type: MimeType,
+    ) -> Result<bool, jni::errors::Error> {
+        let decoder_name = env.new_string(decoder_name)?;
+        let mime_type = env.new_string(mime_type.to_android_str())?;
+        env.call_method(
+            self.singleton.as_obj(),
+            "decoderSupportsType",
+            "(Ljava/lang/String;Ljava/lang/String;)Z",
+            &[decoder_name.into(), mime_type.into()],
+        )?
+        .z()
     }
 
     /// List the available codec profiles for the decoder.
@@ -211,6 +672,18 @@ impl NativeLibSingleton {
     }
 }
 
+/// The decoder name without surrounding whitespace. A blank name is as good as none.
+fn non_blank_decoder_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Reduce the given width:height ratio to its lowest terms.
+fn reduce_ratio(width: i32, height: i32) -> (i32, i32) {
+    let divisor = crate::util::gcd(width, height);
+    (width / divisor, height / divisor)
+}
+
 /// Initializes the native library.
 #[export_name = "Java_com_debug_myapplication_NativeLibSingleton_createNativeInstance"]
 pub extern "system" fn create_native_instance(
@@ -253,8 +726,25 @@ pub extern "system" fn create_native_instance(
         }
     };
 
+    let mut live_instance = match LIVE_INSTANCE.lock() {
+        Ok(lock_guard) => lock_guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    // Free the instance from a previous call before replacing it
+    if *live_instance != 0 {
+        log::warn!("Native instance already exists, destroying the previous one");
+        let prev = unsafe { NativeLibSingleton::from_raw_integer(*live_instance) };
+        *live_instance = 0;
+        prev.shutdown();
+        std::mem::drop(prev);
+    }
+
     match NativeLibSingleton::new(vm, singleton, api_level) {
-        Ok(instance) => Arc::new(instance).into_java_long(),
+        Ok(instance) => {
+            *live_instance = Arc::new(instance).into_java_long();
+            *live_instance
+        }
         Err(e) => {
             log::error!("Error creating native instance: {e}");
             0
@@ -269,14 +759,24 @@ pub extern "system" fn destroy_native_instance(
     _singleton: jni::sys::jobject,
     ptr: jni::sys::jlong,
 ) {
-    if ptr != 0 {
+    let mut live_instance = match LIVE_INSTANCE.lock() {
+        Ok(lock_guard) => lock_guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    // Only the live instance is freed, a stale pointer was already destroyed when it was replaced
+    if ptr != 0 && ptr == *live_instance {
+        *live_instance = 0;
         let arc = unsafe { NativeLibSingleton::from_raw_integer(ptr) };
-        arc.signal_event(MediaPlayerEvent::MainActivityDestroyed);
+        arc.shutdown();
         std::mem::drop(arc); // Unnecessary but emphasizes that it will be dropped and freed
+    } else if ptr != 0 {
+        log::warn!("Ignoring `destroy_native_instance` on a stale instance");
     }
 }
 
-/// Sends the `MediaPlayerActivity`'s `android.view.Surface` to the decoder.
+/// Sends an `android.view.Surface` to the decoder, e.g. the `MediaPlayerActivity`'s `SurfaceView`
+/// or one created from a `SurfaceTexture`.
 #[export_name = "Java_com_debug_myapplication_NativeLibSingleton_sendSurface"]
 pub extern "system" fn send_surface(
     env: JNIEnv,
@@ -317,6 +817,307 @@ pub extern "system" fn destroy_surface(
     }
 }
 
+/// Rotate the decoded video clockwise by the given degrees (0, 90, 180 or 270).
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setVideoRotation"]
+pub extern "system" fn set_video_rotation(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    degrees: jni::sys::jint,
+) {
+    if ptr != 0 {
+        if !matches!(degrees, 0 | 90 | 180 | 270) {
+            log::error!("Invalid video rotation: {degrees}");
+            return;
+        }
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_video_rotation(degrees);
+    }
+}
+
+/// Tell the decoder whether the `MediaPlayerActivity` window has focus.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setWindowFocus"]
+pub extern "system" fn set_window_focus(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    has_focus: jni::sys::jboolean,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.signal_event(MediaPlayerEvent::FocusChanged(has_focus != 0));
+    }
+}
+
+/// Set which kind of decoder the next session should use, see `DecoderPreference`.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setDecoderPreference"]
+pub extern "system" fn set_decoder_preference(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    preference: jni::sys::jint,
+) {
+    if ptr != 0 {
+        let Some(preference) = DecoderPreference::from_i32(preference) else {
+            log::error!("Invalid decoder preference: {preference}");
+            return;
+        };
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_decoder_preference(preference);
+    }
+}
+
+/// Set how the next session requests keyframes, see `KeyframeStrategy`.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setKeyframeStrategy"]
+pub extern "system" fn set_keyframe_strategy(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    strategy: jni::sys::jint,
+) {
+    if ptr != 0 {
+        let Some(strategy) = KeyframeStrategy::from_i32(strategy) else {
+            log::error!("Invalid keyframe strategy: {strategy}");
+            return;
+        };
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_keyframe_strategy(strategy);
+    }
+}
+
+/// Set whether the next session favors latency or smoothness, see `RenderPolicy`.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setRenderPolicy"]
+pub extern "system" fn set_render_policy(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    policy: jni::sys::jint,
+) {
+    if ptr != 0 {
+        let Some(policy) = RenderPolicy::from_i32(policy) else {
+            log::error!("Invalid render policy: {policy}");
+            return;
+        };
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_render_policy(policy);
+    }
+}
+
+/// Set whether the next session's signaling uses `wss://`. `pinned_sha256` is the hex SHA-256
+/// fingerprint of the host's certificate, which `wss://` requires since the host is addressed by
+/// its IP.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setSignalingSecurity"]
+pub extern "system" fn set_signaling_security(
+    env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    use_tls: jni::sys::jboolean,
+    pinned_sha256: jni::sys::jstring,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+
+        if use_tls == 0 {
+            instance.set_signaling_security(Some(SignalingSecurity::Plain));
+            return;
+        }
+
+        let mut fingerprint = String::new();
+        if !pinned_sha256.is_null() {
+            let pinned_sha256 = unsafe { JString::from_raw(pinned_sha256) };
+            match env.get_string(pinned_sha256) {
+                Ok(s) => fingerprint = s.into(),
+                Err(e) => log::error!("Error reading the certificate fingerprint: {e}"),
+            }
+        }
+        // Never falls back to plaintext, the session refuses to start instead
+        let security = if fingerprint.trim().is_empty() {
+            log::error!("Encrypted signaling needs the certificate fingerprint of the host");
+            None
+        } else {
+            let parsed = SignalingSecurity::parse_fingerprint(&fingerprint);
+            if parsed.is_none() {
+                log::error!("Invalid certificate fingerprint: {fingerprint}");
+            }
+            parsed.map(|pinned_sha256| SignalingSecurity::Tls { pinned_sha256 })
+        };
+        instance.set_signaling_security(security);
+    }
+}
+
+/// Drop SEI NALUs before they reach the decoder, for devices whose decoders fail on them.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setStripSei"]
+pub extern "system" fn set_strip_sei(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    strip: jni::sys::jboolean,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_strip_sei(strip != 0);
+    }
+}
+
+/// Log a line of connection statistics every few seconds, for bug reports.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_setDiagnosticsLogging"]
+pub extern "system" fn set_diagnostics_logging(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    enabled: jni::sys::jboolean,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.set_diagnostics_enabled(enabled != 0);
+    }
+}
+
+/// Forward a gamepad button press or release to the host.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_sendGamepadButton"]
+pub extern "system" fn send_gamepad_button(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    keycode: jni::sys::jint,
+    pressed: jni::sys::jboolean,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.update_gamepad(|state| state.set_button(keycode, pressed != 0));
+    }
+}
+
+/// Forward the stick, trigger and hat axes of a gamepad motion event to the host.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_sendGamepadAxes"]
+pub extern "system" fn send_gamepad_axes(
+    env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    axes: jni::sys::jfloatArray,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+
+        if axes.is_null() {
+            log::error!("Null `FloatArray` passed to `send_gamepad_axes`");
+            return;
+        }
+
+        let mut raw = [0.0; NUM_RAW_AXES];
+        match env.get_array_length(axes) {
+            Ok(len) if len as usize == NUM_RAW_AXES => (),
+            Ok(len) => {
+                log::error!("Expected {NUM_RAW_AXES} gamepad axes, got {len}");
+                return;
+            }
+            Err(e) => {
+                log::error!("Error reading the gamepad axes: {e}");
+                return;
+            }
+        }
+        if let Err(e) = env.get_float_array_region(axes, 0, &mut raw) {
+            log::error!("Error reading the gamepad axes: {e}");
+            return;
+        }
+        instance.update_gamepad(|state| state.set_axes(&raw));
+    }
+}
+
+/// Send text copied on the device to the host.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_sendClipboard"]
+pub extern "system" fn send_clipboard(
+    env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+    text: jni::sys::jstring,
+) {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+
+        if text.is_null() {
+            log::error!("Null `String` passed to `send_clipboard`");
+            return;
+        }
+
+        let text = unsafe { JString::from_raw(text) };
+        match env.get_string(text) {
+            Ok(text) => instance.set_local_clipboard(text.into()),
+            Err(e) => log::error!("Error reading the clipboard text: {e}"),
+        }
+    }
+}
+
+/// Number of frames rendered since the instance was created, for the instrumentation tests to
+/// check that video is flowing. Returns 0 for a null instance.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_getRenderedFrameCount"]
+pub extern "system" fn get_rendered_frame_count(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+) -> jni::sys::jlong {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.rendered_frames() as jni::sys::jlong
+    } else {
+        0
+    }
+}
+
+/// Name of the decoder used by the current session, or null if none was chosen yet.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_getDecoderName"]
+pub extern "system" fn get_decoder_name(
+    env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+) -> jni::sys::jstring {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        if let Some(name) = instance.stats().decoder_name() {
+            match env.new_string(name) {
+                Ok(name) => return name.into_raw(),
+                Err(e) => log::error!("Error creating decoder name string: {e}"),
+            }
+        }
+    }
+    std::ptr::null_mut()
+}
+
+/// What was negotiated for the track being decoded, e.g. `video/avc pt=102 clock=90000
+/// profile-level-id=42e01f packetization-mode=1`, or null if decoding has not started.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_getNegotiatedCodec"]
+pub extern "system" fn get_negotiated_codec(
+    env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+) -> jni::sys::jstring {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        if let Some(codec) = instance.stats().negotiated_codec() {
+            match env.new_string(codec.to_string()) {
+                Ok(codec) => return codec.into_raw(),
+                Err(e) => log::error!("Error creating negotiated codec string: {e}"),
+            }
+        }
+    }
+    std::ptr::null_mut()
+}
+
+/// Connection quality of the current session from 0 to 100 for display in the UI.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_getConnectionQuality"]
+pub extern "system" fn get_connection_quality(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+) -> jni::sys::jint {
+    if ptr != 0 {
+        let instance = unsafe { NativeLibSingleton::as_ref(ptr) };
+        instance.stats().quality() as jni::sys::jint
+    } else {
+        0
+    }
+}
+
 /// Start the WebRTC decoder.
 #[export_name = "Java_com_debug_myapplication_NativeLibSingleton_startMediaPlayer"]
 pub extern "system" fn start_media_player(
@@ -332,3 +1133,35 @@ pub extern "system" fn start_media_player(
         std::mem::forget(arc); // Prevent the `Arc` from being dropped
     }
 }
+
+/// Stop the running WebRTC session, if any, and start a new one.
+#[export_name = "Java_com_debug_myapplication_NativeLibSingleton_restartMediaPlayer"]
+pub extern "system" fn restart_media_player(
+    _env: JNIEnv,
+    _singleton: jni::sys::jobject,
+    ptr: jni::sys::jlong,
+) {
+    if ptr != 0 {
+        log::info!("restarting");
+
+        let arc = unsafe { NativeLibSingleton::from_raw_integer(ptr) };
+        arc.stop_session();
+        arc.spawn(webrtc::start_webrtc);
+        std::mem::forget(arc); // Prevent the `Arc` from being dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blank_decoder_names_are_none() {
+        assert_eq!(non_blank_decoder_name(""), None);
+        assert_eq!(non_blank_decoder_name(" \t\n"), None);
+        assert_eq!(
+            non_blank_decoder_name(" c2.android.avc.decoder\n").as_deref(),
+            Some("c2.android.avc.decoder")
+        );
+    }
+}
@@ -0,0 +1,76 @@
+/// Layout of raw YUV frames, the `MediaCodecInfo.CodecCapabilities.COLOR_Format*` constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorFormat {
+    Yuv420Planar,
+    Yuv420SemiPlanar,
+    Yuv420Flexible,
+    P010,
+}
+
+impl ColorFormat {
+    const COLOR_FORMAT_YUV420_PLANAR: i32 = 19;
+    const COLOR_FORMAT_YUV420_SEMI_PLANAR: i32 = 21;
+    const COLOR_FORMAT_YUV_P010: i32 = 54;
+    const COLOR_FORMAT_YUV420_FLEXIBLE: i32 = 0x7F420888;
+
+    /// Convert to the value stored under `AMEDIAFORMAT_KEY_COLOR_FORMAT`.
+    pub fn to_android_i32(self) -> i32 {
+        match self {
+            ColorFormat::Yuv420Planar => Self::COLOR_FORMAT_YUV420_PLANAR,
+            ColorFormat::Yuv420SemiPlanar => Self::COLOR_FORMAT_YUV420_SEMI_PLANAR,
+            ColorFormat::Yuv420Flexible => Self::COLOR_FORMAT_YUV420_FLEXIBLE,
+            ColorFormat::P010 => Self::COLOR_FORMAT_YUV_P010,
+        }
+    }
+
+    /// Bytes per luma sample.
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            ColorFormat::Yuv420Planar
+            | ColorFormat::Yuv420SemiPlanar
+            | ColorFormat::Yuv420Flexible => 1,
+            ColorFormat::P010 => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for ColorFormat {
+    type Error = ();
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            Self::COLOR_FORMAT_YUV420_PLANAR => Ok(ColorFormat::Yuv420Planar),
+            Self::COLOR_FORMAT_YUV420_SEMI_PLANAR => Ok(ColorFormat::Yuv420SemiPlanar),
+            Self::COLOR_FORMAT_YUV420_FLEXIBLE => Ok(ColorFormat::Yuv420Flexible),
+            Self::COLOR_FORMAT_YUV_P010 => Ok(ColorFormat::P010),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_android_values() {
+        for color_format in [
+            ColorFormat::Yuv420Planar,
+            ColorFormat::Yuv420SemiPlanar,
+            ColorFormat::Yuv420Flexible,
+            ColorFormat::P010,
+        ] {
+            assert_eq!(
+                ColorFormat::try_from(color_format.to_android_i32()),
+                Ok(color_format)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_android_values_are_rejected() {
+        // COLOR_FormatSurface, what hardware decoders report when rendering to a surface
+        assert_eq!(ColorFormat::try_from(0x7F000789), Err(()));
+        assert_eq!(ColorFormat::try_from(0), Err(()));
+    }
+}
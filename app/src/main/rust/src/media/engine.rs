@@ -1,32 +1,57 @@
 use super::{
     format::MediaFormat,
+    mime::MimeType,
     status::{AsMediaStatus, MediaStatus},
 };
 use crate::window::NativeWindow;
 use ndk_sys::{
-    AMediaCodec, AMediaCodec_configure, AMediaCodec_createCodecByName, AMediaCodec_delete,
-    AMediaCodec_dequeueInputBuffer, AMediaCodec_dequeueOutputBuffer, AMediaCodec_getInputBuffer,
+    AMediaCodec, AMediaCodecBufferInfo, AMediaCodec_configure, AMediaCodec_createCodecByName,
+    AMediaCodec_createDecoderByType, AMediaCodec_delete, AMediaCodec_dequeueInputBuffer,
+    AMediaCodec_dequeueOutputBuffer, AMediaCodec_getInputBuffer, AMediaCodec_getOutputFormat,
     AMediaCodec_queueInputBuffer, AMediaCodec_releaseOutputBuffer, AMediaCodec_setOutputSurface,
     AMediaCodec_start, AMediaCodec_stop, AMEDIACODEC_BUFFER_FLAG_CODEC_CONFIG,
-    AMEDIACODEC_CONFIGURE_FLAG_ENCODE, AMEDIACODEC_INFO_OUTPUT_BUFFERS_CHANGED,
-    AMEDIACODEC_INFO_OUTPUT_FORMAT_CHANGED, AMEDIACODEC_INFO_TRY_AGAIN_LATER,
+    AMEDIACODEC_BUFFER_FLAG_END_OF_STREAM, AMEDIACODEC_CONFIGURE_FLAG_ENCODE,
+    AMEDIACODEC_INFO_OUTPUT_BUFFERS_CHANGED, AMEDIACODEC_INFO_OUTPUT_FORMAT_CHANGED,
+    AMEDIACODEC_INFO_TRY_AGAIN_LATER,
 };
 use std::{
     ffi::{c_long, c_ulong, CString},
     mem::MaybeUninit,
     ops::{Deref, DerefMut},
     ptr::NonNull,
+    sync::Mutex,
     time::Duration,
 };
 
+// `BUFFER_FLAG_PARTIAL_FRAME` of the Java API, added in API level 26
+const BUFFER_FLAG_PARTIAL_FRAME: u32 = 8;
+// How long `queue_access_unit` waits for each buffer after the first of a split frame
+const PARTIAL_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Encapsulates a encoder/decoder.
-#[repr(transparent)]
-pub struct MediaEngine(NonNull<AMediaCodec>);
+pub struct MediaEngine {
+    codec: NonNull<AMediaCodec>,
+    // What the codec was configured with, kept for `reset`
+    format: Mutex<Option<MediaFormat>>,
+    is_encoder: bool,
+    window: Mutex<Option<NativeWindow>>,
+    codec_config: Mutex<Vec<Vec<u8>>>,
+}
 
-// SAFETY: `MediaEngine` is just a pointer. It should be safe to move to another thread.
+// SAFETY: `MediaEngine` is a pointer along with what it was configured with. It should be safe to
+// move to another thread.
 unsafe impl Send for MediaEngine {}
 
-// FIXME: Is this safe?
+// SAFETY: The decoder input task calls `dequeue_input_buffer`, `queue_input_buffer`, the `submit_*`
+// methods and `reset` while the render loop concurrently calls `dequeue_output`, `release_output`,
+// `output_format` and `set_output_surface`. Each `AMediaCodec_*` function of the NDK wraps the
+// native `android::MediaCodec`, which posts the request to the codec's own `ALooper` and waits for
+// the reply, so calls made from different threads are serialized there and never touch the codec
+// state directly. Input and output buffer indices are separate pools, which is why the NDK allows
+// one thread to feed input while another drains output. The pointer of a `MediaOutputBuffer` is
+// only invalidated by a stop, so callers must not `reset` while one is held. Deleting the codec
+// requires ownership so it cannot race with the shared references. The format is behind a mutex
+// since `set_rotation` can change it.
 unsafe impl Sync for MediaEngine {}
 
 impl Drop for MediaEngine {
@@ -35,7 +60,7 @@ impl Drop for MediaEngine {
             if let Err(e) = AMediaCodec_stop(self.as_inner()).success() {
                 log::error!("Error stoping the `MediaCodec`: {e}");
             }
-            AMediaCodec_delete(self.0.as_ptr());
+            AMediaCodec_delete(self.codec.as_ptr());
         }
     }
 }
@@ -43,25 +68,107 @@ impl Drop for MediaEngine {
 impl MediaEngine {
     /// Create a new `MediaEngine`.
     pub fn create_by_name(name: &str) -> Result<MediaEngine, MediaStatus> {
+        if name.is_empty() {
+            return Err(MediaStatus::EmptyDecoderName);
+        }
         let name = CString::new(name).map_err(|_| MediaStatus::StringNulError)?;
         let ptr = unsafe { AMediaCodec_createCodecByName(name.as_ptr().cast()) };
         if let Some(decoder) = NonNull::new(ptr) {
-            Ok(MediaEngine(decoder))
+            Ok(MediaEngine::from_codec(decoder))
         } else {
             Err(MediaStatus::MediaCodecCreationFailed)
         }
     }
 
+    /// Create the platform's default decoder for the MIME type.
+    pub fn create_by_type(mime_type: MimeType) -> Result<MediaEngine, MediaStatus> {
+        let mime_type = mime_type.to_android_cstr();
+        let ptr = unsafe { AMediaCodec_createDecoderByType(mime_type.as_ptr()) };
+        if let Some(decoder) = NonNull::new(ptr) {
+            Ok(MediaEngine::from_codec(decoder))
+        } else {
+            Err(MediaStatus::MediaCodecCreationFailed)
+        }
+    }
+
+    fn from_codec(codec: NonNull<AMediaCodec>) -> MediaEngine {
+        MediaEngine {
+            codec,
+            format: Mutex::new(None),
+            is_encoder: false,
+            window: Mutex::new(None),
+            codec_config: Mutex::new(Vec::new()),
+        }
+    }
+
     /// Convert to an Android NDK [AMediaCodec] pointer.
     pub fn as_inner(&self) -> *mut AMediaCodec {
-        self.0.as_ptr()
+        self.codec.as_ptr()
     }
 
     /// Initializes using the given format then start the `MediaCodec`.
     ///
-    /// This is a combination of the configure and start steps.
+    /// This is a combination of the configure and start steps. The format and window are kept so
+    /// that `reset` can repeat them.
     pub fn initialize(
         &mut self,
+        format: MediaFormat,
+        window: Option<&NativeWindow>,
+        is_encoder: bool,
+    ) -> Result<(), MediaStatus> {
+        self.configure_and_start(&format, window, is_encoder)?;
+        if let Ok(mut lock_guard) = self.format.lock() {
+            *lock_guard = Some(format);
+        }
+        self.is_encoder = is_encoder;
+        if let Ok(mut lock_guard) = self.window.lock() {
+            *lock_guard = window.cloned();
+        }
+        if let Ok(mut lock_guard) = self.codec_config.lock() {
+            lock_guard.clear();
+        }
+        Ok(())
+    }
+
+    /// Stops the codec, configures it again with what it was last given, then restarts it and
+    /// submits the codec specific data again.
+    ///
+    /// Unlike a flush this discards the codec's internal state, which may recover a codec that
+    /// keeps rejecting valid input. Buffers dequeued before the reset must not be used after it.
+    pub fn reset(&self) -> Result<(), MediaStatus> {
+        let format = self.format.lock().map_err(|_| MediaStatus::NotConfigured)?;
+        let format = format.as_ref().ok_or(MediaStatus::NotConfigured)?;
+        unsafe {
+            AMediaCodec_stop(self.as_inner()).success()?;
+        }
+        let window = match self.window.lock() {
+            Ok(lock_guard) => lock_guard.clone(),
+            Err(_) => None,
+        };
+        self.configure_and_start(format, window.as_ref(), self.is_encoder)?;
+
+        let codec_config = match self.codec_config.lock() {
+            Ok(lock_guard) => lock_guard.clone(),
+            Err(_) => Vec::new(),
+        };
+        for data in &codec_config {
+            self.submit_access_unit(data, 0, AMEDIACODEC_BUFFER_FLAG_CODEC_CONFIG as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Changes the clockwise rotation of the output. `MediaCodec` only reads the rotation when it
+    /// is configured, so this takes effect on the next `reset`.
+    pub fn set_rotation(&self, degrees: i32) -> Result<(), MediaStatus> {
+        let mut format = self.format.lock().map_err(|_| MediaStatus::NotConfigured)?;
+        format
+            .as_mut()
+            .ok_or(MediaStatus::NotConfigured)?
+            .set_rotation(degrees)
+    }
+
+    fn configure_and_start(
+        &self,
         format: &MediaFormat,
         window: Option<&NativeWindow>,
         is_encoder: bool,
@@ -89,23 +196,111 @@ impl MediaEngine {
         }
     }
 
+    /// Returns the format of the decoder output. The values are only meaningful after the codec
+    /// has signaled an output format change.
+    pub fn output_format(&self) -> Result<MediaFormat, MediaStatus> {
+        let ptr = unsafe { AMediaCodec_getOutputFormat(self.as_inner()) };
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(unsafe { MediaFormat::from_raw(ptr) }),
+            None => Err(MediaStatus::AllocationError),
+        }
+    }
+
     /// Resets the output of the decoder to a new surface.
     pub fn set_output_surface(&self, window: &NativeWindow) -> Result<(), MediaStatus> {
-        unsafe { AMediaCodec_setOutputSurface(self.as_inner(), window.as_inner()).success() }
+        unsafe {
+            AMediaCodec_setOutputSurface(self.as_inner(), window.as_inner()).success()?;
+        }
+        if let Ok(mut lock_guard) = self.window.lock() {
+            *lock_guard = Some(window.clone());
+        }
+        Ok(())
     }
 
     /// Submits the codec specific data. Must be called before submitting frame data.
+    ///
+    /// `data` must fit in one input buffer or `MediaStatus::BufferTooSmall` is returned. Data
+    /// split across several buffers, e.g. one per parameter set, is submitted with one call each.
     pub fn submit_codec_config(&self, data: &[u8]) -> Result<(), MediaStatus> {
-        let mut input_buffer = self.dequeue_input_buffer(MediaTimeout::INFINITE)?;
-        let min_len = data.len().min(input_buffer.len());
-        input_buffer[..min_len].copy_from_slice(&data[..min_len]);
+        self.submit_access_unit(data, 0, AMEDIACODEC_BUFFER_FLAG_CODEC_CONFIG as u32)?;
+        if let Ok(mut lock_guard) = self.codec_config.lock() {
+            lock_guard.push(data.to_vec());
+        }
+        Ok(())
+    }
 
-        self.queue_input_buffer(
-            input_buffer,
-            min_len as c_ulong,
-            0,
-            AMEDIACODEC_BUFFER_FLAG_CODEC_CONFIG as u32,
-        )
+    /// Copies a whole access unit into an input buffer and queues it.
+    pub fn submit_access_unit(
+        &self,
+        data: &[u8],
+        pts_micros: u64,
+        flags: u32,
+    ) -> Result<(), MediaStatus> {
+        self.submit_with(pts_micros, flags, |buffer| {
+            let dst = buffer
+                .get_mut(..data.len())
+                .ok_or(MediaStatus::BufferTooSmall)?;
+            dst.copy_from_slice(data);
+            Ok(data.len())
+        })
+    }
+
+    /// Copies an access unit into `input_buffer` and queues it.
+    ///
+    /// If it does not fit and `split` is true, the rest goes into more input buffers with all but
+    /// the last marked as a partial frame. Splitting needs API level 26. Otherwise
+    /// `MediaStatus::BufferTooSmall` is returned and the input buffer is handed back empty.
+    /// `MediaStatus::NoAvailableBuffer` means the codec stopped taking the parts, leaving the frame
+    /// incomplete.
+    pub fn queue_access_unit(
+        &self,
+        mut input_buffer: MediaInputBuffer,
+        data: &[u8],
+        pts_micros: u64,
+        flags: u32,
+        split: bool,
+    ) -> Result<(), MediaStatus> {
+        let mut remaining = data;
+        loop {
+            let n = remaining.len().min(input_buffer.remaining_capacity());
+            let fits = n == remaining.len();
+            if !fits && (!split || n == 0) {
+                self.queue_input_buffer(input_buffer, 0, 0, 0)?;
+                return Err(MediaStatus::BufferTooSmall);
+            }
+
+            input_buffer[..n].copy_from_slice(&remaining[..n]);
+            remaining = &remaining[n..];
+            if fits {
+                return self.queue_input_buffer(input_buffer, n as c_ulong, pts_micros, flags);
+            }
+            self.queue_input_buffer(
+                input_buffer,
+                n as c_ulong,
+                pts_micros,
+                flags | BUFFER_FLAG_PARTIAL_FRAME,
+            )?;
+            input_buffer = self.dequeue_input_buffer(MediaTimeout::new(PARTIAL_FRAME_TIMEOUT))?;
+        }
+    }
+
+    /// Lets `write` fill an input buffer in place then queues the number of bytes it returns.
+    ///
+    /// On error the input buffer is handed back to the codec empty.
+    pub fn submit_with<F>(&self, pts_micros: u64, flags: u32, write: F) -> Result<(), MediaStatus>
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, MediaStatus>,
+    {
+        let mut input_buffer = self.dequeue_input_buffer(MediaTimeout::INFINITE)?;
+        match write(&mut input_buffer) {
+            Ok(n) if n <= input_buffer.len() => {
+                self.queue_input_buffer(input_buffer, n as c_ulong, pts_micros, flags)
+            }
+            res => {
+                self.queue_input_buffer(input_buffer, 0, 0, 0)?;
+                res.and(Err(MediaStatus::BufferTooSmall))
+            }
+        }
     }
 
     /// Get the next available input buffer. Returns `MediaStatus::NoAvailableBuffer` if no buffer
@@ -155,13 +350,12 @@ impl MediaEngine {
         }
     }
 
-    /// Renders the decoder output to the surface.
-    #[inline(always)]
-    pub fn release_output_buffer(
+    /// Dequeues a decoded buffer along with its metadata. Returns `None` if the codec reported a
+    /// change in the output instead of a buffer.
+    pub fn dequeue_output(
         &self,
         timeout: MediaTimeout,
-        render: bool,
-    ) -> Result<(), MediaStatus> {
+    ) -> Result<Option<MediaOutputBuffer>, MediaStatus> {
         const TRY_AGAIN_LATER: c_long = AMEDIACODEC_INFO_TRY_AGAIN_LATER as c_long;
         const OUTPUT_FORMAT_CHANGED: c_long = AMEDIACODEC_INFO_OUTPUT_FORMAT_CHANGED as c_long;
         const OUTPUT_BUFFERS_CHANGED: c_long = AMEDIACODEC_INFO_OUTPUT_BUFFERS_CHANGED as c_long;
@@ -171,37 +365,59 @@ impl MediaEngine {
         match unsafe {
             AMediaCodec_dequeueOutputBuffer(self.as_inner(), buffer_info.as_mut_ptr(), timeout.0)
         } {
-            TRY_AGAIN_LATER => {
-                // This should be unreachable since timeout is set to be infinite
-                Err(MediaStatus::NoAvailableBuffer)
-            }
+            TRY_AGAIN_LATER => Err(MediaStatus::NoAvailableBuffer),
             OUTPUT_FORMAT_CHANGED => {
-                // ignoring format change assuming the underlying surface can handle it
-                Ok(())
+                // The surface handles the new format, `output_format` has the new geometry
+                Ok(None)
             }
 
             OUTPUT_BUFFERS_CHANGED => {
                 // Deprecated in API level 21 and this is using 23 as minimum. This should be
                 // unreachable.
-                Ok(())
+                Ok(None)
             }
             index => {
-                // Proper index, use on `AMediaCodec_releaseOutputBuffer`
-                unsafe {
-                    AMediaCodec_releaseOutputBuffer(self.as_inner(), index as c_ulong, render)
-                        .success()
-                }
+                // The info is only filled in when an actual buffer was dequeued
+                let info = BufferInfo::from(unsafe { buffer_info.assume_init() });
+                Ok(Some(MediaOutputBuffer {
+                    index: index as c_ulong,
+                    info,
+                }))
             }
         }
     }
+
+    /// Returns a buffer from `dequeue_output` to the codec, rendering it to the surface if
+    /// `render` is true.
+    pub fn release_output(
+        &self,
+        output_buffer: MediaOutputBuffer,
+        render: bool,
+    ) -> Result<(), MediaStatus> {
+        unsafe {
+            AMediaCodec_releaseOutputBuffer(self.as_inner(), output_buffer.index, render).success()
+        }
+    }
 }
 
 /// Input to the `MediaEngine`.
+///
+/// The buffer borrows the `MediaEngine` so it cannot outlive the codec, and it is consumed by
+/// `queue_input_buffer` so the index cannot be reused after being returned to the codec. It is
+/// `Send` because the codec memory has no thread affinity. The input task holds it across
+/// `.await` points so it may resume on a different runtime worker.
 pub struct MediaInputBuffer<'a> {
     index: c_ulong,
     buffer: &'a mut [u8],
 }
 
+impl<'a> MediaInputBuffer<'a> {
+    /// Number of bytes that can be written to the buffer.
+    pub fn remaining_capacity(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
 impl<'a> Deref for MediaInputBuffer<'a> {
     type Target = [u8];
 
@@ -218,6 +434,55 @@ impl<'a> DerefMut for MediaInputBuffer<'a> {
     }
 }
 
+/// Decoded buffer owned by the application until it is passed to `MediaEngine::release_output`.
+#[derive(Debug)]
+pub struct MediaOutputBuffer {
+    index: c_ulong,
+    info: BufferInfo,
+}
+
+impl MediaOutputBuffer {
+    /// Metadata of the decoded buffer.
+    pub fn info(&self) -> &BufferInfo {
+        &self.info
+    }
+}
+
+/// Typed version of [AMediaCodecBufferInfo].
+#[derive(Debug, Clone, Copy)]
+pub struct BufferInfo {
+    pub offset: i32,
+    pub size: i32,
+    pub presentation_time_us: i64,
+    pub flags: u32,
+}
+
+impl BufferInfo {
+    // `BUFFER_FLAG_KEY_FRAME` of the Java API, missing from the NDK headers
+    const FLAG_KEY_FRAME: u32 = 1;
+
+    /// Whether the buffer contains a key frame.
+    pub fn is_key_frame(&self) -> bool {
+        self.flags & Self::FLAG_KEY_FRAME != 0
+    }
+
+    /// Whether this is the last buffer the codec will output.
+    pub fn is_end_of_stream(&self) -> bool {
+        self.flags & AMEDIACODEC_BUFFER_FLAG_END_OF_STREAM as u32 != 0
+    }
+}
+
+impl From<AMediaCodecBufferInfo> for BufferInfo {
+    fn from(info: AMediaCodecBufferInfo) -> Self {
+        BufferInfo {
+            offset: info.offset,
+            size: info.size,
+            presentation_time_us: info.presentationTimeUs,
+            flags: info.flags,
+        }
+    }
+}
+
 /// Timeout value for `MediaEngine` methods.
 #[derive(Debug, Clone, Copy)]
 pub struct MediaTimeout(i64);
@@ -234,3 +499,21 @@ impl MediaTimeout {
         MediaTimeout(timeout_micros as i64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both are rejected before reaching the NDK
+    #[test]
+    fn invalid_decoder_names_are_rejected() {
+        assert_eq!(
+            MediaEngine::create_by_name("").err(),
+            Some(MediaStatus::EmptyDecoderName)
+        );
+        assert_eq!(
+            MediaEngine::create_by_name("c2.android\0.avc.decoder").err(),
+            Some(MediaStatus::StringNulError)
+        );
+    }
+}
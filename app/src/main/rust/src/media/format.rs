@@ -1,14 +1,24 @@
-use super::{MediaStatus, MimeType};
+use super::{ColorFormat, MediaStatus, MimeType};
 use ndk_sys::{
-    AMediaFormat, AMediaFormat_delete, AMediaFormat_new, AMediaFormat_setInt32,
-    AMediaFormat_setString, AMEDIAFORMAT_KEY_HEIGHT, AMEDIAFORMAT_KEY_MAX_HEIGHT,
-    AMEDIAFORMAT_KEY_MAX_WIDTH, AMEDIAFORMAT_KEY_MIME, AMEDIAFORMAT_KEY_PRIORITY,
-    AMEDIAFORMAT_KEY_WIDTH,
+    AMediaFormat, AMediaFormat_delete, AMediaFormat_getInt32, AMediaFormat_getString,
+    AMediaFormat_new, AMediaFormat_setInt32, AMediaFormat_setString,
+    AMEDIAFORMAT_KEY_CHANNEL_COUNT, AMEDIAFORMAT_KEY_COLOR_FORMAT, AMEDIAFORMAT_KEY_FRAME_RATE,
+    AMEDIAFORMAT_KEY_HEIGHT, AMEDIAFORMAT_KEY_MAX_HEIGHT, AMEDIAFORMAT_KEY_MAX_WIDTH,
+    AMEDIAFORMAT_KEY_MIME, AMEDIAFORMAT_KEY_PRIORITY, AMEDIAFORMAT_KEY_ROTATION,
+    AMEDIAFORMAT_KEY_SAMPLE_RATE, AMEDIAFORMAT_KEY_WIDTH,
+};
+use std::{
+    ffi::{c_char, CStr, CString},
+    ptr::NonNull,
 };
-use std::ptr::NonNull;
 
 // Only available starting API level 30
 const MEDIAFORMAT_KEY_LOW_LATENCY: &'static str = "low-latency\0";
+// Set by decoders on their output format, the bounds are inclusive
+const MEDIAFORMAT_KEY_CROP_LEFT: &'static str = "crop-left\0";
+const MEDIAFORMAT_KEY_CROP_TOP: &'static str = "crop-top\0";
+const MEDIAFORMAT_KEY_CROP_RIGHT: &'static str = "crop-right\0";
+const MEDIAFORMAT_KEY_CROP_BOTTOM: &'static str = "crop-bottom\0";
 
 /// RAII wrapper for [AMediaFormat].
 #[repr(transparent)]
@@ -22,7 +32,8 @@ impl Drop for MediaFormat {
     }
 }
 
-// FIXME: Is this safe?
+// SAFETY: `AMediaFormat` is a plain heap-allocated key-value store with no thread affinity.
+// `MediaFormat` is deliberately not `Sync` since the setters are unsynchronized.
 unsafe impl Send for MediaFormat {}
 
 impl MediaFormat {
@@ -35,6 +46,11 @@ impl MediaFormat {
         }
     }
 
+    /// Take ownership of an [AMediaFormat] pointer, e.g., one returned by the `MediaCodec`.
+    pub unsafe fn from_raw(ptr: NonNull<AMediaFormat>) -> MediaFormat {
+        MediaFormat(ptr)
+    }
+
     /// Convert to an Android NDK [AMediaFormat] pointer.
     pub fn as_inner(&self) -> *mut AMediaFormat {
         self.0.as_ptr()
@@ -67,6 +83,32 @@ impl MediaFormat {
         }
     }
 
+    /// Sets the sample rate of audio formats.
+    pub fn set_sample_rate(&mut self, sample_rate: i32) {
+        unsafe {
+            AMediaFormat_setInt32(self.as_inner(), AMEDIAFORMAT_KEY_SAMPLE_RATE, sample_rate);
+        }
+    }
+
+    /// Sets the number of channels of audio formats.
+    pub fn set_channel_count(&mut self, channels: i32) {
+        unsafe {
+            AMediaFormat_setInt32(self.as_inner(), AMEDIAFORMAT_KEY_CHANNEL_COUNT, channels);
+        }
+    }
+
+    /// Sets the clockwise rotation applied to the decoder output. Only 0, 90, 180 and 270 degrees
+    /// are accepted.
+    pub fn set_rotation(&mut self, degrees: i32) -> Result<(), MediaStatus> {
+        if !matches!(degrees, 0 | 90 | 180 | 270) {
+            return Err(MediaStatus::InvalidRotation);
+        }
+        unsafe {
+            AMediaFormat_setInt32(self.as_inner(), AMEDIAFORMAT_KEY_ROTATION, degrees);
+        }
+        Ok(())
+    }
+
     /// Sets the codec priority to be realtime or not. Added in API level 23.
     pub fn set_realtime_priority(&mut self, realtime: bool) {
         unsafe {
@@ -89,13 +131,207 @@ impl MediaFormat {
         }
     }
 
-    pub fn set_integer(&mut self, key: &str, val: i32) {
-        use std::ffi::CString;
+    /// Sets the layout of raw frames for decoding to buffers.
+    pub fn set_color_format(&mut self, color_format: ColorFormat) {
+        unsafe {
+            AMediaFormat_setInt32(
+                self.as_inner(),
+                AMEDIAFORMAT_KEY_COLOR_FORMAT,
+                color_format.to_android_i32(),
+            );
+        }
+    }
 
+    pub fn set_integer(&mut self, key: &str, val: i32) {
         if let Ok(cstring) = CString::new(key) {
             unsafe {
                 AMediaFormat_setInt32(self.as_inner(), cstring.as_ptr().cast(), val);
             }
         }
     }
+
+    /// Reads back an integer value. Returns `None` if the key is absent.
+    pub fn get_int32(&self, key: &str) -> Option<i32> {
+        let cstring = CString::new(key).ok()?;
+        self.get_int32_raw(cstring.as_ptr())
+    }
+
+    /// Reads back a string value. Returns `None` if the key is absent.
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        let cstring = CString::new(key).ok()?;
+        self.get_string_raw(cstring.as_ptr())
+    }
+
+    /// Returns the mime type, if set.
+    pub fn mime_type(&self) -> Option<String> {
+        self.get_string_raw(unsafe { AMEDIAFORMAT_KEY_MIME })
+    }
+
+    /// Returns the width, if set.
+    pub fn width(&self) -> Option<i32> {
+        self.get_int32_raw(unsafe { AMEDIAFORMAT_KEY_WIDTH })
+    }
+
+    /// Returns the height, if set.
+    pub fn height(&self) -> Option<i32> {
+        self.get_int32_raw(unsafe { AMEDIAFORMAT_KEY_HEIGHT })
+    }
+
+    /// Returns the crop rectangle as (left, top, right, bottom), if set. The bounds are inclusive.
+    pub fn crop(&self) -> Option<(i32, i32, i32, i32)> {
+        Some((
+            self.get_int32_raw(MEDIAFORMAT_KEY_CROP_LEFT.as_ptr().cast())?,
+            self.get_int32_raw(MEDIAFORMAT_KEY_CROP_TOP.as_ptr().cast())?,
+            self.get_int32_raw(MEDIAFORMAT_KEY_CROP_RIGHT.as_ptr().cast())?,
+            self.get_int32_raw(MEDIAFORMAT_KEY_CROP_BOTTOM.as_ptr().cast())?,
+        ))
+    }
+
+    /// Returns the size of the region that is shown, i.e. the crop rectangle if the codec set one
+    /// or the whole frame otherwise.
+    pub fn display_size(&self) -> Option<(i32, i32)> {
+        let (width, height) = match self.crop() {
+            Some((left, top, right, bottom)) => (right - left + 1, bottom - top + 1),
+            None => (self.width()?, self.height()?),
+        };
+        (width > 0 && height > 0).then_some((width, height))
+    }
+
+    /// Returns the frame rate, if set.
+    pub fn frame_rate(&self) -> Option<i32> {
+        self.get_int32_raw(unsafe { AMEDIAFORMAT_KEY_FRAME_RATE })
+    }
+
+    /// Checks that the keys a decoder needs are set before passing this to
+    /// `MediaEngine::initialize`, whose errors do not say what is missing.
+    pub fn validate_for_decode(&self, mime_type: MimeType) -> Result<(), MediaFormatError> {
+        match self.mime_type() {
+            None => return Err(MediaFormatError::MissingMimeType),
+            Some(s) if s != mime_type.to_android_str() => {
+                return Err(MediaFormatError::MimeTypeMismatch(s))
+            }
+            Some(_) => (),
+        }
+        if mime_type.is_video() {
+            if self.width().map_or(true, |w| w <= 0) {
+                return Err(MediaFormatError::MissingWidth);
+            }
+            if self.height().map_or(true, |h| h <= 0) {
+                return Err(MediaFormatError::MissingHeight);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the layout of raw frames, if set and known.
+    pub fn color_format(&self) -> Option<ColorFormat> {
+        let value = self.get_int32_raw(unsafe { AMEDIAFORMAT_KEY_COLOR_FORMAT })?;
+        ColorFormat::try_from(value).ok()
+    }
+
+    /// Returns whether low latency mode is enabled, if the codec reports it.
+    pub fn low_latency(&self) -> Option<bool> {
+        self.get_int32_raw(MEDIAFORMAT_KEY_LOW_LATENCY.as_ptr().cast())
+            .map(|val| val != 0)
+    }
+
+    fn get_int32_raw(&self, key: *const c_char) -> Option<i32> {
+        let mut val = 0;
+        if unsafe { AMediaFormat_getInt32(self.as_inner(), key, &mut val) } {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    fn get_string_raw(&self, key: *const c_char) -> Option<String> {
+        let mut ptr = std::ptr::null();
+        // The returned string is owned by the format and is only valid until the next access
+        if unsafe { AMediaFormat_getString(self.as_inner(), key, &mut ptr) } && !ptr.is_null() {
+            let s = unsafe { CStr::from_ptr(ptr) };
+            s.to_str().ok().map(|s| s.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+/// Reason a `MediaFormat` is not ready to configure a decoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaFormatError {
+    MissingMimeType,
+    MimeTypeMismatch(String),
+    MissingWidth,
+    MissingHeight,
+}
+
+impl std::fmt::Display for MediaFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaFormatError::MissingMimeType => write!(f, "MIME type is not set"),
+            MediaFormatError::MimeTypeMismatch(s) => write!(f, "Unexpected MIME type {s}"),
+            MediaFormatError::MissingWidth => write!(f, "Width is not set"),
+            MediaFormatError::MissingHeight => write!(f, "Height is not set"),
+        }
+    }
+}
+
+impl std::error::Error for MediaFormatError {}
+
+// `AMediaFormat` lives in libmediandk so these only run on a device or emulator
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_missing_mime_type() {
+        let mut format = MediaFormat::new().unwrap();
+        format.set_resolution(1920, 1080);
+        assert_eq!(
+            format.validate_for_decode(MimeType::VideoH264),
+            Err(MediaFormatError::MissingMimeType)
+        );
+    }
+
+    #[test]
+    fn rejects_other_mime_type() {
+        let mut format = MediaFormat::new().unwrap();
+        format.set_mime_type(MimeType::VideoH265);
+        format.set_resolution(1920, 1080);
+        assert_eq!(
+            format.validate_for_decode(MimeType::VideoH264),
+            Err(MediaFormatError::MimeTypeMismatch(
+                MimeType::VideoH265.to_android_str().to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_zero_resolution() {
+        let mut format = MediaFormat::new().unwrap();
+        format.set_mime_type(MimeType::VideoH264);
+        assert_eq!(
+            format.validate_for_decode(MimeType::VideoH264),
+            Err(MediaFormatError::MissingWidth)
+        );
+        format.set_resolution(1920, 0);
+        assert_eq!(
+            format.validate_for_decode(MimeType::VideoH264),
+            Err(MediaFormatError::MissingHeight)
+        );
+        format.set_resolution(0, 1080);
+        assert_eq!(
+            format.validate_for_decode(MimeType::VideoH264),
+            Err(MediaFormatError::MissingWidth)
+        );
+        format.set_resolution(1920, 1080);
+        assert_eq!(format.validate_for_decode(MimeType::VideoH264), Ok(()));
+    }
+
+    #[test]
+    fn audio_needs_no_resolution() {
+        let mut format = MediaFormat::new().unwrap();
+        format.set_mime_type(MimeType::AudioOpus);
+        assert_eq!(format.validate_for_decode(MimeType::AudioOpus), Ok(()));
+    }
 }
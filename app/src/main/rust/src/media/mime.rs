@@ -37,6 +37,21 @@ impl MimeType {
         s.trim_end_matches('\0')
     }
 
+    /// Returns true if the MIME type is an audio format.
+    pub fn is_audio(self) -> bool {
+        match self {
+            MimeType::AudioPcma | MimeType::AudioPcmu | MimeType::AudioOpus => true,
+            MimeType::VideoAv1 | MimeType::VideoH264 | MimeType::VideoH265 | MimeType::VideoVp8 => {
+                false
+            }
+        }
+    }
+
+    /// Returns true if the MIME type is a video format.
+    pub fn is_video(self) -> bool {
+        !self.is_audio()
+    }
+
     /// Convert `MimeType` to SDP MIME type.
     pub fn to_sdp_str(self) -> &'static str {
         match self {
@@ -73,3 +88,61 @@ impl FromStr for MimeType {
         Err(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [MimeType; 7] = [
+        MimeType::AudioPcma,
+        MimeType::AudioPcmu,
+        MimeType::AudioOpus,
+        MimeType::VideoAv1,
+        MimeType::VideoH264,
+        MimeType::VideoH265,
+        MimeType::VideoVp8,
+    ];
+
+    #[test]
+    fn audio_and_video_are_exclusive() {
+        let audio: Vec<MimeType> = ALL.into_iter().filter(|m| m.is_audio()).collect();
+        assert_eq!(
+            audio,
+            [
+                MimeType::AudioPcma,
+                MimeType::AudioPcmu,
+                MimeType::AudioOpus
+            ]
+        );
+        for mime_type in ALL {
+            assert_ne!(mime_type.is_audio(), mime_type.is_video(), "{mime_type:?}");
+            // The variants are named after their kind
+            assert_eq!(
+                mime_type.is_audio(),
+                mime_type.to_sdp_str().starts_with("audio/"),
+                "{mime_type:?}"
+            );
+        }
+    }
+    #[test]
+    fn parses_its_own_strings() {
+        for mime_type in ALL {
+            assert_eq!(MimeType::from_str(mime_type.to_sdp_str()), Ok(mime_type));
+            assert_eq!(
+                MimeType::from_str(mime_type.to_android_str()),
+                Ok(mime_type)
+            );
+            assert_eq!(
+                mime_type.to_android_cstr().to_str(),
+                Ok(mime_type.to_android_str())
+            );
+        }
+    }
+
+    #[test]
+    fn parsing_ignores_case() {
+        assert_eq!(MimeType::from_str("VIDEO/h264"), Ok(MimeType::VideoH264));
+        assert_eq!(MimeType::from_str("Video/AVC"), Ok(MimeType::VideoH264));
+        assert_eq!(MimeType::from_str("video/mp4v-es"), Err(()));
+    }
+}
@@ -1,11 +1,13 @@
+mod color;
 mod engine;
 mod format;
 mod mime;
 mod status;
 
 pub use self::{
-    engine::{MediaEngine, MediaTimeout},
-    format::MediaFormat,
+    color::ColorFormat,
+    engine::{MediaEngine, MediaInputBuffer, MediaTimeout},
+    format::{MediaFormat, MediaFormatError},
     mime::MimeType,
     status::MediaStatus,
 };
@@ -7,18 +7,43 @@ pub enum MediaStatus {
     AllocationError,
     StringNulError,
     MediaCodecCreationFailed,
+    EmptyDecoderName,
     NoAvailableBuffer,
+    BufferTooSmall,
+    InvalidRotation,
+    NotConfigured,
 }
 
-// Required for `std::error::Error`. Format using `std::fmt::Debug`.
+// Required for `std::error::Error`. Format using `std::fmt::Debug` except for the NDK codes.
 impl std::fmt::Display for MediaStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        match self {
+            MediaStatus::Sys(status) => std::fmt::Display::fmt(status, f),
+            _ => std::fmt::Debug::fmt(self, f),
+        }
     }
 }
 
 impl std::error::Error for MediaStatus {}
 
+impl MediaStatus {
+    /// Whether the device ran out of codec instances, e.g. while another app is playing video.
+    pub fn is_insufficient_resource(&self) -> bool {
+        matches!(
+            self,
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_INSUFFICIENT_RESOURCE)
+        )
+    }
+
+    /// Whether the codec rejected the input as malformed.
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIA_ERROR_MALFORMED)
+        )
+    }
+}
+
 /// Helper trait for ease of error handling of `ndk_sys::media_status_t`.
 pub trait AsMediaStatus: private::Sealed {
     /// Return `Ok(())` if `AMEDIA_OK` else return an error.
@@ -79,6 +104,13 @@ pub enum NonZeroSysMediaStatus {
     AMEDIA_IMGREADER_IMAGE_NOT_LOCKED = -30005,
 }
 
+/// Prints the NDK constant name along with its value, e.g. `AMEDIA_ERROR_MALFORMED (-10001)`.
+impl std::fmt::Display for NonZeroSysMediaStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?} ({})", *self as i32)
+    }
+}
+
 impl TryFrom<media_status_t> for NonZeroSysMediaStatus {
     type Error = ();
 
@@ -118,3 +150,62 @@ impl TryFrom<media_status_t> for NonZeroSysMediaStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_ndk_name_and_value() {
+        let status = NonZeroSysMediaStatus::AMEDIA_ERROR_MALFORMED;
+        assert_eq!(status.to_string(), "AMEDIA_ERROR_MALFORMED (-10001)");
+        assert_eq!(
+            MediaStatus::Sys(status).to_string(),
+            "AMEDIA_ERROR_MALFORMED (-10001)"
+        );
+        assert_eq!(
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_INSUFFICIENT_RESOURCE)
+                .to_string(),
+            "AMEDIACODEC_ERROR_INSUFFICIENT_RESOURCE (1100)"
+        );
+        assert_eq!(MediaStatus::BufferTooSmall.to_string(), "BufferTooSmall");
+    }
+
+    #[test]
+    fn ok_is_not_an_error() {
+        assert_eq!(media_status_t::AMEDIA_OK.success(), Ok(()));
+        assert_eq!(
+            media_status_t::AMEDIA_ERROR_MALFORMED.success(),
+            Err(MediaStatus::Sys(
+                NonZeroSysMediaStatus::AMEDIA_ERROR_MALFORMED
+            ))
+        );
+    }
+
+    #[test]
+    fn classifies_insufficient_resource() {
+        let status =
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_INSUFFICIENT_RESOURCE);
+        assert!(status.is_insufficient_resource());
+        for status in [
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_RECLAIMED),
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIA_ERROR_MALFORMED),
+            MediaStatus::NoAvailableBuffer,
+        ] {
+            assert!(!status.is_insufficient_resource());
+        }
+    }
+
+    #[test]
+    fn classifies_malformed() {
+        let status = MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIA_ERROR_MALFORMED);
+        assert!(status.is_malformed());
+        for status in [
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_INSUFFICIENT_RESOURCE),
+            MediaStatus::Sys(NonZeroSysMediaStatus::AMEDIACODEC_ERROR_RECLAIMED),
+            MediaStatus::NoAvailableBuffer,
+        ] {
+            assert!(!status.is_malformed());
+        }
+    }
+}
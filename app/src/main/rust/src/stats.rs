@@ -0,0 +1,581 @@
+use crate::webrtc::NegotiatedCodec;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often `QualityMeter` produces a score.
+const QUALITY_WINDOW: Duration = Duration::from_secs(1);
+/// Score lost per percent of lost frames, capped at `MAX_LOSS_PENALTY`.
+const LOSS_PENALTY_PER_PERCENT: f64 = 8.0;
+const MAX_LOSS_PENALTY: f64 = 60.0;
+/// Score lost per millisecond of jitter, capped at `MAX_JITTER_PENALTY`.
+const JITTER_PENALTY_PER_MS: f64 = 0.5;
+const MAX_JITTER_PENALTY: f64 = 20.0;
+/// Decode latency that costs nothing, and the score lost per millisecond above it.
+const FREE_DECODE_LATENCY_MS: f64 = 20.0;
+const LATENCY_PENALTY_PER_MS: f64 = 0.25;
+const MAX_LATENCY_PENALTY: f64 = 20.0;
+/// How often `DiagnosticsLog` produces a line.
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often `BitrateEstimator` revises its target.
+const BITRATE_WINDOW: Duration = Duration::from_secs(1);
+/// Frame loss above this means the link is congested, below `UNDERUSE_LOSS` that it may have room
+/// for more. The target is held in between so that it doesn't oscillate.
+const OVERUSE_LOSS: f64 = 0.10;
+const UNDERUSE_LOSS: f64 = 0.02;
+/// Jitter this far above its baseline means queues are building up along the path.
+const OVERUSE_JITTER_MS: f64 = 20.0;
+/// How fast the jitter baseline may rise per window, so that a lasting change of route is not
+/// taken for congestion forever.
+const JITTER_BASELINE_DRIFT_MS: f64 = 1.0;
+/// Backing off goes below what actually arrived, ramping up is gradual.
+const BITRATE_DECREASE: f64 = 0.85;
+const BITRATE_INCREASE: f64 = 1.08;
+/// The target only grows while the sender uses most of it. A static screen says nothing about the
+/// capacity of the link.
+const BITRATE_UTILIZATION: f64 = 0.75;
+/// No ramping up for this long after backing off.
+const HOLD_AFTER_DECREASE: Duration = Duration::from_secs(3);
+/// Targets within this fraction of the last reported one are not reported.
+const BITRATE_REPORT_THRESHOLD: f64 = 0.05;
+const MIN_TARGET_BITRATE: f64 = 500_000.0;
+const MAX_TARGET_BITRATE: f64 = 50_000_000.0;
+
+/// Coding type of a decoded frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Idr,
+    I,
+    P,
+    B,
+    Other,
+}
+
+/// Counters describing the state of the decoder. Updated from the decoder loops and read from
+/// anywhere else, so every field is an atomic or behind a lock.
+#[derive(Debug, Default)]
+pub struct DecoderStats {
+    frames_in_flight: AtomicUsize,
+    last_queued: Mutex<Option<Instant>>,
+    idr_frames: AtomicU64,
+    i_frames: AtomicU64,
+    p_frames: AtomicU64,
+    b_frames: AtomicU64,
+    other_frames: AtomicU64,
+    frames_since_idr: AtomicU64,
+    idr_interval: AtomicU64,
+    low_latency: AtomicBool,
+    stalls: AtomicU64,
+    degraded: AtomicBool,
+    recovering: AtomicBool,
+    recovery_pts: AtomicU64,
+    decode_latency_us: AtomicU64,
+    quality: AtomicU32,
+    // Bits of the `f64`, 0 until one is resolved
+    frame_rate: AtomicU64,
+    decoder_name: Mutex<Option<String>>,
+    negotiated_codec: Mutex<Option<NegotiatedCodec>>,
+}
+
+impl DecoderStats {
+    /// Clear the counters at the start of a new session.
+    pub fn reset(&self) {
+        self.frames_in_flight.store(0, Ordering::Release);
+        if let Ok(mut lock_guard) = self.last_queued.lock() {
+            *lock_guard = None;
+        }
+        self.idr_frames.store(0, Ordering::Release);
+        self.i_frames.store(0, Ordering::Release);
+        self.p_frames.store(0, Ordering::Release);
+        self.b_frames.store(0, Ordering::Release);
+        self.other_frames.store(0, Ordering::Release);
+        self.frames_since_idr.store(0, Ordering::Release);
+        self.idr_interval.store(0, Ordering::Release);
+        self.low_latency.store(false, Ordering::Release);
+        self.stalls.store(0, Ordering::Release);
+        self.degraded.store(false, Ordering::Release);
+        self.recovering.store(false, Ordering::Release);
+        self.recovery_pts.store(0, Ordering::Release);
+        self.decode_latency_us.store(0, Ordering::Release);
+        self.quality.store(100, Ordering::Release);
+        self.frame_rate.store(0, Ordering::Release);
+        self.set_decoder_name(None);
+        self.set_negotiated_codec(None);
+    }
+
+    /// Number of frames queued to the decoder whose output has not been released yet.
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames_in_flight.load(Ordering::Acquire)
+    }
+
+    /// Record that a frame was queued to the decoder.
+    pub fn frame_queued(&self) {
+        self.frames_in_flight.fetch_add(1, Ordering::AcqRel);
+        if let Ok(mut lock_guard) = self.last_queued.lock() {
+            *lock_guard = Some(Instant::now());
+        }
+    }
+
+    /// When a frame was last queued to the decoder, `None` if none was this session.
+    pub fn last_queued(&self) -> Option<Instant> {
+        self.last_queued
+            .lock()
+            .ok()
+            .and_then(|last_queued| *last_queued)
+    }
+
+    /// Forget the frames in flight after the codec discarded them.
+    pub fn frames_discarded(&self) {
+        self.frames_in_flight.store(0, Ordering::Release);
+    }
+
+    /// Record that an output buffer was released.
+    pub fn frame_released(&self) {
+        // Saturate since the output might include frames queued before the last reset
+        let _ = self
+            .frames_in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+    }
+
+    /// Record the type of a frame submitted to the decoder.
+    pub fn record_frame(&self, frame_type: FrameType) {
+        let counter = match frame_type {
+            FrameType::Idr => {
+                let interval = self.frames_since_idr.swap(0, Ordering::AcqRel);
+                // The first IDR of a session does not end an interval
+                if self.idr_frames.load(Ordering::Acquire) > 0 {
+                    self.idr_interval.store(interval, Ordering::Release);
+                }
+                &self.idr_frames
+            }
+            FrameType::I => &self.i_frames,
+            FrameType::P => &self.p_frames,
+            FrameType::B => &self.b_frames,
+            FrameType::Other => &self.other_frames,
+        };
+        counter.fetch_add(1, Ordering::AcqRel);
+        self.frames_since_idr.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Number of frames of the given type submitted to the decoder.
+    pub fn frame_count(&self, frame_type: FrameType) -> u64 {
+        let counter = match frame_type {
+            FrameType::Idr => &self.idr_frames,
+            FrameType::I => &self.i_frames,
+            FrameType::P => &self.p_frames,
+            FrameType::B => &self.b_frames,
+            FrameType::Other => &self.other_frames,
+        };
+        counter.load(Ordering::Acquire)
+    }
+
+    /// Number of frames between the two most recent IDRs, or 0 if fewer than two were seen.
+    pub fn idr_interval(&self) -> u64 {
+        self.idr_interval.load(Ordering::Acquire)
+    }
+
+    /// Record whether the codec reported low latency mode as enabled.
+    pub fn set_low_latency(&self, enabled: bool) {
+        self.low_latency.store(enabled, Ordering::Release);
+    }
+
+    /// Whether the current codec is running in low latency mode.
+    pub fn low_latency(&self) -> bool {
+        self.low_latency.load(Ordering::Acquire)
+    }
+
+    /// Record a track read that timed out without any packet.
+    pub fn record_stall(&self) {
+        self.stalls.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Number of track reads that timed out in this session.
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Acquire)
+    }
+
+    /// Mark whether the sender appears to have stalled.
+    pub fn set_degraded(&self, degraded: bool) {
+        self.degraded.store(degraded, Ordering::Release);
+    }
+
+    /// Whether the connection is currently considered degraded.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Acquire)
+    }
+
+    /// Enter the recovering state after losing the reference frame. Nothing is rendered until the
+    /// next IDR is decoded.
+    pub fn start_recovery(&self) {
+        self.recovery_pts.store(u64::MAX, Ordering::Release);
+        self.recovering.store(true, Ordering::Release);
+    }
+
+    /// Record the timestamp of the IDR that ends the current recovery.
+    pub fn set_recovery_point(&self, pts: u64) {
+        if self.is_recovering() {
+            self.recovery_pts.store(pts, Ordering::Release);
+        }
+    }
+
+    /// Returns true if a decoded frame with the given timestamp can be shown, leaving the
+    /// recovering state once the recovery IDR is reached.
+    pub fn recovery_reached(&self, pts: u64) -> bool {
+        if !self.is_recovering() {
+            return true;
+        }
+        if pts >= self.recovery_pts.load(Ordering::Acquire) {
+            self.recovering.store(false, Ordering::Release);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the decoder is waiting for an IDR after a loss.
+    pub fn is_recovering(&self) -> bool {
+        self.recovering.load(Ordering::Acquire)
+    }
+
+    /// Record the time a frame spent in the decoder, smoothed over the last few frames.
+    pub fn record_decode_latency(&self, latency_us: u64) {
+        let _ = self
+            .decode_latency_us
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+                Some((old * 7 + latency_us) / 8)
+            });
+    }
+
+    /// Smoothed time from queueing a frame to its output, in microseconds.
+    pub fn decode_latency_us(&self) -> u64 {
+        self.decode_latency_us.load(Ordering::Acquire)
+    }
+
+    /// Record the name of the decoder in use, e.g. `c2.qti.avc.decoder`.
+    pub fn set_decoder_name(&self, name: Option<String>) {
+        if let Ok(mut lock_guard) = self.decoder_name.lock() {
+            *lock_guard = name;
+        }
+    }
+
+    /// Name of the decoder in use, if one was chosen yet.
+    pub fn decoder_name(&self) -> Option<String> {
+        self.decoder_name.lock().ok().and_then(|name| name.clone())
+    }
+
+    /// Record what was negotiated for the track being decoded.
+    pub fn set_negotiated_codec(&self, codec: Option<NegotiatedCodec>) {
+        if let Ok(mut lock_guard) = self.negotiated_codec.lock() {
+            *lock_guard = codec;
+        }
+    }
+
+    /// What was negotiated for the track being decoded, if decoding started.
+    pub fn negotiated_codec(&self) -> Option<NegotiatedCodec> {
+        self.negotiated_codec
+            .lock()
+            .ok()
+            .and_then(|codec| codec.clone())
+    }
+
+    /// Record the nominal frame rate of the stream.
+    pub fn set_frame_rate(&self, frame_rate: f64) {
+        self.frame_rate
+            .store(frame_rate.to_bits(), Ordering::Release);
+    }
+
+    /// Nominal frame rate of the stream, if it was resolved yet.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let frame_rate = f64::from_bits(self.frame_rate.load(Ordering::Acquire));
+        (frame_rate > 0.0).then_some(frame_rate)
+    }
+
+    /// Record the latest score from `QualityMeter`.
+    pub fn set_quality(&self, score: u32) {
+        self.quality.store(score, Ordering::Release);
+    }
+
+    /// Connection quality from 0 to 100, see `quality_score`.
+    pub fn quality(&self) -> u32 {
+        self.quality.load(Ordering::Acquire)
+    }
+}
+
+/// Scores the playback experience from 0 (unusable) to 100 (perfect).
+///
+/// Starts at 100 and subtracts a capped penalty for each of:
+/// - `loss`, the fraction of frames lost, 8 points per percent up to 60
+/// - `jitter_ms`, the variation in frame arrival times, 0.5 points per ms up to 20
+/// - `decode_latency_ms`, 0.25 points per ms above 20ms up to 20
+///
+/// Loss weighs the most since it causes visible corruption and keyframe requests, while jitter and
+/// latency only make the stream feel sluggish.
+pub fn quality_score(loss: f64, jitter_ms: f64, decode_latency_ms: f64) -> u32 {
+    let loss_penalty = (loss * 100.0 * LOSS_PENALTY_PER_PERCENT).min(MAX_LOSS_PENALTY);
+    let jitter_penalty = (jitter_ms * JITTER_PENALTY_PER_MS).min(MAX_JITTER_PENALTY);
+    let latency_penalty = ((decode_latency_ms - FREE_DECODE_LATENCY_MS).max(0.0)
+        * LATENCY_PENALTY_PER_MS)
+        .min(MAX_LATENCY_PENALTY);
+    let score = 100.0 - loss_penalty - jitter_penalty - latency_penalty;
+    score.clamp(0.0, 100.0).round() as u32
+}
+
+/// Tracks frame arrivals and losses of the input loop to compute `quality_score` once per
+/// `QUALITY_WINDOW`.
+pub struct QualityMeter {
+    window_start: Instant,
+    received: u32,
+    lost: u32,
+    total_lost: u64,
+    last_arrival: Option<Instant>,
+    last_interval: Option<Duration>,
+    jitter_ms: f64,
+}
+
+impl Default for QualityMeter {
+    fn default() -> Self {
+        QualityMeter {
+            window_start: Instant::now(),
+            received: 0,
+            lost: 0,
+            total_lost: 0,
+            last_arrival: None,
+            last_interval: None,
+            jitter_ms: 0.0,
+        }
+    }
+}
+
+impl QualityMeter {
+    /// Record a frame that was received whole.
+    pub fn record_frame(&mut self) {
+        let now = Instant::now();
+        self.received += 1;
+        if let Some(last_arrival) = self.last_arrival.replace(now) {
+            let interval = now - last_arrival;
+            if let Some(last_interval) = self.last_interval.replace(interval) {
+                // Smoothed like the interarrival jitter of RFC 3550
+                let delta = interval.as_secs_f64() - last_interval.as_secs_f64();
+                self.jitter_ms += (delta.abs() * 1000.0 - self.jitter_ms) / 16.0;
+            }
+        }
+    }
+
+    /// Record a frame that was lost or dropped because of missing packets.
+    pub fn record_loss(&mut self) {
+        self.lost += 1;
+        self.total_lost += 1;
+    }
+
+    /// Number of frames lost since the meter was created.
+    pub fn total_lost(&self) -> u64 {
+        self.total_lost
+    }
+
+    /// Smoothed variation in frame arrival times, in milliseconds.
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter_ms
+    }
+
+    /// Returns a new score if the current window is over.
+    pub fn poll(&mut self, decode_latency_us: u64) -> Option<u32> {
+        if self.window_start.elapsed() < QUALITY_WINDOW {
+            return None;
+        }
+        let score = if self.received == 0 {
+            // Nothing arrived for a whole window
+            0
+        } else {
+            let loss = self.lost as f64 / (self.received + self.lost) as f64;
+            quality_score(loss, self.jitter_ms, decode_latency_us as f64 / 1000.0)
+        };
+        self.window_start = Instant::now();
+        self.received = 0;
+        self.lost = 0;
+        Some(score)
+    }
+}
+
+/// Summarizes the input loop once per `DIAGNOSTICS_INTERVAL` in a single line meant to be copied
+/// from `adb logcat` into bug reports. Only counts, so it is cheap enough to leave running.
+pub struct DiagnosticsLog {
+    window_start: Instant,
+    packets: u64,
+    bytes: u64,
+    window_packets: u64,
+    window_bytes: u64,
+    window_lost: u64,
+    window_rendered: u64,
+}
+
+impl Default for DiagnosticsLog {
+    fn default() -> Self {
+        DiagnosticsLog {
+            window_start: Instant::now(),
+            packets: 0,
+            bytes: 0,
+            window_packets: 0,
+            window_bytes: 0,
+            window_lost: 0,
+            window_rendered: 0,
+        }
+    }
+}
+
+impl DiagnosticsLog {
+    /// Record a payload received from the track.
+    pub fn record_packet(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// Returns the line for the last window if it is over and `enabled` is set.
+    pub fn poll(
+        &mut self,
+        enabled: bool,
+        quality: &QualityMeter,
+        stats: &DecoderStats,
+        rendered_frames: u64,
+    ) -> Option<String> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < DIAGNOSTICS_INTERVAL {
+            return None;
+        }
+        let secs = elapsed.as_secs_f64();
+        let packets = self.packets - self.window_packets;
+        let bytes = self.bytes - self.window_bytes;
+        let lost = quality.total_lost().saturating_sub(self.window_lost);
+        let rendered = rendered_frames.saturating_sub(self.window_rendered);
+
+        self.window_start = Instant::now();
+        self.window_packets = self.packets;
+        self.window_bytes = self.bytes;
+        self.window_lost = quality.total_lost();
+        self.window_rendered = rendered_frames;
+
+        enabled.then(|| {
+            format!(
+                "diag: {} pkts ({:.0}/s), {:.2} Mbit/s, {} lost, jitter {:.1}ms, {:.1} fps, \
+                 latency {}ms, quality {}",
+                self.packets,
+                packets as f64 / secs,
+                bytes as f64 * 8.0 / secs / 1_000_000.0,
+                lost,
+                quality.jitter_ms(),
+                rendered as f64 / secs,
+                stats.decode_latency_us() / 1000,
+                stats.quality(),
+            )
+        })
+    }
+}
+
+/// Receiver side estimate of the bitrate the link can carry, from what the input loop sees: bytes
+/// received, frames lost and how much their arrival jitters. Decreases multiplicatively on loss or
+/// rising jitter and increases in small steps otherwise, like the loss-based controller of GCC.
+pub struct BitrateEstimator {
+    window_start: Instant,
+    bytes: u64,
+    received: u32,
+    lost: u32,
+    target_bps: Option<f64>,
+    reported_bps: Option<u32>,
+    jitter_baseline_ms: Option<f64>,
+    hold_until: Option<Instant>,
+}
+
+impl Default for BitrateEstimator {
+    fn default() -> Self {
+        BitrateEstimator {
+            window_start: Instant::now(),
+            bytes: 0,
+            received: 0,
+            lost: 0,
+            target_bps: None,
+            reported_bps: None,
+            jitter_baseline_ms: None,
+            hold_until: None,
+        }
+    }
+}
+
+impl BitrateEstimator {
+    /// Record a payload received from the track.
+    pub fn record_packet(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /// Record a frame that was received whole.
+    pub fn record_frame(&mut self) {
+        self.received += 1;
+    }
+
+    /// Record a frame that was lost or dropped because of missing packets.
+    pub fn record_loss(&mut self) {
+        self.lost += 1;
+    }
+
+    /// Returns a new target in bits per second if the current window is over and the target moved
+    /// enough to be worth telling the sender.
+    pub fn poll(&mut self, jitter_ms: f64) -> Option<u32> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < BITRATE_WINDOW {
+            return None;
+        }
+        let incoming_bps = self.bytes as f64 * 8.0 / elapsed.as_secs_f64();
+        let frames = self.received + self.lost;
+        let lost = self.lost;
+        self.window_start = Instant::now();
+        self.bytes = 0;
+        self.received = 0;
+        self.lost = 0;
+
+        // Nothing to judge the link by, e.g. the track stalled
+        if frames == 0 {
+            return None;
+        }
+        let loss = lost as f64 / frames as f64;
+        let baseline = match self.jitter_baseline_ms {
+            Some(baseline) => jitter_ms.min(baseline + JITTER_BASELINE_DRIFT_MS),
+            None => jitter_ms,
+        };
+        self.jitter_baseline_ms = Some(baseline);
+        let overuse = loss > OVERUSE_LOSS || jitter_ms - baseline > OVERUSE_JITTER_MS;
+
+        let now = Instant::now();
+        let target = match self.target_bps {
+            // Start from what the sender already does
+            None => incoming_bps,
+            Some(target) if overuse => {
+                self.hold_until = Some(now + HOLD_AFTER_DECREASE);
+                (incoming_bps * BITRATE_DECREASE).min(target)
+            }
+            Some(target) => {
+                let holding = self.hold_until.map_or(false, |until| now < until);
+                let utilized = incoming_bps >= target * BITRATE_UTILIZATION;
+                if loss < UNDERUSE_LOSS && utilized && !holding {
+                    target * BITRATE_INCREASE
+                } else {
+                    target
+                }
+            }
+        };
+        let target = target.clamp(MIN_TARGET_BITRATE, MAX_TARGET_BITRATE);
+        self.target_bps = Some(target);
+
+        let target = target as u32;
+        let changed = self.reported_bps.map_or(true, |reported| {
+            (target as f64 - reported as f64).abs() > reported as f64 * BITRATE_REPORT_THRESHOLD
+        });
+        if changed {
+            self.reported_bps = Some(target);
+            Some(target)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use crate::{gamepad::NUM_AXES, NativeLibSingleton};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+use webrtc::data_channel::{
+    data_channel_message::DataChannelMessage, data_channel_state::RTCDataChannelState,
+    RTCDataChannel,
+};
+
+/// Version of the control protocol, sent to the host in `ControlMessage::Hello`. Bump it when the
+/// meaning of an existing message changes. New messages and new optional fields don't need a bump
+/// since both sides ignore what they don't know.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Largest message `RTCDataChannel::on_message` can receive, the host has the same limit.
+const MAX_MESSAGE_SIZE: usize = 16384;
+/// Room left in a message for everything in a `ControlMessage::Clipboard` except the text.
+const CLIPBOARD_MESSAGE_OVERHEAD: usize = 64;
+/// Most bytes a single character takes once escaped as JSON, e.g. `\u0001`.
+const MAX_ESCAPED_CHAR_LEN: usize = 6;
+/// Incoming clipboard text beyond this is dropped instead of growing the buffer without bound.
+const MAX_CLIPBOARD_LEN: usize = 1 << 20;
+/// Gamepad changes within this are sent as one message so that moving a stick doesn't flood the
+/// channel.
+const GAMEPAD_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Messages exchanged with the host over the control data channel, as JSON objects whose `type`
+/// field names the variant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlMessage {
+    /// First message each side sends, announcing the protocol version it speaks.
+    Hello { version: u32 },
+    /// New shape of the remote cursor as tightly packed RGBA pixels, base64 encoded.
+    CursorImage {
+        hotspot_x: i32,
+        hotspot_y: i32,
+        width: i32,
+        height: i32,
+        #[serde(with = "base64_bytes")]
+        rgba: Vec<u8>,
+    },
+    /// Where the hotspot of the remote cursor is, as a fraction of the video's width and height
+    /// so that it doesn't depend on the resolution.
+    CursorPosition { x: f32, y: f32 },
+    /// Clipboard text. Text too large for one message is split, with `more` set on all but the
+    /// last part.
+    Clipboard {
+        text: String,
+        #[serde(default)]
+        more: bool,
+    },
+    /// Sent when the decoder sees B-frames, which add latency without helping a live stream.
+    RequestNoBFrames,
+    /// Current state of the controller, see `crate::gamepad` for the layout.
+    GamepadState { buttons: u32, axes: [f32; NUM_AXES] },
+    /// Bitrate in bits per second that the host's encoder should aim for, estimated from loss and
+    /// jitter on the client. Only sent when it changes noticeably, the host keeps using the last
+    /// one until then.
+    TargetBitrate { bps: u32 },
+    /// Any message type added after this client was built. Never sent.
+    #[serde(other, skip_serializing)]
+    Unknown,
+}
+
+/// Binary data as a base64 string. As an array of JSON numbers it takes about four bytes per byte,
+/// which leaves even small cursor images over `MAX_MESSAGE_SIZE`.
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Install the message handler on the control data channel.
+pub fn handle_data_channel(data_channel: Arc<RTCDataChannel>, singleton: Arc<NativeLibSingleton>) {
+    let opened = Arc::new(Notify::new());
+    let opened_clone = opened.clone();
+    data_channel.on_open(Box::new(move || {
+        opened_clone.notify_one();
+        Box::pin(async {})
+    }));
+    let closed = Arc::new(Notify::new());
+    let closed_clone = closed.clone();
+    data_channel.on_close(Box::new(move || {
+        closed_clone.notify_one();
+        Box::pin(async {})
+    }));
+    tokio::spawn(send_to_host(
+        data_channel.clone(),
+        singleton.clone(),
+        opened,
+        closed,
+    ));
+
+    let clipboard = Arc::new(Mutex::new(ClipboardBuffer::default()));
+    data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let singleton = singleton.clone();
+        let clipboard = clipboard.clone();
+        Box::pin(async move {
+            match serde_json::from_slice::<ControlMessage>(&msg.data) {
+                Ok(msg) => handle_message(&singleton, &clipboard, msg),
+                Err(e) => log::error!("Invalid control message: {e}"),
+            }
+        })
+    }));
+}
+
+/// Announce the protocol version, then forward local events to the host until the data channel
+/// closes.
+async fn send_to_host(
+    data_channel: Arc<RTCDataChannel>,
+    singleton: Arc<NativeLibSingleton>,
+    opened: Arc<Notify>,
+    closed: Arc<Notify>,
+) {
+    let mut clipboard = singleton.watch_clipboard();
+    let mut b_frames = singleton.watch_b_frames();
+    let mut gamepad = singleton.watch_gamepad();
+    let mut target_bitrate = singleton.watch_target_bitrate();
+    // Only sync what gets copied after connecting
+    clipboard.borrow_and_update();
+    b_frames.borrow_and_update();
+    // Re-estimated from scratch by the new session's decoder
+    target_bitrate.borrow_and_update();
+    let mut last_gamepad_send: Option<Instant> = None;
+
+    // `notify_one` keeps the permit so opening after the check is not missed
+    if data_channel.ready_state() != RTCDataChannelState::Open {
+        tokio::select! {
+            _ = opened.notified() => (),
+            _ = closed.notified() => return,
+        }
+    }
+    let hello = ControlMessage::Hello {
+        version: PROTOCOL_VERSION,
+    };
+    if !send_message(&data_channel, &hello).await {
+        return;
+    }
+
+    let max_message_size = max_message_size(&data_channel).await;
+    loop {
+        tokio::select! {
+            changed = clipboard.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                // Clone so that the lock is not held across the sends
+                let text = clipboard.borrow_and_update().clone();
+                if let Some(text) = text {
+                    send_clipboard(&data_channel, &text, max_message_size).await;
+                }
+            }
+            changed = b_frames.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                send_message(&data_channel, &ControlMessage::RequestNoBFrames).await;
+            }
+            changed = target_bitrate.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let bps = *target_bitrate.borrow_and_update();
+                if let Some(bps) = bps {
+                    send_message(&data_channel, &ControlMessage::TargetBitrate { bps }).await;
+                }
+            }
+            changed = gamepad.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                // Whatever changes until then goes out together
+                if let Some(t) = last_gamepad_send {
+                    tokio::time::sleep_until(t + GAMEPAD_INTERVAL).await;
+                }
+                last_gamepad_send = Some(Instant::now());
+                let state = *gamepad.borrow_and_update();
+                let msg = ControlMessage::GamepadState {
+                    buttons: state.buttons(),
+                    axes: state.axes(),
+                };
+                send_message(&data_channel, &msg).await;
+            }
+            _ = closed.notified() => break,
+        }
+    }
+}
+
+async fn send_clipboard(data_channel: &RTCDataChannel, text: &str, max_message_size: usize) {
+    let Some(max_len) = clipboard_text_len(max_message_size) else {
+        log::error!("Clipboard text can't fit in messages of {max_message_size} bytes");
+        return;
+    };
+    for msg in clipboard_messages(text, max_len) {
+        if !send_message(data_channel, &msg).await {
+            break;
+        }
+    }
+}
+
+/// Room for the escaped text in each clipboard message, `None` if a character might not fit.
+fn clipboard_text_len(max_message_size: usize) -> Option<usize> {
+    let max_len = max_message_size.saturating_sub(CLIPBOARD_MESSAGE_OVERHEAD);
+    (max_len >= MAX_ESCAPED_CHAR_LEN).then_some(max_len)
+}
+
+/// The `ControlMessage::Clipboard` parts of `text`, each at most `max_len` bytes of escaped text.
+fn clipboard_messages(text: &str, max_len: usize) -> Vec<ControlMessage> {
+    let chunks = split_clipboard(text, max_len);
+    let num_chunks = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| ControlMessage::Clipboard {
+            text: chunk.to_owned(),
+            more: i + 1 < num_chunks,
+        })
+        .collect()
+}
+
+/// Returns whether the message was sent. Failures are logged.
+async fn send_message(data_channel: &RTCDataChannel, msg: &ControlMessage) -> bool {
+    let json = match serde_json::to_string(msg) {
+        Ok(json) => json,
+        Err(e) => {
+            log::error!("Failed to serialize control message: {e}");
+            return false;
+        }
+    };
+    match data_channel.send_text(json).await {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("Failed to send control message: {e}");
+            false
+        }
+    }
+}
+
+async fn max_message_size(data_channel: &RTCDataChannel) -> usize {
+    let transport = data_channel.transport().await.and_then(|t| t.upgrade());
+    // The SCTP transport reports 0 when it does not know the limit
+    match transport.map(|t| t.get_capabilities().max_message_size as usize) {
+        Some(size) if size > 0 => size.min(MAX_MESSAGE_SIZE),
+        _ => MAX_MESSAGE_SIZE,
+    }
+}
+
+/// Split `text` on character boundaries such that each part is at most `max_len` bytes once
+/// escaped as a JSON string.
+fn split_clipboard(text: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        let escaped_len = match c {
+            '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+            '\0'..='\u{1f}' => MAX_ESCAPED_CHAR_LEN,
+            _ => c.len_utf8(),
+        };
+        if len + escaped_len > max_len {
+            chunks.push(&text[start..i]);
+            start = i;
+            len = 0;
+        }
+        len += escaped_len;
+    }
+    chunks.push(&text[start..]);
+    chunks
+}
+
+/// Clipboard text from the host that is still being received.
+#[derive(Default)]
+struct ClipboardBuffer {
+    text: String,
+    /// Set when the text exceeded `MAX_CLIPBOARD_LEN`. The remaining parts are ignored.
+    overflowed: bool,
+}
+
+impl ClipboardBuffer {
+    /// Add a part of the text. Returns the whole text once the last part arrived.
+    fn push(&mut self, text: &str, more: bool) -> Option<String> {
+        if !self.overflowed && self.text.len() + text.len() > MAX_CLIPBOARD_LEN {
+            log::error!("Clipboard text from the host is too large");
+            self.text.clear();
+            self.overflowed = true;
+        }
+        if self.overflowed {
+            self.overflowed = more;
+            return None;
+        }
+        self.text.push_str(text);
+        if more {
+            return None;
+        }
+        Some(std::mem::take(&mut self.text))
+    }
+}
+
+fn handle_message(
+    singleton: &NativeLibSingleton,
+    clipboard: &Mutex<ClipboardBuffer>,
+    msg: ControlMessage,
+) {
+    match msg {
+        ControlMessage::Hello { version } => {
+            if version != PROTOCOL_VERSION {
+                log::warn!("Host speaks control protocol {version}, expected {PROTOCOL_VERSION}");
+            }
+        }
+        ControlMessage::CursorImage {
+            hotspot_x,
+            hotspot_y,
+            width,
+            height,
+            rgba,
+        } => {
+            let expected_len = width as i64 * height as i64 * 4;
+            if width <= 0 || height <= 0 || rgba.len() as i64 != expected_len {
+                log::error!(
+                    "Malformed cursor image: {width}x{height}, {} bytes",
+                    rgba.len()
+                );
+                return;
+            }
+            let result = singleton.with_env(|env| {
+                singleton.set_remote_cursor(env, hotspot_x, hotspot_y, width, height, &rgba)
+            });
+            if let Err(e) = result {
+                log::error!("Failed to set the remote cursor: {e}");
+            }
+        }
+        ControlMessage::CursorPosition { x, y } => {
+            if !x.is_finite() || !y.is_finite() {
+                log::error!("Malformed cursor position: {x}, {y}");
+                return;
+            }
+            let (x, y) = (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+            let result = singleton.with_env(|env| singleton.set_remote_cursor_position(env, x, y));
+            if let Err(e) = result {
+                log::error!("Failed to move the remote cursor: {e}");
+            }
+        }
+        ControlMessage::Clipboard { text, more } => {
+            let Ok(mut buffer) = clipboard.lock() else {
+                return;
+            };
+            let Some(text) = buffer.push(&text, more) else {
+                return;
+            };
+            let result = singleton.with_env(|env| singleton.set_clipboard(env, &text));
+            if let Err(e) = result {
+                log::error!("Failed to set the clipboard: {e}");
+            }
+        }
+        // Only ever sent to the host
+        ControlMessage::RequestNoBFrames => log::warn!("Unexpected RequestNoBFrames from the host"),
+        ControlMessage::GamepadState { .. } => log::warn!("Unexpected GamepadState from the host"),
+        ControlMessage::TargetBitrate { .. } => {
+            log::warn!("Unexpected TargetBitrate from the host")
+        }
+        // From a newer host, nothing to do with it
+        ControlMessage::Unknown => log::debug!("Ignoring a control message of an unknown type"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escaped_len(chunk: &str) -> usize {
+        // Without the quotes
+        serde_json::to_string(chunk).unwrap().len() - 2
+    }
+
+    #[test]
+    fn splits_on_character_boundaries() {
+        // 2, 3 and 4 byte characters that can't fit evenly into the limit
+        let text = "aéé€😀€😀b";
+        for max_len in 4..12 {
+            let chunks = split_clipboard(text, max_len);
+            assert_eq!(chunks.concat(), text);
+            for chunk in chunks {
+                assert!(!chunk.is_empty());
+                assert!(escaped_len(chunk) <= max_len, "{chunk:?} over {max_len}");
+            }
+        }
+        assert_eq!(split_clipboard("aé😀", 4), ["aé", "😀"]);
+    }
+
+    #[test]
+    fn counts_escaped_length() {
+        let text = "\"\\\n\u{1}\u{7f}x";
+        for max_len in 6..16 {
+            let chunks = split_clipboard(text, max_len);
+            assert_eq!(chunks.concat(), text);
+            for chunk in chunks {
+                assert!(escaped_len(chunk) <= max_len, "{chunk:?} over {max_len}");
+            }
+        }
+        // Each quote takes two bytes once escaped
+        assert_eq!(split_clipboard("\"\"\"", 4), ["\"\"", "\""]);
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split_clipboard("", 16), [""]);
+        assert_eq!(split_clipboard("text", 4), ["text"]);
+    }
+
+    #[test]
+    fn tiny_message_sizes_leave_no_room() {
+        assert_eq!(clipboard_text_len(0), None);
+        assert_eq!(clipboard_text_len(CLIPBOARD_MESSAGE_OVERHEAD), None);
+        assert_eq!(
+            clipboard_text_len(CLIPBOARD_MESSAGE_OVERHEAD + MAX_ESCAPED_CHAR_LEN - 1),
+            None
+        );
+        assert_eq!(
+            clipboard_text_len(CLIPBOARD_MESSAGE_OVERHEAD + MAX_ESCAPED_CHAR_LEN),
+            Some(MAX_ESCAPED_CHAR_LEN)
+        );
+        assert_eq!(
+            clipboard_text_len(MAX_MESSAGE_SIZE),
+            Some(MAX_MESSAGE_SIZE - CLIPBOARD_MESSAGE_OVERHEAD)
+        );
+    }
+
+    #[test]
+    fn more_is_set_on_all_but_the_last_part() {
+        let more: Vec<bool> = clipboard_messages("abcdefg", 3)
+            .into_iter()
+            .map(|msg| match msg {
+                ControlMessage::Clipboard { more, .. } => more,
+                msg => panic!("Unexpected {msg:?}"),
+            })
+            .collect();
+        assert_eq!(more, [true, true, false]);
+
+        let msgs = clipboard_messages("abc", 3);
+        assert!(matches!(
+            &msgs[..],
+            [ControlMessage::Clipboard { more: false, .. }]
+        ));
+    }
+
+    #[test]
+    fn joins_parts() {
+        let mut buffer = ClipboardBuffer::default();
+        let mut text = None;
+        for msg in clipboard_messages("aé😀€b", 3) {
+            let ControlMessage::Clipboard { text: part, more } = msg else {
+                panic!("Unexpected {msg:?}");
+            };
+            assert!(text.is_none());
+            text = buffer.push(&part, more);
+        }
+        assert_eq!(text.as_deref(), Some("aé😀€b"));
+    }
+
+    #[test]
+    fn drops_text_over_the_limit() {
+        let mut buffer = ClipboardBuffer::default();
+        let part = "a".repeat(MAX_CLIPBOARD_LEN);
+        assert_eq!(buffer.push(&part, true), None);
+        assert_eq!(buffer.push("b", true), None);
+        assert_eq!(buffer.push("c", false), None);
+        // The next text starts over
+        assert_eq!(buffer.push("d", false).as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn clipboard_without_more_is_the_last_part() {
+        let msg: ControlMessage =
+            serde_json::from_str(r#"{"type":"Clipboard","text":"é"}"#).unwrap();
+        assert!(matches!(
+            msg,
+            ControlMessage::Clipboard { text, more: false } if text == "é"
+        ));
+    }
+
+    #[test]
+    fn unknown_messages_deserialize() {
+        for json in [
+            r#"{"type":"FromTheFuture"}"#,
+            r#"{"type":"FromTheFuture","field":[1,2,3]}"#,
+        ] {
+            let msg: ControlMessage = serde_json::from_str(json).unwrap();
+            assert!(matches!(msg, ControlMessage::Unknown));
+        }
+        assert!(serde_json::to_string(&ControlMessage::Unknown).is_err());
+    }
+
+    #[test]
+    fn cursor_image_is_base64() {
+        let msg = ControlMessage::CursorImage {
+            hotspot_x: 0,
+            hotspot_y: 0,
+            width: 1,
+            height: 1,
+            rgba: vec![0xff, 0x00, 0x80, 0xff],
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""rgba":"/wCA/w==""#), "{json}");
+        let msg: ControlMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            msg,
+            ControlMessage::CursorImage { rgba, .. } if rgba == [0xff, 0x00, 0x80, 0xff]
+        ));
+    }
+}
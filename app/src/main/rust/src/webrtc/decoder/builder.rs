@@ -1,12 +1,13 @@
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::{Arc, Mutex}, collections::HashMap};
 use webrtc::{track::track_remote::TrackRemote, rtp_transceiver::rtp_receiver::RTCRtpReceiver};
-use webrtc_helper::{DecoderBuilder, Codec, codecs::{CodecType, h264::{H264Codec, H264Profile}}, WebRtcPeer};
-use crate::{NativeLibSingleton, media::MimeType};
+use webrtc_helper::{DecoderBuilder, Codec, codecs::{CodecType, h264::H264Codec}, WebRtcPeer};
+use crate::{DecoderPreference, NativeLibSingleton, media::MimeType};
 
 pub struct AndroidDecoderBuilder {
     singleton: Arc<NativeLibSingleton>,
     codecs: Vec<Codec>,
     codec_map: HashMap<MimeType, String>,
+    session_id: u64,
 }
 
 impl DecoderBuilder for AndroidDecoderBuilder {
@@ -26,12 +27,16 @@ impl DecoderBuilder for AndroidDecoderBuilder {
     ) {
         let singleton = self.singleton;
         let codec_map = self.codec_map;
+        let session_id = self.session_id;
 
         let handle = tokio::runtime::Handle::current();
         handle.spawn(async move {
             log::info!("start_decoder");
-            if let Err(e) = super::start_decoder(track, rtp_receiver, peer, singleton, codec_map).await {
-                log::error!("Decoder failure: {e:?}");
+            if let Err(e) =
+                super::start_decoder(track, rtp_receiver, peer, singleton, codec_map, session_id)
+                    .await
+            {
+                log::error!("Decoder failure: {e}");
             }
             log::info!("start_decoder exit");
         });
@@ -42,75 +47,110 @@ impl AndroidDecoderBuilder {
     pub fn new(
         singleton: Arc<NativeLibSingleton>,
     ) -> Result<AndroidDecoderBuilder, jni::errors::Error> {
+        let session_id = singleton.current_session();
         let mut codecs = Vec::new();
         let mut codec_map = HashMap::new();
-        {
-            // Array of (mime type str, Android profile id -> Codec)
-            let mime_types: [(MimeType, fn(i32) -> Option<Codec>); 3] = [
-                (MimeType::VideoAv1, |_| None),
-                (MimeType::VideoH265, |_| None),
-                (MimeType::VideoH264, |id| {
-                    h264_profile_from_android_id(id).map(|profile| H264Codec::new(profile).into())
-                }),
-            ];
-
-            let env = singleton.global_vm().attach_current_thread()?;
-
-            for (mime_type, converter) in mime_types {
-                let decoder_name = match singleton.choose_decoder_for_type(&env, mime_type) {
-                    Ok(Some(decoder_name)) => decoder_name,
-                    Ok(None) => {
-                        log::info!("No decoder for {mime_type:?}");
-                        continue;
-                    }
-                    Err(e) => {
-                        log::error!("Error while finding decoder: {e}");
-                        continue;
-                    }
-                };
-                let profiles =
-                    match singleton.list_profiles_for_decoder(&env, &decoder_name, mime_type) {
-                        Ok(Some(profiles)) => profiles,
-                        Ok(None) => {
-                            log::info!("Possibly invalid decoder name: {decoder_name}");
-                            continue;
-                        }
-                        Err(e) => {
-                            log::error!("Error while listing profiles: {e}");
-                            continue;
-                        }
-                    };
-                for id in profiles {
-                    if let Some(codec) = converter(id) {
-                        codecs.push(codec);
-                    }
+
+        // Array of (mime type str, Android profile id -> Codec)
+        let mime_types: [(MimeType, fn(i32) -> Option<Codec>); 3] = [
+            (MimeType::VideoAv1, |_| None),
+            (MimeType::VideoH265, |_| None),
+            (MimeType::VideoH264, |id| {
+                super::h264::profile_from_android_id(id)
+                    .map(|profile| H264Codec::new(profile).into())
+            }),
+        ];
+
+        let preference = singleton.decoder_preference();
+        let discovered = discover_decoders(
+            &singleton,
+            &mime_types.map(|(mime_type, _)| mime_type),
+            preference,
+        )?;
+
+        for (mime_type, converter) in mime_types {
+            let Some((_, decoder_name, profiles)) =
+                discovered.iter().find(|(m, _, _)| *m == mime_type) else {
+                continue;
+            };
+            for &id in profiles {
+                if let Some(codec) = converter(id) {
+                    codecs.push(codec);
                 }
-                codec_map.insert(mime_type, decoder_name);
             }
+            codec_map.insert(mime_type, decoder_name.clone());
         }
+
         Ok(AndroidDecoderBuilder {
             singleton,
             codecs,
             codec_map,
+            session_id,
         })
     }
+
+    /// The RTCP feedback to declare for each advertised codec.
+    pub fn rtcp_feedback(&self) -> Vec<(MimeType, &'static [&'static str])> {
+        self.codec_map
+            .keys()
+            .map(|&mime_type| (mime_type, super::rtcp_helper::RTCP_FEEDBACK))
+            .collect()
+    }
 }
 
-// https://developer.android.com/reference/android/media/MediaCodecInfo.CodecProfileLevel
-fn h264_profile_from_android_id(id: i32) -> Option<H264Profile> {
-    match id {
-        1 => Some(H264Profile::Baseline),
-        2 => Some(H264Profile::Main),
-        4 => Some(H264Profile::Extended),
-        8 => Some(H264Profile::High),
-        16 => Some(H264Profile::High10),
-        32 => Some(H264Profile::High422),
-        64 => Some(H264Profile::High444),
-        65536 => Some(H264Profile::ConstrainedBaseline),
-        524288 => Some(H264Profile::ConstrainedHigh),
-        id => {
-            log::info!("Unknown H.264 profile id: {}", id);
-            None
+// Decoder discovery goes through Kotlin and the device's codecs do not change while the process
+// is alive, so the result is reused by restarted sessions unless the preference changed.
+static DISCOVERED_DECODERS: Mutex<Option<(DecoderPreference, Vec<(MimeType, String, Vec<i32>)>)>> =
+    Mutex::new(None);
+
+/// Returns (MIME type, decoder name, Android profile ids) for each MIME type with a decoder.
+fn discover_decoders(
+    singleton: &NativeLibSingleton,
+    mime_types: &[MimeType],
+    preference: DecoderPreference,
+) -> Result<Vec<(MimeType, String, Vec<i32>)>, jni::errors::Error> {
+    if let Ok(lock_guard) = DISCOVERED_DECODERS.lock() {
+        if let Some((cached_preference, discovered)) = lock_guard.as_ref() {
+            if *cached_preference == preference {
+                return Ok(discovered.clone());
+            }
         }
     }
+
+    let discovered = singleton.with_env(|env| {
+        let mut discovered = Vec::new();
+
+        for &mime_type in mime_types {
+            let decoder_name = match singleton.choose_decoder_for_type(env, mime_type, preference) {
+                Ok(Some(decoder_name)) => decoder_name,
+                Ok(None) => {
+                    log::info!("No decoder for {mime_type:?}");
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Error while finding decoder: {e}");
+                    continue;
+                }
+            };
+            let profiles = singleton.list_profiles_for_decoder(env, &decoder_name, mime_type);
+            let profiles = match profiles {
+                Ok(Some(profiles)) => profiles,
+                Ok(None) => {
+                    log::info!("Possibly invalid decoder name: {decoder_name}");
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Error while listing profiles: {e}");
+                    continue;
+                }
+            };
+            discovered.push((mime_type, decoder_name, profiles));
+        }
+        Ok(discovered)
+    })?;
+
+    if let Ok(mut lock_guard) = DISCOVERED_DECODERS.lock() {
+        *lock_guard = Some((preference, discovered.clone()));
+    }
+    Ok(discovered)
 }
@@ -1,20 +1,36 @@
 use super::AndroidDecoder;
+use crate::stats::FrameType;
 use webrtc_helper::codecs::{
-    h264::{H264Codec, H264Depacketizer},
+    h264::{H264Codec, H264Depacketizer, H264Profile},
     util::nalu_chunks,
 };
 
 const NALU_TYPE_BITMASK: u8 = 0x1F;
+const NALU_TYPE_SLICE: u8 = 1;
+const NALU_TYPE_IDR_PIC: u8 = 5;
 const NALU_TYPE_SPS: u8 = 7;
 const NALU_TYPE_PPS: u8 = 8;
 const NALU_DELIMITER: [u8; 4] = [0, 0, 0, 1];
 
+// https://developer.android.com/reference/android/media/MediaCodecInfo.CodecProfileLevel
+const AVC_PROFILE_BASELINE: i32 = 0x01;
+const AVC_PROFILE_MAIN: i32 = 0x02;
+const AVC_PROFILE_EXTENDED: i32 = 0x04;
+const AVC_PROFILE_HIGH: i32 = 0x08;
+const AVC_PROFILE_HIGH10: i32 = 0x10;
+const AVC_PROFILE_HIGH422: i32 = 0x20;
+const AVC_PROFILE_HIGH444: i32 = 0x40;
+const AVC_PROFILE_CONSTRAINED_BASELINE: i32 = 0x10000;
+const AVC_PROFILE_CONSTRAINED_HIGH: i32 = 0x80000;
+
 #[derive(Default)]
 pub struct H264Decoder {
     sps: Option<Vec<u8>>,
     pps: Option<Vec<u8>>,
-    codec_config: Option<Vec<u8>>,
+    // SPS and PPS with start codes, kept apart since some decoders reject them combined
+    codec_config: Option<[Vec<u8>; 2]>,
     resolution: Option<(i32, i32)>,
+    sps_info: Option<SpsInfo>,
 }
 
 impl AndroidDecoder for H264Decoder {
@@ -28,8 +44,24 @@ impl AndroidDecoder for H264Decoder {
         self.resolution
     }
 
-    fn codec_config(&self) -> Option<&[u8]> {
-        self.codec_config.as_ref().map(|x| x.as_slice())
+    fn codec_config(&self) -> &[Vec<u8>] {
+        self.codec_config.as_ref().map_or(&[], |x| x.as_slice())
+    }
+
+    fn display_size(&self) -> Option<(i32, i32)> {
+        self.sps_info.map(|info| info.display_size)
+    }
+
+    fn frame_rate(&self) -> Option<f64> {
+        self.sps_info.and_then(|info| info.frame_rate)
+    }
+
+    fn is_keyframe(payload: &[u8]) -> bool {
+        nalu_chunks(payload).any(|nalu| {
+            nalu.first().map_or(false, |header| {
+                header & NALU_TYPE_BITMASK == NALU_TYPE_IDR_PIC
+            })
+        })
     }
 
     fn read_payload(&mut self, payload: &[u8]) -> Result<(), ()> {
@@ -41,6 +73,7 @@ impl AndroidDecoder for H264Decoder {
                 NALU_TYPE_SPS => {
                     if let Some((width, height)) = H264Codec::get_resolution(nalu) {
                         self.resolution = Some((width as i32, height as i32));
+                        self.sps_info = parse_sps(nalu);
                         self.sps = Some(nalu.to_vec());
                         self.build_codec_config();
                     }
@@ -61,15 +94,420 @@ impl H264Decoder {
         if self.sps.is_some() && self.pps.is_some() && self.resolution.is_some() {
             let sps = self.sps.as_ref().unwrap();
             let pps = self.pps.as_ref().unwrap();
-            let mut codec_config =
-                Vec::with_capacity(2 * NALU_DELIMITER.len() + sps.len() + pps.len());
+            let with_delimiter = |nalu: &[u8]| [&NALU_DELIMITER[..], nalu].concat();
+
+            self.codec_config = Some([with_delimiter(sps), with_delimiter(pps)]);
+        }
+    }
+}
+
+/// Map a `MediaCodecInfo.CodecProfileLevel` AVC profile constant to the profile advertised in
+/// the SDP. This is the only place the Android constants are interpreted.
+pub fn profile_from_android_id(id: i32) -> Option<H264Profile> {
+    match id {
+        AVC_PROFILE_BASELINE => Some(H264Profile::Baseline),
+        AVC_PROFILE_MAIN => Some(H264Profile::Main),
+        AVC_PROFILE_EXTENDED => Some(H264Profile::Extended),
+        AVC_PROFILE_HIGH => Some(H264Profile::High),
+        AVC_PROFILE_HIGH10 => Some(H264Profile::High10),
+        AVC_PROFILE_HIGH422 => Some(H264Profile::High422),
+        AVC_PROFILE_HIGH444 => Some(H264Profile::High444),
+        AVC_PROFILE_CONSTRAINED_BASELINE => Some(H264Profile::ConstrainedBaseline),
+        AVC_PROFILE_CONSTRAINED_HIGH => Some(H264Profile::ConstrainedHigh),
+        id => {
+            log::info!("Unknown H.264 profile id: {}", id);
+            None
+        }
+    }
+}
+
+/// Classify a NALU (without the start code) by the coding type of the picture it starts. Returns
+/// `None` for parameter sets, SEI and the slices after the first one of a picture. The codec
+/// outputs one buffer per picture, so only the NALUs classified here count as frames.
+pub fn classify_nalu(nalu: &[u8]) -> Option<FrameType> {
+    let header = nalu.first()?;
+    let nalu_type = header & NALU_TYPE_BITMASK;
+    if nalu_type != NALU_TYPE_SLICE && nalu_type != NALU_TYPE_IDR_PIC {
+        return None;
+    }
+    // slice_header() starts with first_mb_in_slice then slice_type, both ue(v)
+    let mut reader = ExpGolombReader::new(&nalu[1..]);
+    if reader.read_ue()? != 0 {
+        return None;
+    }
+    if nalu_type == NALU_TYPE_IDR_PIC {
+        return Some(FrameType::Idr);
+    }
+    let frame_type = match reader.read_ue().map(|t| t % 5) {
+        Some(0) | Some(3) => FrameType::P, // P, SP
+        Some(1) => FrameType::B,
+        Some(2) | Some(4) => FrameType::I, // I, SI
+        _ => FrameType::Other,
+    };
+    Some(frame_type)
+}
 
-            codec_config.extend_from_slice(&NALU_DELIMITER);
-            codec_config.extend_from_slice(sps);
-            codec_config.extend_from_slice(&NALU_DELIMITER);
-            codec_config.extend_from_slice(pps);
+/// What the decoder uses from an SPS besides the coded resolution.
+#[derive(Debug, Clone, Copy)]
+struct SpsInfo {
+    /// Size of the picture without the cropped margins, e.g. 1920x1080 for a 1920x1088 frame.
+    display_size: (i32, i32),
+    /// From the timing info of the VUI. Most encoders write it but it is optional.
+    frame_rate: Option<f64>,
+}
+
+/// Parse an SPS (without the start code).
+fn parse_sps(sps: &[u8]) -> Option<SpsInfo> {
+    let mut reader = ExpGolombReader::new(sps.get(1..)?);
+    let profile_idc = reader.read_bits(8)?;
+    reader.read_bits(16)?; // constraint_set flags, level_idc
+    reader.read_ue()?; // seq_parameter_set_id
+
+    // ChromaArrayType, 4:2:0 unless the profile says otherwise
+    let mut chroma_array_type = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        let chroma_format_idc = reader.read_ue()?;
+        chroma_array_type = chroma_format_idc;
+        // separate_colour_plane_flag, the planes are then coded like monochrome
+        if chroma_format_idc == 3 && reader.read_bit()? == 1 {
+            chroma_array_type = 0;
+        }
+        reader.read_ue()?; // bit_depth_luma_minus8
+        reader.read_ue()?; // bit_depth_chroma_minus8
+        reader.read_bit()?; // qpprime_y_zero_transform_bypass_flag
+        if reader.read_bit()? == 1 {
+            let num_lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..num_lists {
+                if reader.read_bit()? == 1 {
+                    reader.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    reader.read_ue()?; // log2_max_frame_num_minus4
+    match reader.read_ue()? {
+        0 => {
+            reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.read_bit()?; // delta_pic_order_always_zero_flag
+            reader.read_se()?; // offset_for_non_ref_pic
+            reader.read_se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?;
+            }
+        }
+        _ => (),
+    }
+    reader.read_ue()?; // max_num_ref_frames
+    reader.read_bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = reader.read_ue()? as i64 + 1;
+    let height_in_map_units = reader.read_ue()? as i64 + 1;
+    let frame_mbs_only = reader.read_bit()? as i64;
+    if frame_mbs_only == 0 {
+        reader.read_bit()?; // mb_adaptive_frame_field_flag
+    }
+    reader.read_bit()?; // direct_8x8_inference_flag
+    let mut width = width_in_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_in_map_units * 16;
+    if reader.read_bit()? == 1 {
+        // Offsets are in chroma samples, and in field rows for interlaced streams
+        let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        let left = reader.read_ue()? as i64;
+        let right = reader.read_ue()? as i64;
+        let top = reader.read_ue()? as i64;
+        let bottom = reader.read_ue()? as i64;
+        width -= crop_unit_x * (left + right);
+        height -= crop_unit_y * (top + bottom);
+    }
+    if width <= 0 || height <= 0 || width > i32::MAX as i64 || height > i32::MAX as i64 {
+        return None;
+    }
+
+    Some(SpsInfo {
+        display_size: (width as i32, height as i32),
+        frame_rate: vui_frame_rate(&mut reader),
+    })
+}
+
+/// Reads the frame rate from the `vui_parameters()` that end an SPS, if present.
+fn vui_frame_rate(reader: &mut ExpGolombReader) -> Option<f64> {
+    // vui_parameters_present_flag
+    if reader.read_bit()? == 0 {
+        return None;
+    }
+    if reader.read_bit()? == 1 && reader.read_bits(8)? == 255 {
+        reader.read_bits(32)?; // sar_width, sar_height
+    }
+    if reader.read_bit()? == 1 {
+        reader.read_bit()?; // overscan_appropriate_flag
+    }
+    if reader.read_bit()? == 1 {
+        reader.read_bits(4)?; // video_format, video_full_range_flag
+        if reader.read_bit()? == 1 {
+            // colour_primaries, transfer_characteristics, matrix_coefficients
+            reader.read_bits(24)?;
+        }
+    }
+    if reader.read_bit()? == 1 {
+        reader.read_ue()?; // chroma_sample_loc_type_top_field
+        reader.read_ue()?; // chroma_sample_loc_type_bottom_field
+    }
+    if reader.read_bit()? == 0 {
+        return None;
+    }
+    let num_units_in_tick = reader.read_bits(32)?;
+    let time_scale = reader.read_bits(32)?;
+    if num_units_in_tick == 0 {
+        return None;
+    }
+    // A frame is two fields, i.e. two ticks
+    let frame_rate = time_scale as f64 / (2.0 * num_units_in_tick as f64);
+    (1.0..=480.0).contains(&frame_rate).then_some(frame_rate)
+}
+
+/// Reads Exp-Golomb coded values from an RBSP, skipping emulation prevention bytes.
+struct ExpGolombReader<'a> {
+    data: &'a [u8],
+    byte_index: usize,
+    bit_index: u8,
+    zeroes: usize,
+}
+
+impl<'a> ExpGolombReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        ExpGolombReader {
+            data,
+            byte_index: 0,
+            bit_index: 0,
+            zeroes: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        if self.bit_index == 0 {
+            let byte = *self.data.get(self.byte_index)?;
+            // 0x000003 is an emulation prevention sequence, drop the 0x03
+            if self.zeroes >= 2 && byte == 3 {
+                self.zeroes = 0;
+                self.byte_index += 1;
+                return self.read_bit();
+            }
+            self.zeroes = if byte == 0 { self.zeroes + 1 } else { 0 };
+        }
+        let byte = self.data[self.byte_index];
+        let bit = (byte >> (7 - self.bit_index)) & 1;
+        self.bit_index += 1;
+        if self.bit_index == 8 {
+            self.bit_index = 0;
+            self.byte_index += 1;
+        }
+        Some(bit as u32)
+    }
+
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeroes = 0;
+        while self.read_bit()? == 0 {
+            leading_zeroes += 1;
+            if leading_zeroes > 31 {
+                return None;
+            }
+        }
+        let mut value = 0;
+        for _ in 0..leading_zeroes {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some((1 << leading_zeroes) - 1 + value)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let k = self.read_ue()?;
+        let magnitude = ((k as u64 + 1) / 2) as i32;
+        Some(if k % 2 == 1 { magnitude } else { -magnitude })
+    }
+
+    /// Read up to 32 bits as an unsigned integer.
+    fn read_bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..n {
+            value = value.wrapping_shl(1) | self.read_bit()?;
+        }
+        Some(value)
+    }
+
+    /// Skip a `scaling_list()` of the given size.
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let mut last_scale = 8;
+        let mut next_scale = 8;
+        for _ in 0..size {
+            if next_scale != 0 {
+                next_scale = (last_scale + i64::from(self.read_se()?)).rem_euclid(256);
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::DecoderStats;
+
+    // 1920x1088 High profile SPS as written by x264, cropped to 1080 rows, 30 fps
+    const SPS_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x2a, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00,
+        0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    // 3840x2160 High 10 with BT.2020 colours, PQ and 60000/1001 fps
+    const SPS_2160P: &[u8] = &[
+        0x67, 0x6e, 0x00, 0x33, 0xa6, 0xcd, 0xa0, 0x0f, 0x00, 0x10, 0xfa, 0x6a, 0x12, 0x20, 0x12,
+        0x80, 0x00, 0x01, 0xf4, 0x80, 0x00, 0xea, 0x60, 0x42,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+    // Slice headers only, first_mb_in_slice is 0 and then 1
+    const IDR_FIRST_SLICE: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+    const IDR_SECOND_SLICE: &[u8] = &[0x65, 0x41, 0x9a, 0x00];
+    // slice_type 5 and 6 with first_mb_in_slice 0, then slice_type 5 with first_mb_in_slice 1
+    const P_FIRST_SLICE: &[u8] = &[0x41, 0x9a];
+    const B_FIRST_SLICE: &[u8] = &[0x01, 0x9e];
+    const P_SECOND_SLICE: &[u8] = &[0x41, 0x46];
+    const SEI: &[u8] = &[0x06, 0x05, 0x01, 0x00, 0x80];
+
+    #[test]
+    fn classifies_first_slices_only() {
+        assert_eq!(classify_nalu(IDR_FIRST_SLICE), Some(FrameType::Idr));
+        assert_eq!(classify_nalu(P_FIRST_SLICE), Some(FrameType::P));
+        assert_eq!(classify_nalu(B_FIRST_SLICE), Some(FrameType::B));
+        assert_eq!(classify_nalu(IDR_SECOND_SLICE), None);
+        assert_eq!(classify_nalu(P_SECOND_SLICE), None);
+        assert_eq!(classify_nalu(SPS_1080P), None);
+        assert_eq!(classify_nalu(PPS), None);
+        assert_eq!(classify_nalu(SEI), None);
+        assert_eq!(classify_nalu(&[]), None);
+    }
+
+    #[test]
+    fn access_unit_is_one_frame_in_flight() {
+        let stats = DecoderStats::default();
+        for nalu in [SPS_1080P, PPS, IDR_FIRST_SLICE, IDR_SECOND_SLICE] {
+            if classify_nalu(nalu).is_some() {
+                stats.frame_queued();
+            }
+        }
+        assert_eq!(stats.frames_in_flight(), 1);
+        stats.frame_released();
+        assert_eq!(stats.frames_in_flight(), 0);
+    }
+
+    #[test]
+    fn codec_config_round_trips_through_nalu_chunks() {
+        let mut decoder = H264Decoder::default();
+        let payload = [&NALU_DELIMITER[..], SPS_1080P, &NALU_DELIMITER, PPS].concat();
+        assert!(decoder.read_payload(&payload).is_ok());
+        assert!(decoder.init_done());
+
+        // Submitted one buffer at a time
+        let codec_config = decoder.codec_config();
+        assert_eq!(codec_config.len(), 2);
+        let nalus: Vec<&[u8]> = codec_config
+            .iter()
+            .flat_map(|buffer| nalu_chunks(buffer))
+            .collect();
+        assert_eq!(nalus, [SPS_1080P, PPS]);
+
+        // The same bytes as one Annex-B stream
+        let stream = codec_config.concat();
+        let nalus: Vec<&[u8]> = nalu_chunks(&stream).collect();
+        assert_eq!(nalus, [SPS_1080P, PPS]);
+
+        // The coded size, cropping it is left to `parse_sps`
+        assert_eq!(H264Codec::get_resolution(nalus[0]), Some((1920, 1088)));
+        assert_eq!(decoder.resolution(), Some((1920, 1088)));
+    }
+
+    #[test]
+    fn sps_crops_to_display_size() {
+        let info = parse_sps(SPS_1080P).unwrap();
+        assert_eq!(info.display_size, (1920, 1080));
+        // Nothing to crop
+        let info = parse_sps(SPS_2160P).unwrap();
+        assert_eq!(info.display_size, (3840, 2160));
+    }
+
+    #[test]
+    fn sps_frame_rate_from_vui_timing() {
+        let info = parse_sps(SPS_1080P).unwrap();
+        assert_eq!(info.frame_rate, Some(30.0));
+        let info = parse_sps(SPS_2160P).unwrap();
+        let frame_rate = info.frame_rate.unwrap();
+        assert!((frame_rate - 60000.0 / 1001.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn truncated_sps_is_rejected() {
+        // Cut off before the resolution
+        assert!(parse_sps(&SPS_1080P[..6]).is_none());
+        assert!(parse_sps(&[]).is_none());
+    }
+
+    #[test]
+    fn reads_exp_golomb_codes() {
+        // 1 010 011 00100
+        let mut reader = ExpGolombReader::new(&[0xa6, 0x40]);
+        let values: Vec<_> = (0..4).map(|_| reader.read_ue()).collect();
+        assert_eq!(values, [Some(0), Some(1), Some(2), Some(3)]);
+
+        // 010 011 00100
+        let mut reader = ExpGolombReader::new(&[0x4c, 0x80]);
+        let values: Vec<_> = (0..3).map(|_| reader.read_se()).collect();
+        assert_eq!(values, [Some(1), Some(-1), Some(2)]);
+
+        let mut reader = ExpGolombReader::new(&[0x00]);
+        assert_eq!(reader.read_ue(), None);
+    }
+
+    #[test]
+    fn skips_emulation_prevention_bytes() {
+        let mut reader = ExpGolombReader::new(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x03]);
+        assert_eq!(reader.read_bits(24), Some(0x000001));
+        assert_eq!(reader.read_bits(24), Some(0x000003));
+        assert_eq!(reader.read_bit(), None);
+    }
+
+    #[test]
+    fn maps_android_profile_ids() {
+        // Values of MediaCodecInfo.CodecProfileLevel.AVCProfile*
+        let table: &[(i32, fn(&H264Profile) -> bool)] = &[
+            (0x01, |p| matches!(p, H264Profile::Baseline)),
+            (0x02, |p| matches!(p, H264Profile::Main)),
+            (0x04, |p| matches!(p, H264Profile::Extended)),
+            (0x08, |p| matches!(p, H264Profile::High)),
+            (0x10, |p| matches!(p, H264Profile::High10)),
+            (0x20, |p| matches!(p, H264Profile::High422)),
+            (0x40, |p| matches!(p, H264Profile::High444)),
+            (0x10000, |p| matches!(p, H264Profile::ConstrainedBaseline)),
+            (0x80000, |p| matches!(p, H264Profile::ConstrainedHigh)),
+        ];
+        for &(id, is_expected) in table {
+            let profile = profile_from_android_id(id);
+            assert!(profile.as_ref().map_or(false, is_expected), "id {id:#x}");
+        }
+    }
 
-            self.codec_config = Some(codec_config);
+    #[test]
+    fn unknown_android_profile_ids_are_none() {
+        for id in [0, 0x03, 0x80, 0x20000, -1] {
+            assert!(profile_from_android_id(id).is_none(), "id {id:#x}");
         }
     }
 }
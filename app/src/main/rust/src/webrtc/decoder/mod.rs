@@ -1,24 +1,37 @@
 mod builder;
 mod h264;
+mod opus;
 mod rtcp_helper;
+mod surface;
 
 pub use self::builder::AndroidDecoderBuilder;
-use self::rtcp_helper::RateLimitedPli;
+use self::{
+    rtcp_helper::KeyframeRequester,
+    surface::{SurfaceChange, SurfaceLifecycle, SurfaceState},
+};
+use super::{report_connection_error, wait_until_connected, NegotiatedCodec, CONNECTION_TIMEOUT};
 use crate::{
-    media::{MediaEngine, MediaFormat, MediaStatus, MediaTimeout, MimeType},
+    event_queue::EventReceiver,
+    media::{
+        MediaEngine, MediaFormat, MediaFormatError, MediaInputBuffer, MediaStatus, MediaTimeout,
+        MimeType,
+    },
+    stats::{BitrateEstimator, DiagnosticsLog, FrameType, QualityMeter},
     window::NativeWindow,
-    MediaPlayerEvent, NativeLibSingleton,
+    MediaPlayerEvent, NativeLibSingleton, RenderPolicy,
 };
 use std::{
-    collections::HashMap,
-    str::FromStr,
+    collections::{HashMap, VecDeque},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver};
+use tokio::{
+    sync::mpsc::{self, error::TryRecvError},
+    task::JoinHandle,
+};
 use webrtc::{
     peer_connection::peer_connection_state::RTCPeerConnectionState,
     rtp_transceiver::rtp_receiver::RTCRtpReceiver, track::track_remote::TrackRemote,
@@ -32,22 +45,60 @@ use webrtc_helper::{
     WebRtcPeer,
 };
 
-const PLI_INTERVAL: Duration = Duration::from_millis(50);
+const KEYFRAME_REQUEST_RETRY: Duration = Duration::from_millis(500);
 const NUM_BUFFERED_PACKETS: usize = 128;
+const MAX_FRAMES_IN_FLIGHT: usize = 8;
+const TRACK_READ_TIMEOUT: Duration = Duration::from_millis(500);
+const STALL_TIMEOUTS_BEFORE_DEGRADED: u32 = 3;
+const MALFORMED_ERRORS_BEFORE_RESET: u32 = 3;
+/// Empty payloads in a row, with no media in between, that mean the sender is broken.
+const EMPTY_PAYLOADS_BEFORE_RESYNC: u32 = 64;
+const EMPTY_PAYLOAD_WINDOW: Duration = Duration::from_secs(1);
+/// Frames in flight with no output for this long means the codec hung.
+const DECODER_HANG_TIMEOUT: Duration = Duration::from_secs(2);
+/// Blocking dequeues are done in slices of this so that a hung codec can't block forever.
+const DEQUEUE_SLICE: Duration = Duration::from_millis(100);
+const SURFACE_DEBOUNCE: Duration = Duration::from_millis(50);
+const SURFACE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const CATCH_UP_AFTER: Duration = Duration::from_secs(1);
+// Short enough that a debounced surface is still applied on time
+const PAUSED_EVENT_WAIT: Duration = Duration::from_millis(50);
+/// Assumed when neither the bitstream nor the SDP gives the frame rate.
+const DEFAULT_FRAME_RATE: f64 = 60.0;
+/// How many frame intervals `RenderPolicy::Smooth` holds frames for.
+const SMOOTH_FRAMES_HELD: f64 = 2.0;
+/// `RenderPolicy::Smooth` starts over from the current frame if it is due this far off.
+const SMOOTH_MAX_LATENESS: Duration = Duration::from_millis(100);
+const CODEC_RESOURCE_RETRIES: u32 = 2;
+const CODEC_RESOURCE_RETRY_DELAY: Duration = Duration::from_millis(500);
+// The first codec plus 5 recreations, past that the stream is assumed to be undecodable
+const MAX_CODEC_CREATIONS: usize = 6;
+const CODEC_CREATION_WINDOW: Duration = Duration::from_secs(30);
 const MAX_NALU_SIZE: usize = 250_000;
+/// Frames kept while the codec is configured, beyond this they are dropped for a new keyframe.
+const MAX_HELD_BYTES: usize = 2 * MAX_NALU_SIZE;
+const VENDOR_LOW_LATENCY_KEY: &str = "vendor.rtc-ext-dec-low-latency.enable";
+// Qualcomm decoders, the only ones known to accept the key above
+const VENDOR_LOW_LATENCY_DECODER_PREFIXES: [&str; 2] = ["c2.qti.", "OMX.qcom."];
 const NALU_TYPE_BITMASK: u8 = 0x1F;
 const NALU_TYPE_IDR_PIC: u8 = 5;
+const NALU_TYPE_SEI: u8 = 6;
+const NALU_DELIMITER_LEN: usize = 4;
 
 #[derive(Debug)]
 pub enum DecoderError {
     MediaEngine(MediaStatus),
+    InvalidFormat(MediaFormatError),
     RtcpSend(webrtc::Error),
     AttachThread(jni::errors::Error),
     SetAspectRatio(jni::errors::Error),
     UnknownMimeType,
-    FailedToGetReceiver,
     NativeWindowCreate,
+    SurfaceTimeout,
     NoDecoderFound,
+    DecoderMimeMismatch,
+    CodecCreationLimit,
+    ConnectionTimeout,
     ApplicationClosed,
 }
 
@@ -62,132 +113,428 @@ macro_rules! impl_from {
     };
 }
 
+impl std::fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecoderError::MediaEngine(e) => write!(f, "MediaCodec error: {e}"),
+            DecoderError::InvalidFormat(e) => write!(f, "Invalid MediaFormat: {e}"),
+            DecoderError::RtcpSend(e) => write!(f, "Failed to send RTCP: {e}"),
+            DecoderError::AttachThread(e) => write!(f, "Failed to attach to the JVM: {e}"),
+            DecoderError::SetAspectRatio(e) => write!(f, "Failed to set the aspect ratio: {e}"),
+            _ => std::fmt::Debug::fmt(self, f),
+        }
+    }
+}
+
+impl std::error::Error for DecoderError {}
+
 impl_from!(MediaStatus, MediaEngine);
+impl_from!(MediaFormatError, InvalidFormat);
 impl_from!(webrtc::Error, RtcpSend);
 impl_from!(jni::errors::Error, AttachThread);
 
 async fn start_decoder(
     track: Arc<TrackRemote>,
-    _rtp_receiver: Arc<RTCRtpReceiver>,
+    rtp_receiver: Arc<RTCRtpReceiver>,
     peer: Arc<WebRtcPeer>,
     singleton: Arc<NativeLibSingleton>,
     codec_map: HashMap<MimeType, String>,
+    session_id: u64,
 ) -> Result<(), DecoderError> {
-    while peer.connection_state() != RTCPeerConnectionState::Connected {
-        tokio::time::sleep(Duration::from_millis(10)).await;
+    match tokio::time::timeout(CONNECTION_TIMEOUT, wait_until_connected(&peer)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(_)) => return Err(DecoderError::ApplicationClosed),
+        Err(_) => return Err(DecoderError::ConnectionTimeout),
     }
 
     // TODO: Check sdp_fmtp_line for SPS/PPS
     let codec_params = track.codec().await;
-    let mime_type = MimeType::from_str(&codec_params.capability.mime_type)
-        .map_err(|_| DecoderError::UnknownMimeType)?;
+    let negotiated =
+        NegotiatedCodec::from_parameters(&codec_params).ok_or(DecoderError::UnknownMimeType)?;
+    log::info!("Negotiated {negotiated}");
 
     let decoder_name = codec_map
-        .get(&mime_type)
+        .get(&negotiated.mime_type)
         .ok_or(DecoderError::NoDecoderFound)?;
 
-    let mut receiver = singleton
-        .get_event_receiver()
-        .ok_or(DecoderError::FailedToGetReceiver)?;
+    // Before the surface is read so that no change to it is missed
+    let mut receiver = singleton.subscribe_events();
+    decode_track(
+        track,
+        rtp_receiver,
+        peer,
+        &singleton,
+        &negotiated,
+        decoder_name,
+        session_id,
+        &mut receiver,
+    )
+    .await
+}
 
-    let decoder = match mime_type {
+async fn decode_track(
+    track: Arc<TrackRemote>,
+    rtp_receiver: Arc<RTCRtpReceiver>,
+    peer: Arc<WebRtcPeer>,
+    singleton: &Arc<NativeLibSingleton>,
+    negotiated: &NegotiatedCodec,
+    decoder_name: &str,
+    session_id: u64,
+    receiver: &mut EventReceiver,
+) -> Result<(), DecoderError> {
+    // Before the codec is created so that its initial state is kept
+    singleton.stats().reset();
+    singleton
+        .stats()
+        .set_negotiated_codec(Some(negotiated.clone()));
+
+    // Both loops request keyframes, share the requester so that their combined rate is bounded
+    let keyframes = Arc::new(KeyframeRequester::new(
+        track.ssrc(),
+        singleton.keyframe_strategy(),
+    ));
+    // Ends with the receiver when the session closes
+    tokio::spawn(rtcp_helper::read_rtcp(rtp_receiver, keyframes.clone()));
+
+    // Carried from codec creation into rendering
+    let mut surface = SurfaceLifecycle::new();
+    // Shared by both loops so that no packet is lost in between
+    let mut track_reader = TrackReader::spawn(track);
+
+    let (decoder, held_frames) = match negotiated.mime_type {
         MimeType::AudioPcma => todo!(),
         MimeType::AudioPcmu => todo!(),
         MimeType::AudioOpus => todo!(),
         MimeType::VideoAv1 => todo!(),
-        MimeType::VideoH264 => Arc::new(
-            create_media_engine::<h264::H264Decoder>(
-                &singleton,
-                &track,
+        MimeType::VideoH264 => {
+            let (media_engine, held_frames) = create_media_engine::<h264::H264Decoder>(
+                singleton,
+                &mut track_reader,
                 &peer,
-                mime_type,
+                &keyframes,
+                negotiated,
                 decoder_name,
-                &mut receiver,
+                session_id,
+                receiver,
+                &mut surface,
             )
-            .await?,
-        ),
+            .await?;
+            (Arc::new(media_engine), held_frames)
+        }
         MimeType::VideoH265 => todo!(),
         MimeType::VideoVp8 => todo!(),
     };
 
+    // Timestamps of queued frames are relative to this. Used to tell which outputs come after a
+    // recovery and how long frames spent in the decoder.
+    let session_start = Instant::now();
     let exit = Arc::new(AtomicBool::new(false));
     let exit_clone = exit.clone();
+    let skip_to_keyframe = Arc::new(AtomicBool::new(false));
+    let skip_to_keyframe_clone = skip_to_keyframe.clone();
+    // Set by the render loop's watchdog, on a rotation change or on malformed input, done by the
+    // input task since it owns the input buffers
+    let reset_codec = Arc::new(AtomicBool::new(false));
+    let reset_codec_clone = reset_codec.clone();
+    // Held by the render loop while it owns an output buffer and by the input task while it resets
+    // the codec, a reset would pull the buffer out from under the render loop
+    let codec_lock = Arc::new(tokio::sync::Mutex::new(()));
+    let codec_lock_clone = codec_lock.clone();
     let peer_clone = peer.clone();
     let decoder_clone = decoder.clone();
+    let singleton_clone = singleton.clone();
+    let keyframes_clone = keyframes.clone();
 
     let join_handle = tokio::spawn(async move {
         let peer = peer_clone;
         let decoder = decoder_clone;
         let exit = exit_clone;
+        let skip_to_keyframe = skip_to_keyframe_clone;
+        let reset_codec = reset_codec_clone;
+        let codec_lock = codec_lock_clone;
+        let singleton = singleton_clone;
 
-        let mut pli = RateLimitedPli::new(track.ssrc(), PLI_INTERVAL);
+        let keyframes = keyframes_clone;
+        let mut waiting_for_idr_since: Option<Instant> = None;
 
         let mut has_reference_frame = false;
-        let mut reorder_buffer = BufferedTrackRemote::new(track.clone(), NUM_BUFFERED_PACKETS);
-        let mut input_buffer = decoder.dequeue_input_buffer(MediaTimeout::INFINITE)?;
-        let mut reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
+        let split_frames = singleton.api_level() >= 26;
+
+        // What arrived along with the codec config goes in right behind it, starting with the
+        // keyframe, so that the keyframe doesn't have to be requested again
+        for frame in held_frames {
+            let pts = session_start.elapsed().as_micros() as u64;
+            let nalu = frame.get(NALU_DELIMITER_LEN..).unwrap_or(&[]);
+            let frame_type = h264::classify_nalu(nalu);
+            let input_buffer = match dequeue_input_within(&decoder, &exit, DECODER_HANG_TIMEOUT) {
+                Ok(input_buffer) => input_buffer,
+                // The main loop asks for a new keyframe instead
+                Err(DecoderError::MediaEngine(MediaStatus::NoAvailableBuffer)) => {
+                    log::warn!("No input buffer for the frames held during configuration");
+                    has_reference_frame = false;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            match decoder.queue_access_unit(input_buffer, &frame, pts, 0, split_frames) {
+                Ok(_) => {
+                    if !has_reference_frame {
+                        has_reference_frame = true;
+                        keyframes.keyframe_received();
+                        singleton.stats().set_recovery_point(pts);
+                    }
+                    if let Some(frame_type) = frame_type {
+                        singleton.stats().frame_queued();
+                        singleton.stats().record_frame(frame_type);
+                    }
+                }
+                Err(e) => {
+                    // The rest refers to this one, wait for the next keyframe instead
+                    log::error!("Failed to queue a frame held during configuration: {e}");
+                    has_reference_frame = false;
+                    break;
+                }
+            }
+        }
+
+        let mut b_frames_reported = false;
+        let mut empty_payloads = EmptyPayloadMeter::default();
+        let (mut input_buffer, _) =
+            dequeue_input_or_reset(&decoder, &reset_codec, &codec_lock, &exit).await?;
+        // Frames that the codec's input buffers are unlikely to fit are reassembled here instead
+        let mut heap_buffer = vec![0u8; MAX_NALU_SIZE];
+        let mut largest_nalu = 0;
+        let mut use_heap = needs_heap_buffer(&input_buffer, largest_nalu, !has_reference_frame);
+        let strip_sei = singleton.strip_sei();
+        let mut reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+        let mut consecutive_timeouts = 0;
+        let mut consecutive_malformed = 0;
+        let mut quality = QualityMeter::default();
+        let mut diagnostics = DiagnosticsLog::default();
+        let mut bitrate = BitrateEstimator::default();
 
         // DEBUG
         let mut timings = DebugTimings::new();
 
         while !exit.load(Ordering::Acquire) {
-            match reorder_buffer.recv().await {
-                Ok(payload) => match reader.push(payload) {
+            // Jump to live instead of decoding the backlog by dropping everything until an IDR
+            if skip_to_keyframe.swap(false, Ordering::AcqRel) {
+                has_reference_frame = false;
+                reader.finish();
+                use_heap = needs_heap_buffer(&input_buffer, largest_nalu, true);
+                reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+                keyframes.request(&peer).await?;
+            }
+
+            let res = track_reader.recv(TRACK_READ_TIMEOUT).await;
+            if res.is_ok() && consecutive_timeouts > 0 {
+                consecutive_timeouts = 0;
+                singleton.stats().set_degraded(false);
+            }
+            if let Some(score) = quality.poll(singleton.stats().decode_latency_us()) {
+                singleton.stats().set_quality(score);
+            }
+            if let Some(bps) = bitrate.poll(quality.jitter_ms()) {
+                singleton.report_target_bitrate(bps);
+            }
+            if let Some(line) = diagnostics.poll(
+                singleton.diagnostics_enabled(),
+                &quality,
+                singleton.stats(),
+                singleton.rendered_frames(),
+            ) {
+                log::info!("{line}");
+            }
+
+            if let Ok(payload) = &res {
+                diagnostics.record_packet(payload.len());
+                bitrate.record_packet(payload.len());
+                empty_payloads.payload_received();
+            }
+
+            match res {
+                Ok(payload) => match reader.push(&payload) {
                     Ok(()) => {
                         let n = reader.finish();
-                        let nalu = &input_buffer[..n];
+                        largest_nalu = largest_nalu.max(n);
+
+                        // The depacketizer stops writing once the buffer is full so a completely
+                        // filled buffer means the NALU was most likely truncated
+                        let capacity = if use_heap {
+                            heap_buffer.len()
+                        } else {
+                            input_buffer.remaining_capacity()
+                        };
+                        if n >= capacity {
+                            if use_heap {
+                                log::warn!("Dropping NALU larger than {capacity} bytes");
+                            } else {
+                                log::warn!(
+                                    "Dropping NALU that exceeded the input buffer ({capacity} \
+                                     bytes), reassembling the next ones separately"
+                                );
+                            }
+                            has_reference_frame = false;
+                            use_heap = needs_heap_buffer(&input_buffer, largest_nalu, true);
+                            reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+                            keyframes.request(&peer).await?;
+                            continue;
+                        }
+
+                        let nalu: &[u8] = if use_heap {
+                            &heap_buffer[..n]
+                        } else {
+                            &input_buffer[..n]
+                        };
+                        let pts = session_start.elapsed().as_micros() as u64;
 
                         if !has_reference_frame {
-                            let nalu_type = nalu[4] & NALU_TYPE_BITMASK;
+                            let nalu_type = nalu[NALU_DELIMITER_LEN] & NALU_TYPE_BITMASK;
                             if nalu_type != NALU_TYPE_IDR_PIC {
-                                pli.send(&peer).await?;
-                                reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
+                                if waiting_for_idr_since.is_none() {
+                                    waiting_for_idr_since = Some(Instant::now());
+                                    // Hide whatever the decoder still outputs from before the loss
+                                    singleton.stats().start_recovery();
+                                }
+                                keyframes.request(&peer).await?;
+                                reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
                                 continue;
                             } else {
                                 has_reference_frame = true;
+                                waiting_for_idr_since = None;
+                                keyframes.keyframe_received();
+                                singleton.stats().set_recovery_point(pts);
                             }
                         }
 
+                        // Nothing needs the SEI and some decoders choke on it, e.g. on large user
+                        // data or HDR metadata
+                        if strip_sei
+                            && nalu[NALU_DELIMITER_LEN] & NALU_TYPE_BITMASK == NALU_TYPE_SEI
+                        {
+                            reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+                            continue;
+                        }
+
                         // DEBUG
                         timings.snapshot();
 
-                        let res = decoder.queue_input_buffer(input_buffer, n as _, 0, 0);
-                        input_buffer = decoder.dequeue_input_buffer(MediaTimeout::INFINITE)?;
-                        reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
+                        // Only the first slice of a picture gets one, which is also the only one
+                        // with an output buffer of its own
+                        let frame_type = h264::classify_nalu(&nalu[NALU_DELIMITER_LEN..]);
+                        if frame_type == Some(FrameType::B) && !b_frames_reported {
+                            // Each B-frame holds back output until its later reference arrives
+                            log::warn!("The host is sending B-frames, expect added latency");
+                            singleton.report_b_frames();
+                            b_frames_reported = true;
+                        }
+
+                        let res = if use_heap {
+                            let nalu = &heap_buffer[..n];
+                            decoder.queue_access_unit(input_buffer, nalu, pts, 0, split_frames)
+                        } else {
+                            decoder.queue_input_buffer(input_buffer, n as _, pts, 0)
+                        };
                         match res {
-                            Ok(_) => (), // TODO: Use a channel to signal the other thread?
-                            Err(e) => log::error!("queue_input_buffer error: {e}"),
+                            Ok(_) => {
+                                consecutive_malformed = 0;
+                                if let Some(frame_type) = frame_type {
+                                    singleton.stats().frame_queued();
+                                    singleton.stats().record_frame(frame_type);
+                                }
+                                quality.record_frame();
+                                bitrate.record_frame();
+                            }
+                            // Only from the heap buffer, without `split_frames`. Whatever refers
+                            // to the frame can't be decoded either.
+                            Err(MediaStatus::BufferTooSmall) => {
+                                log::warn!("Dropping {n} byte frame, too large to queue");
+                                has_reference_frame = false;
+                            }
+                            // Only part of a split frame was queued
+                            Err(MediaStatus::NoAvailableBuffer) => {
+                                log::warn!("Codec stopped taking the parts of a {n} byte frame");
+                                has_reference_frame = false;
+                            }
+                            Err(e) => {
+                                log::error!("queue_input_buffer error: {e}");
+                                if e.is_malformed() {
+                                    consecutive_malformed += 1;
+                                }
+                            }
+                        }
+
+                        // A flush keeps the codec's state, start it over if it keeps rejecting
+                        // valid input. The network session is left alone.
+                        if consecutive_malformed >= MALFORMED_ERRORS_BEFORE_RESET {
+                            log::warn!(
+                                "Codec rejected {consecutive_malformed} frames in a row, resetting"
+                            );
+                            consecutive_malformed = 0;
+                            reset_codec.store(true, Ordering::Release);
+                        }
+
+                        // Stop pulling from the reorder buffer while the decoder is backed up
+                        while singleton.stats().frames_in_flight() >= MAX_FRAMES_IN_FLIGHT
+                            && !exit.load(Ordering::Acquire)
+                            && !reset_codec.load(Ordering::Acquire)
+                        {
+                            tokio::time::sleep(Duration::from_millis(1)).await;
                         }
+
+                        let was_reset;
+                        (input_buffer, was_reset) =
+                            dequeue_input_or_reset(&decoder, &reset_codec, &codec_lock, &exit)
+                                .await?;
+                        if was_reset {
+                            singleton.stats().frames_discarded();
+                            has_reference_frame = false;
+                            keyframes.request(&peer).await?;
+                        }
+                        use_heap =
+                            needs_heap_buffer(&input_buffer, largest_nalu, !has_reference_frame);
+                        reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
                     }
                     Err(DepacketizerError::NeedMoreInput) => continue,
                     Err(e) => {
                         log::error!("Depacketization error: {e:?}");
+                        quality.record_loss();
+                        bitrate.record_loss();
                         has_reference_frame = false;
                         reader.finish();
-                        reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
-                        pli.send(&peer).await?;
+                        use_heap = needs_heap_buffer(&input_buffer, largest_nalu, true);
+                        reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+                        keyframes.request(&peer).await?;
                     }
                 },
                 Err(e) => {
-                    match e {
-                        ReorderBufferError::HeaderParsingError
-                        | ReorderBufferError::TrackRemoteReadError => {
-                            has_reference_frame = false;
-                            reader.finish();
-                            reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
-                            pli.send(&peer).await?;
+                    let recovery = ReorderRecovery::for_error(&e, &mut empty_payloads);
+                    if recovery == ReorderRecovery::Stall {
+                        consecutive_timeouts += 1;
+                        singleton.stats().record_stall();
+                        if consecutive_timeouts == STALL_TIMEOUTS_BEFORE_DEGRADED {
+                            log::warn!(
+                                "No packets received for {}ms",
+                                (TRACK_READ_TIMEOUT * consecutive_timeouts).as_millis()
+                            );
+                            singleton.stats().set_degraded(true);
                         }
-                        ReorderBufferError::PacketTooShort => (), // Empty payload?
-                        ReorderBufferError::BufferFull => {
-                            // TODO: Should be NACK
-                            has_reference_frame = false;
-                            reader.finish();
-                            reader = H264Depacketizer::wrap_buffer(&mut input_buffer);
-                            pli.send(&peer).await?;
-                        }
-                        _ => (),
-                        // ReorderBufferError::TrackRemoteReadTimeout => todo!(),
-                        // ReorderBufferError::UnableToMaintainReorderBuffer => todo!(), // TODO: RENAME THIS
+                    }
+                    let actions = recovery.actions(consecutive_timeouts);
+                    if actions.record_loss {
+                        quality.record_loss();
+                        bitrate.record_loss();
+                    }
+                    if actions.drop_reference_frame {
+                        has_reference_frame = false;
+                    }
+                    if actions.reset_reader {
+                        reader.finish();
+                        use_heap = needs_heap_buffer(&input_buffer, largest_nalu, true);
+                        reader = wrap_reader(&mut input_buffer, &mut heap_buffer, use_heap);
+                    }
+                    if actions.request_keyframe {
+                        keyframes.request(&peer).await?;
                     }
                 }
             }
@@ -196,48 +543,315 @@ async fn start_decoder(
         Result::<(), DecoderError>::Ok(())
     });
 
-    let mut render = true;
+    let render_result = render_loop(
+        singleton,
+        &peer,
+        &decoder,
+        &skip_to_keyframe,
+        &reset_codec,
+        &codec_lock,
+        session_start,
+        session_id,
+        receiver,
+        &mut surface,
+    )
+    .await;
+
+    // Stop the input task and wait for it so that nothing else touches the codec
+    exit.store(true, Ordering::Release);
+    match join_handle.await {
+        Ok(Err(e)) => log::error!("Input task failed: {e}"),
+        Err(e) => log::error!("Error joining thread: {e:?}"),
+        Ok(Ok(())) => (),
+    }
+
+    // The input task has released its reference so the codec is stopped and deleted here
+    if Arc::try_unwrap(decoder).is_err() {
+        log::error!("`MediaEngine` is still referenced after the input task exited");
+    }
+
+    render_result?;
+    Err(DecoderError::ApplicationClosed)
+}
+
+/// Renders the decoder output until the connection closes or the activity is destroyed.
+async fn render_loop(
+    singleton: &Arc<NativeLibSingleton>,
+    peer: &Arc<WebRtcPeer>,
+    decoder: &MediaEngine,
+    skip_to_keyframe: &AtomicBool,
+    reset_codec: &AtomicBool,
+    codec_lock: &tokio::sync::Mutex<()>,
+    session_start: Instant,
+    session_id: u64,
+    receiver: &mut EventReceiver,
+    surface: &mut SurfaceLifecycle,
+) -> Result<(), DecoderError> {
+    // Whether the last non-blocking dequeue while paused returned a buffer
+    let mut output_pending = false;
+    // Some firmware hangs without reporting an error, watch for output stopping
+    let mut last_output = Instant::now();
+    // The codec only settles on its output format once it has seen the first frame
+    let mut low_latency_checked = false;
+    let mut pacer = match singleton.render_policy() {
+        RenderPolicy::LowLatency => None,
+        RenderPolicy::Smooth => Some(FramePacer::new(
+            singleton.stats().frame_rate().unwrap_or(DEFAULT_FRAME_RATE),
+        )),
+    };
 
     loop {
         if peer.connection_state() != RTCPeerConnectionState::Connected {
             break;
         }
+        if singleton.current_session() != session_id {
+            break;
+        }
 
-        match receiver.try_recv() {
+        if let Some(new_surface) = surface.take_pending(SURFACE_DEBOUNCE) {
+            let native_window =
+                match singleton.with_env(|env| Ok(NativeWindow::new(env, &new_surface.as_obj()))) {
+                    Ok(native_window) => native_window.ok_or(DecoderError::NativeWindowCreate)?,
+                    Err(e) => {
+                        // Might be transient, try again after another debounce period
+                        log::error!("Failed to attach to the JVM for the new surface: {e}");
+                        surface.retry(new_surface);
+                        continue;
+                    }
+                };
+
+            // The surface can already be gone when switching in and out of picture-in-picture
+            // quickly, wait for the next one instead of ending the session
+            let switched = {
+                // A reset in between would configure the codec with the old surface
+                let _codec = codec_lock.lock().await;
+                decoder.set_output_surface(&native_window)
+            };
+            if let Err(e) = switched {
+                log::error!("Failed to switch to the new surface: {e}");
+                continue;
+            }
+            let without_surface = surface.applied();
+
+            // The surface may belong to a differently sized window, e.g. picture-in-picture
+            let reapplied =
+                singleton.with_env(|env| singleton.reapply_media_player_aspect_ratio(env));
+            if let Err(e) = reapplied {
+                log::error!("Failed to reapply the aspect ratio: {e}");
+            }
+
+            // Whatever is still queued is stale after a long pause, e.g. from the background
+            if without_surface >= CATCH_UP_AFTER {
+                skip_to_keyframe.store(true, Ordering::Release);
+            }
+        }
+
+        // Nothing is shown while paused so there is no need to block on or spin for the output
+        let paused = surface.state() != SurfaceState::Rendering;
+        let event = if paused && !output_pending {
+            match tokio::time::timeout(PAUSED_EVENT_WAIT, receiver.recv()).await {
+                Ok(Some(msg)) => Ok(msg),
+                Ok(None) => Err(TryRecvError::Disconnected),
+                Err(_) => Err(TryRecvError::Empty),
+            }
+        } else {
+            receiver.try_recv()
+        };
+        let output_timeout = if paused {
+            MediaTimeout::new(Duration::ZERO)
+        } else {
+            MediaTimeout::new(DEQUEUE_SLICE)
+        };
+        output_pending = false;
+
+        // Until the output buffer is released, including the pacing delay
+        let _codec = codec_lock.lock().await;
+        match event {
             Ok(msg) => match msg {
                 MediaPlayerEvent::MainActivityDestroyed => {
                     break;
                 }
-                MediaPlayerEvent::SurfaceCreated(surface) => {
-                    let env = singleton.vm.attach_current_thread()?;
-                    let native_window = NativeWindow::new(&env, &surface.as_obj())
-                        .ok_or(DecoderError::NativeWindowCreate)?;
-
-                    // Rendering is possible again
-                    render = true;
-                    decoder.set_output_surface(&native_window)?;
-                }
-                MediaPlayerEvent::SurfaceDestroyed => {
-                    // Stop rendering when there is no surface to render to
-                    render = false;
+                MediaPlayerEvent::RotationChanged(degrees) => {
+                    // `MediaCodec` only reads the rotation when it is configured, and no
+                    // parameter changes it on a running codec
+                    match decoder.set_rotation(degrees) {
+                        Ok(()) => {
+                            log::info!("Rotating the output by {degrees} degrees");
+                            reset_codec.store(true, Ordering::Release);
+                        }
+                        Err(e) => log::error!("Failed to rotate the output: {e}"),
+                    }
                 }
+                msg => match surface.on_event(msg) {
+                    // Jump back to live instead of playing out what was decoded meanwhile
+                    SurfaceChange::Resumed(paused_for) if paused_for >= CATCH_UP_AFTER => {
+                        skip_to_keyframe.store(true, Ordering::Release);
+                    }
+                    _ => (),
+                },
             },
             Err(TryRecvError::Disconnected) => {
                 break;
             }
-            Err(TryRecvError::Empty) => {
-                if let Err(e) = decoder.release_output_buffer(MediaTimeout::INFINITE, render) {
-                    log::error!("release_output_buffer error: {e}");
+            Err(TryRecvError::Empty) => match decoder.dequeue_output(output_timeout) {
+                Ok(Some(output_buffer)) => {
+                    last_output = Instant::now();
+                    // Keep draining while paused so that the input side does not back up
+                    output_pending = paused;
+                    let info = output_buffer.info();
+                    let end_of_stream = info.is_end_of_stream();
+                    let latency = (session_start.elapsed().as_micros() as u64)
+                        .saturating_sub(info.presentation_time_us as u64);
+                    singleton.stats().record_decode_latency(latency);
+                    // Frames decoded before the IDR that ended a recovery may be corrupted
+                    let recovered = singleton
+                        .stats()
+                        .recovery_reached(info.presentation_time_us as u64);
+                    let render = surface.state() == SurfaceState::Rendering && recovered;
+                    if let Some(pacer) = pacer.as_mut() {
+                        if render {
+                            let delay = pacer.delay(info.presentation_time_us as u64);
+                            if !delay.is_zero() {
+                                tokio::time::sleep(delay).await;
+                            }
+                        } else {
+                            // Start over once rendering resumes
+                            pacer.reset();
+                        }
+                    }
+                    match decoder.release_output(output_buffer, render) {
+                        Ok(()) => {
+                            singleton.stats().frame_released();
+                            if render {
+                                singleton.frame_rendered();
+                            }
+                        }
+                        Err(e) => log::error!("release_output error: {e}"),
+                    }
+                    if end_of_stream {
+                        break;
+                    }
+                }
+                // The output format changed
+                Ok(None) => {
+                    last_output = Instant::now();
+                    update_aspect_ratio(singleton, decoder);
+                    if !low_latency_checked {
+                        check_low_latency(singleton, decoder);
+                        low_latency_checked = true;
+                    }
+                }
+                Err(MediaStatus::NoAvailableBuffer) => {
+                    // A codec waiting for more input, e.g. to reorder frames, is not hung
+                    let fed_since_output = singleton
+                        .stats()
+                        .last_queued()
+                        .map_or(false, |last_queued| last_queued > last_output);
+                    let hung = !paused
+                        && singleton.stats().frames_in_flight() > 0
+                        && fed_since_output
+                        && last_output.elapsed() >= DECODER_HANG_TIMEOUT;
+                    if hung {
+                        log::warn!(
+                            "No output for {}ms while being fed, resetting the codec",
+                            last_output.elapsed().as_millis()
+                        );
+                        reset_codec.store(true, Ordering::Release);
+                        // Give the reset time to take effect
+                        last_output = Instant::now();
+                    }
+                }
+                Err(e) => log::error!("dequeue_output error: {e}"),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Dequeue the next input buffer, first resetting the codec if that was requested. No input
+/// buffer is held here, and `codec_lock` keeps the render loop from holding an output buffer, so
+/// the reset is safe. Waits in slices so that a reset requested while waiting on a hung codec
+/// still happens. Returns whether the codec was reset.
+async fn dequeue_input_or_reset<'a>(
+    decoder: &'a MediaEngine,
+    reset_codec: &AtomicBool,
+    codec_lock: &tokio::sync::Mutex<()>,
+    exit: &AtomicBool,
+) -> Result<(MediaInputBuffer<'a>, bool), DecoderError> {
+    let mut was_reset = false;
+    loop {
+        if reset_codec.swap(false, Ordering::AcqRel) {
+            let _codec = codec_lock.lock().await;
+            decoder.reset()?;
+            was_reset = true;
+        }
+        match decoder.dequeue_input_buffer(MediaTimeout::new(DEQUEUE_SLICE)) {
+            Ok(input_buffer) => return Ok((input_buffer, was_reset)),
+            Err(MediaStatus::NoAvailableBuffer) => {
+                if exit.load(Ordering::Acquire) {
+                    return Err(DecoderError::ApplicationClosed);
                 }
             }
+            Err(e) => return Err(e.into()),
         }
     }
+}
 
-    exit.store(true, Ordering::Release);
-    if let Err(e) = join_handle.await {
-        log::error!("Error joining thread: {e:?}");
+/// Dequeue an input buffer, giving up with `MediaStatus::NoAvailableBuffer` after `limit`. Waits
+/// in slices so that the wait also ends with the session.
+fn dequeue_input_within<'a>(
+    decoder: &'a MediaEngine,
+    exit: &AtomicBool,
+    limit: Duration,
+) -> Result<MediaInputBuffer<'a>, DecoderError> {
+    let start = Instant::now();
+    loop {
+        match decoder.dequeue_input_buffer(MediaTimeout::new(DEQUEUE_SLICE)) {
+            Ok(input_buffer) => return Ok(input_buffer),
+            Err(MediaStatus::NoAvailableBuffer) => {
+                if exit.load(Ordering::Acquire) {
+                    return Err(DecoderError::ApplicationClosed);
+                }
+                if start.elapsed() >= limit {
+                    return Err(MediaStatus::NoAvailableBuffer.into());
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Set the aspect ratio again if the geometry the codec reports differs from the one set from the
+/// SPS, e.g. because of cropping or a resolution switch.
+fn update_aspect_ratio(singleton: &NativeLibSingleton, decoder: &MediaEngine) {
+    let display_size = decoder
+        .output_format()
+        .ok()
+        .and_then(|format| format.display_size());
+    let Some((width, height)) = display_size else {
+        return;
+    };
+    let result =
+        singleton.with_env(|env| singleton.update_media_player_aspect_ratio(env, width, height));
+    match result {
+        Ok(true) => log::info!("Output is {width}x{height}, updated the aspect ratio"),
+        Ok(false) => (),
+        Err(e) => log::error!("Failed to update the aspect ratio: {e}"),
+    }
+}
+
+/// See whether the codec actually enabled low latency mode, the flag is only a hint.
+fn check_low_latency(singleton: &NativeLibSingleton, decoder: &MediaEngine) {
+    let low_latency = decoder
+        .output_format()
+        .ok()
+        .and_then(|format| format.low_latency())
+        .unwrap_or(false);
+    singleton.stats().set_low_latency(low_latency);
+    if singleton.api_level() >= 30 && !low_latency {
+        log::warn!("The decoder did not enable low latency mode");
     }
-    return Err(DecoderError::ApplicationClosed);
 }
 
 trait AndroidDecoder: Default {
@@ -245,7 +859,16 @@ trait AndroidDecoder: Default {
 
     fn init_done(&self) -> bool;
     fn resolution(&self) -> Option<(i32, i32)>;
-    fn codec_config(&self) -> Option<&[u8]>;
+    /// Codec specific data, one input buffer's worth per element.
+    fn codec_config(&self) -> &[Vec<u8>];
+    /// Size of the picture that is shown, smaller than `resolution` if the bitstream crops the
+    /// frame, e.g. to 1080 rows out of the 1088 coded.
+    fn display_size(&self) -> Option<(i32, i32)>;
+    /// Nominal frame rate if the bitstream declares it.
+    fn frame_rate(&self) -> Option<f64>;
+
+    /// Whether the depacketized payload starts a new coded video sequence.
+    fn is_keyframe(payload: &[u8]) -> bool;
 
     fn read_payload(&mut self, payload: &[u8]) -> Result<(), ()>;
 }
@@ -253,55 +876,214 @@ trait AndroidDecoder: Default {
 // TODO: AndroidDecoder should be a trait object
 async fn create_media_engine<T: AndroidDecoder>(
     singleton: &Arc<NativeLibSingleton>,
-    track: &Arc<TrackRemote>,
+    track_reader: &mut TrackReader,
     peer: &Arc<WebRtcPeer>,
-    mime_type: MimeType,
+    keyframes: &KeyframeRequester,
+    negotiated: &NegotiatedCodec,
     decoder_name: &str,
-    receiver: &mut UnboundedReceiver<MediaPlayerEvent>,
-) -> Result<MediaEngine, DecoderError> {
-    let mut pli = RateLimitedPli::new(track.ssrc(), PLI_INTERVAL);
+    session_id: u64,
+    receiver: &mut EventReceiver,
+    surface: &mut SurfaceLifecycle,
+) -> Result<(MediaEngine, Vec<Vec<u8>>), DecoderError> {
+    let mime_type = negotiated.mime_type;
+    // Cleared if starting the codec early from it fails
+    let mut resolution_hint = negotiated.resolution_hint;
+    // A decoder that can't handle the stream would only fail later with a confusing error
+    let supported =
+        singleton.with_env(|env| singleton.decoder_supports_type(env, decoder_name, mime_type));
+    let codec_name = match supported {
+        Ok(true) => Some(decoder_name),
+        Ok(false) => {
+            log::error!("{decoder_name} does not support {mime_type:?}, using the default decoder");
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to check whether {decoder_name} supports {mime_type:?}: {e}");
+            Some(decoder_name)
+        }
+    };
+    let codec_label = codec_name.unwrap_or("The default decoder");
+    singleton.stats().set_decoder_name(Some(match codec_name {
+        Some(name) => name.to_owned(),
+        None => format!("default {} decoder", mime_type.to_android_str()),
+    }));
 
-    let mut native_window: Option<NativeWindow> = None;
+    // The surface may have been created before this session started
+    let mut native_window: Option<NativeWindow> = match singleton.current_surface() {
+        Some(current) => singleton.with_env(|env| Ok(NativeWindow::new(env, &current.as_obj())))?,
+        None => None,
+    };
+    if native_window.is_some() {
+        surface.applied();
+    }
 
-    let mut reorder_buffer = BufferedTrackRemote::new(track.clone(), NUM_BUFFERED_PACKETS);
+    let mut empty_payloads = EmptyPayloadMeter::default();
     let mut payload_buf = vec![0u8; MAX_NALU_SIZE];
     let mut reader = T::DepacketizerType::wrap_buffer(&mut payload_buf);
     let mut decoder = T::default();
+    let mut held_frames = HeldFrames::default();
+    let mut early_engine: Option<(MediaEngine, (i32, i32))> = None;
+    let mut creation_budget = CreationBudget::new(MAX_CODEC_CREATIONS, CODEC_CREATION_WINDOW);
+
+    // Actively ask for a keyframe instead of waiting for the sender to emit one
+    keyframes.request(peer).await?;
+    let first_keyframe_request = Instant::now();
+
+    // Creating the codec is slow, get it out of the way while the surface and SPS are pending.
+    // Dropped along with everything else if neither arrives.
+    let mut prewarmed_engine = match create_codec(codec_name, mime_type) {
+        Ok(media_engine) => {
+            log::info!(
+                "Created {codec_label} in {:?}",
+                first_keyframe_request.elapsed()
+            );
+            Some(media_engine)
+        }
+        Err(e) => {
+            log::warn!("Failed to create {codec_label} ahead of time: {e}");
+            None
+        }
+    };
+    let mut last_keyframe_request = first_keyframe_request;
 
     loop {
         if peer.connection_state() != RTCPeerConnectionState::Connected {
             return Err(DecoderError::ApplicationClosed);
         }
+        if singleton.current_session() != session_id {
+            return Err(DecoderError::ApplicationClosed);
+        }
 
-        // If everything has been gathered, build the media engine
-        if native_window.is_some() && decoder.init_done() {
-            let mut format = MediaFormat::new()?;
-            format.set_mime_type(mime_type);
-            format.set_realtime_priority(true);
-            if singleton.api_level() >= 30 {
-                format.set_low_latency(true);
+        // Nothing is rendered yet so there is no need to debounce
+        if let Some(new_surface) = surface.take_pending(Duration::ZERO) {
+            match singleton.with_env(|env| Ok(NativeWindow::new(env, &new_surface.as_obj()))) {
+                Ok(new_window) => {
+                    native_window = Some(new_window.ok_or(DecoderError::NativeWindowCreate)?);
+                    surface.applied();
+                }
+                Err(e) => {
+                    // Might be transient, try again on the next iteration
+                    log::error!("Failed to attach to the JVM for the new surface: {e}");
+                    surface.retry(new_surface);
+                }
             }
-            // TODO: Additional format flags
-            // format.set_integer("vendor.rtc-ext-dec-low-latency.enable", 1);
+        }
 
-            if let Some((width, height)) = decoder.resolution() {
-                format.set_resolution(width, height);
-                format.set_max_resolution(width, height);
+        if !decoder.init_done() && last_keyframe_request.elapsed() >= KEYFRAME_REQUEST_RETRY {
+            keyframes.request(peer).await?;
+            last_keyframe_request = Instant::now();
+        }
 
-                let env = singleton.vm.attach_current_thread()?;
-                singleton
-                    .set_media_player_aspect_ratio(&env, width, height)
-                    .map_err(|e| DecoderError::SetAspectRatio(e))?;
+        // If everything has been gathered, build the media engine. Audio has no use for a surface.
+        let has_output = surface.state() != SurfaceState::NoSurface || mime_type.is_audio();
+
+        // Don't keep requesting keyframes for a surface that never comes
+        if !has_output
+            && surface
+                .without_surface_for()
+                .map_or(false, |t| t >= SURFACE_WAIT_TIMEOUT)
+        {
+            return Err(DecoderError::SurfaceTimeout);
+        }
+
+        // With a hint from the host the codec can be started before the first SPS arrives
+        if has_output && early_engine.is_none() && !decoder.init_done() {
+            if let Some(hint) = resolution_hint {
+                if !creation_budget.spend() {
+                    return Err(codec_creation_limit(singleton));
+                }
+                match configure_media_engine(
+                    singleton,
+                    mime_type,
+                    codec_name,
+                    prewarmed_engine.take(),
+                    native_window.as_ref(),
+                    Some(hint),
+                ) {
+                    Ok(media_engine) => early_engine = Some((media_engine, hint)),
+                    Err(e) => {
+                        // Fall back to waiting for the SPS
+                        log::warn!("Failed to start the codec from the resolution hint: {e}");
+                        resolution_hint = None;
+                    }
+                }
             }
+        }
 
-            let mut media_engine = MediaEngine::create_by_name(decoder_name)?;
-            media_engine.initialize(&format, native_window.as_ref(), false)?;
+        if has_output && decoder.init_done() {
+            let resolution = decoder.resolution();
+            if mime_type.is_video() {
+                // The codec is configured with the coded size but only the cropped part is shown
+                if let Some((width, height)) = decoder.display_size().or(resolution) {
+                    singleton
+                        .with_env(|env| singleton.set_media_player_aspect_ratio(env, width, height))
+                        .map_err(|e| DecoderError::SetAspectRatio(e))?;
+                }
+            }
 
-            if let Some(codec_config) = decoder.codec_config() {
+            let media_engine = match early_engine.take() {
+                Some((media_engine, (max_width, max_height)))
+                    if resolution.map_or(true, |(width, height)| {
+                        width <= max_width && height <= max_height
+                    }) =>
+                {
+                    media_engine
+                }
+                early_engine => {
+                    if early_engine.is_some() {
+                        log::info!("Stream resolution {resolution:?} exceeds the hint, restarting");
+                    }
+                    // Stop the early codec before creating its replacement
+                    drop(early_engine);
+
+                    let mut attempt = 0;
+                    loop {
+                        if !creation_budget.spend() {
+                            return Err(codec_creation_limit(singleton));
+                        }
+                        let result = configure_media_engine(
+                            singleton,
+                            mime_type,
+                            codec_name,
+                            prewarmed_engine.take(),
+                            native_window.as_ref(),
+                            resolution,
+                        );
+                        match result {
+                            // Another app might release its codec soon
+                            Err(DecoderError::MediaEngine(status))
+                                if status.is_insufficient_resource() =>
+                            {
+                                if attempt == CODEC_RESOURCE_RETRIES {
+                                    report_connection_error(
+                                        singleton,
+                                        "Not enough decoder resources, close other video apps",
+                                    );
+                                    return Err(DecoderError::MediaEngine(status));
+                                }
+                                attempt += 1;
+                                log::warn!("Insufficient codec resources, retry {attempt}");
+                                tokio::time::sleep(CODEC_RESOURCE_RETRY_DELAY * attempt).await;
+                            }
+                            result => break result?,
+                        }
+                    }
+                }
+            };
+
+            // Submitted in sequence like `MediaCodec` does with csd-0 and csd-1. Must come before any
+            // frame, the held ones are queued by the input task afterwards.
+            for codec_config in decoder.codec_config() {
                 media_engine.submit_codec_config(codec_config)?;
             }
+            // The wait for the first keyframe includes creating the codec
+            keyframes.forget_pending();
+            singleton.stats().set_frame_rate(resolve_frame_rate(
+                decoder.frame_rate(),
+                negotiated.frame_rate_hint,
+            ));
 
-            return Ok(media_engine);
+            return Ok((media_engine, held_frames.frames));
         }
 
         match receiver.try_recv() {
@@ -309,55 +1091,65 @@ async fn create_media_engine<T: AndroidDecoder>(
                 MediaPlayerEvent::MainActivityDestroyed => {
                     return Err(DecoderError::ApplicationClosed)
                 }
-                MediaPlayerEvent::SurfaceCreated(surface) => {
-                    let env = singleton.vm.attach_current_thread()?;
-                    native_window = Some(
-                        NativeWindow::new(&env, &surface.as_obj())
-                            .ok_or(DecoderError::NativeWindowCreate)?,
-                    );
-                }
-                MediaPlayerEvent::SurfaceDestroyed => {
-                    native_window = None;
+                // Read from the singleton when the format is built
+                MediaPlayerEvent::RotationChanged(_) => (),
+                // A new surface is wrapped at the start of the next iteration. Focus only matters
+                // once rendering starts.
+                msg => {
+                    if surface.on_event(msg) == SurfaceChange::Lost {
+                        native_window = None;
+                        // Started with the old surface
+                        early_engine = None;
+                    }
                 }
             },
             Err(TryRecvError::Disconnected) => return Err(DecoderError::ApplicationClosed),
             Err(TryRecvError::Empty) => {
-                match reorder_buffer.recv().await {
-                    Ok(payload) => match reader.push(payload) {
+                let res = track_reader.recv(TRACK_READ_TIMEOUT).await;
+                if res.is_ok() {
+                    empty_payloads.payload_received();
+                }
+                match res {
+                    Ok(payload) => match reader.push(&payload) {
                         Ok(()) => {
                             let bytes_written = reader.finish();
                             let nalu = &payload_buf[..bytes_written];
-                            if let Err(_) = decoder.read_payload(nalu) {
-                                pli.send(peer).await?;
+                            match decoder.read_payload(nalu) {
+                                // A keyframe follows new parameter sets
+                                Ok(()) => held_frames.clear(),
+                                Err(()) => {
+                                    // Keep the keyframe that came with the parameter sets and what
+                                    // refers to it until the codec is configured
+                                    let holding = !held_frames.frames.is_empty()
+                                        || (decoder.init_done() && T::is_keyframe(nalu));
+                                    if !holding || !held_frames.push(nalu) {
+                                        held_frames.clear();
+                                        keyframes.request(peer).await?;
+                                    }
+                                }
                             }
                             reader = T::DepacketizerType::wrap_buffer(&mut payload_buf);
                         }
                         Err(DepacketizerError::NeedMoreInput) => continue,
                         Err(e) => {
                             log::error!("Depacketization error: {e:?}");
-                            pli.send(peer).await?;
+                            held_frames.clear();
+                            keyframes.request(peer).await?;
                             reader.finish();
                             reader = T::DepacketizerType::wrap_buffer(&mut payload_buf);
                         }
                     },
                     Err(e) => {
-                        match e {
-                            ReorderBufferError::HeaderParsingError
-                            | ReorderBufferError::TrackRemoteReadError => {
+                        match ReorderRecovery::for_error(&e, &mut empty_payloads) {
+                            ReorderRecovery::Ignore => (),
+                            ReorderRecovery::Resync => {
+                                held_frames.clear();
                                 reader.finish();
                                 reader = T::DepacketizerType::wrap_buffer(&mut payload_buf);
-                                pli.send(peer).await?;
+                                keyframes.request(peer).await?;
                             }
-                            ReorderBufferError::PacketTooShort => (), // Empty payload?
-                            ReorderBufferError::BufferFull => {
-                                // TODO: Should be NACK
-                                reader.finish();
-                                reader = T::DepacketizerType::wrap_buffer(&mut payload_buf);
-                                pli.send(peer).await?;
-                            }
-                            _ => (),
-                            // ReorderBufferError::TrackRemoteReadTimeout => todo!(),
-                            // ReorderBufferError::UnableToMaintainReorderBuffer => todo!(), // TODO: RENAME THIS
+                            // Keyframe requests are already retried above
+                            ReorderRecovery::Stall => (),
                         }
                     }
                 }
@@ -366,6 +1158,399 @@ async fn create_media_engine<T: AndroidDecoder>(
     }
 }
 
+/// Whether to reassemble into the heap buffer instead of `input_buffer`. Frames vary in size so
+/// the largest NALU so far is given some headroom. The keyframe that is waited for after a loss is
+/// usually larger than anything measured, it goes to the heap so that it can be split instead of
+/// being truncated.
+fn needs_heap_buffer(
+    input_buffer: &MediaInputBuffer,
+    largest_nalu: usize,
+    waiting_for_keyframe: bool,
+) -> bool {
+    waiting_for_keyframe || input_buffer.remaining_capacity() < largest_nalu + largest_nalu / 2
+}
+
+/// Wraps the buffer that the next NALU should be reassembled into.
+fn wrap_reader<'a>(
+    input_buffer: &'a mut [u8],
+    heap_buffer: &'a mut [u8],
+    use_heap: bool,
+) -> H264Depacketizer<'a> {
+    if use_heap {
+        H264Depacketizer::wrap_buffer(heap_buffer)
+    } else {
+        H264Depacketizer::wrap_buffer(input_buffer)
+    }
+}
+
+/// Reads the track on a task of its own and hands the payloads to the decoder loops through a
+/// channel. `BufferedTrackRemote::recv` is not cancel-safe: dropping it under a timeout can lose a
+/// packet it already took off the track or leave the reorder buffer halfway through an update. On
+/// the task every read runs to completion, while `TrackReader::recv` waits on the channel which
+/// is cancel-safe.
+struct TrackReader {
+    payloads: mpsc::Receiver<Result<Vec<u8>, ReorderBufferError>>,
+    task: JoinHandle<()>,
+}
+
+impl TrackReader {
+    fn spawn(track: Arc<TrackRemote>) -> TrackReader {
+        let (tx, payloads) = mpsc::channel(NUM_BUFFERED_PACKETS);
+        let task = tokio::spawn(async move {
+            let mut reorder_buffer = BufferedTrackRemote::new(track, NUM_BUFFERED_PACKETS);
+            loop {
+                let res = reorder_buffer.recv().await.map(|payload| payload.to_vec());
+                if tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        });
+        TrackReader { payloads, task }
+    }
+
+    /// Wait up to `timeout` for the next payload. Cancel-safe, nothing is lost if the returned
+    /// future is dropped before it completes.
+    async fn recv(&mut self, timeout: Duration) -> Result<Vec<u8>, ReorderBufferError> {
+        match tokio::time::timeout(timeout, self.payloads.recv()).await {
+            Ok(Some(res)) => res,
+            // The task only ends when the reader is dropped
+            Ok(None) => Err(ReorderBufferError::TrackRemoteReadError),
+            Err(_) => Err(ReorderBufferError::TrackRemoteReadTimeout),
+        }
+    }
+}
+
+impl Drop for TrackReader {
+    fn drop(&mut self) {
+        // Nothing reads the track after the session, a pending read can be dropped
+        self.task.abort();
+    }
+}
+
+/// What the decoder loops do after the reorder buffer fails to produce a payload. Kept apart from
+/// the loops so that every `ReorderBufferError` is classified in one place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReorderRecovery {
+    /// Nothing was lost, keep going.
+    Ignore,
+    /// Packets were lost. Drop the partial frame and wait for a keyframe.
+    Resync,
+    /// No packets arrived in time.
+    Stall,
+}
+
+impl ReorderRecovery {
+    fn for_error(
+        e: &ReorderBufferError,
+        empty_payloads: &mut EmptyPayloadMeter,
+    ) -> ReorderRecovery {
+        match e {
+            ReorderBufferError::PacketTooShort => {
+                if empty_payloads.record() {
+                    ReorderRecovery::Resync
+                } else {
+                    ReorderRecovery::Ignore
+                }
+            }
+            ReorderBufferError::HeaderParsingError | ReorderBufferError::TrackRemoteReadError => {
+                ReorderRecovery::Resync
+            }
+            // TODO: Should be NACK
+            ReorderBufferError::BufferFull | ReorderBufferError::UnableToMaintainReorderBuffer => {
+                ReorderRecovery::Resync
+            }
+            ReorderBufferError::TrackRemoteReadTimeout => ReorderRecovery::Stall,
+        }
+    }
+
+    /// What the main decoder loop does about it. `consecutive_timeouts` counts the stall being
+    /// handled.
+    fn actions(self, consecutive_timeouts: u32) -> RecoveryActions {
+        match self {
+            ReorderRecovery::Ignore => RecoveryActions::default(),
+            ReorderRecovery::Resync => RecoveryActions {
+                record_loss: true,
+                drop_reference_frame: true,
+                reset_reader: true,
+                request_keyframe: true,
+            },
+            ReorderRecovery::Stall => {
+                // The sender might have dropped a keyframe, ask again periodically
+                let resync = consecutive_timeouts % STALL_TIMEOUTS_BEFORE_DEGRADED == 0;
+                RecoveryActions {
+                    record_loss: false,
+                    drop_reference_frame: resync,
+                    reset_reader: resync,
+                    request_keyframe: resync,
+                }
+            }
+        }
+    }
+}
+
+/// Steps of recovering from a `ReorderRecovery` in the main decoder loop.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct RecoveryActions {
+    /// Count a loss for the quality meter and the bitrate estimator.
+    record_loss: bool,
+    /// Decode nothing until the next keyframe.
+    drop_reference_frame: bool,
+    /// Discard the partially reassembled frame.
+    reset_reader: bool,
+    /// Send a PLI.
+    request_keyframe: bool,
+}
+
+/// Frames that arrive after the parameter sets but before the codec is configured, starting with a
+/// keyframe.
+#[derive(Default)]
+struct HeldFrames {
+    frames: Vec<Vec<u8>>,
+    bytes: usize,
+}
+
+impl HeldFrames {
+    /// Returns false if the frame would exceed `MAX_HELD_BYTES`.
+    fn push(&mut self, frame: &[u8]) -> bool {
+        if self.bytes + frame.len() > MAX_HELD_BYTES {
+            return false;
+        }
+        self.bytes += frame.len();
+        self.frames.push(frame.to_vec());
+        true
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Tells padding apart from a broken sender. Padding-only packets, e.g. for bandwidth probing,
+/// have no payload but come between media packets, while a broken sender sends nothing else.
+#[derive(Default)]
+struct EmptyPayloadMeter {
+    // Empty payloads since the last one with data
+    run: u32,
+    run_start: Option<Instant>,
+}
+
+impl EmptyPayloadMeter {
+    fn payload_received(&mut self) {
+        self.run = 0;
+        self.run_start = None;
+    }
+
+    /// Count an empty payload. Returns `true` once a run of them is long enough to mean a broken
+    /// sender, after which counting starts over.
+    fn record(&mut self) -> bool {
+        let now = Instant::now();
+        let start = match self.run_start {
+            Some(start) if now - start <= EMPTY_PAYLOAD_WINDOW => start,
+            // A slow trickle is left alone
+            _ => {
+                self.run = 0;
+                now
+            }
+        };
+        self.run_start = Some(start);
+        self.run += 1;
+        if self.run < EMPTY_PAYLOADS_BEFORE_RESYNC {
+            return false;
+        }
+        log::warn!(
+            "Received {} empty payloads in {}ms with no media, the sender may be broken",
+            self.run,
+            (now - start).as_millis()
+        );
+        self.payload_received();
+        true
+    }
+}
+
+/// The frame rate to pace against. The bitstream is preferred over the SDP since it describes what
+/// the encoder actually produces.
+fn resolve_frame_rate(bitstream: Option<f64>, sdp: Option<f64>) -> f64 {
+    let (frame_rate, source) = match (bitstream, sdp) {
+        (Some(frame_rate), _) => (frame_rate, "the bitstream"),
+        (None, Some(frame_rate)) => (frame_rate, "the SDP"),
+        (None, None) => (DEFAULT_FRAME_RATE, "the default"),
+    };
+    log::info!("Nominal frame rate is {frame_rate:.2} fps from {source}");
+    frame_rate
+}
+
+/// Creates the named decoder, or the platform's default for the MIME type without a name.
+fn create_codec(
+    decoder_name: Option<&str>,
+    mime_type: MimeType,
+) -> Result<MediaEngine, DecoderError> {
+    match decoder_name {
+        Some(decoder_name) => Ok(MediaEngine::create_by_name(decoder_name)?),
+        // Only used after the chosen decoder turned out not to support the MIME type
+        None => {
+            MediaEngine::create_by_type(mime_type).map_err(|_| DecoderError::DecoderMimeMismatch)
+        }
+    }
+}
+
+/// Configures and starts `media_engine`, or a newly created codec if it is `None`.
+fn initialize_media_engine(
+    singleton: &NativeLibSingleton,
+    mime_type: MimeType,
+    decoder_name: Option<&str>,
+    media_engine: Option<MediaEngine>,
+    native_window: Option<&NativeWindow>,
+    resolution: Option<(i32, i32)>,
+    vendor_low_latency: bool,
+) -> Result<MediaEngine, DecoderError> {
+    let mut format = MediaFormat::new()?;
+    format.set_mime_type(mime_type);
+    format.set_realtime_priority(true);
+    if singleton.api_level() >= 30 {
+        format.set_low_latency(true);
+    }
+    if vendor_low_latency {
+        format.set_integer(VENDOR_LOW_LATENCY_KEY, 1);
+    }
+
+    if mime_type.is_video() {
+        format.set_rotation(singleton.video_rotation())?;
+        if let Some((width, height)) = resolution {
+            format.set_resolution(width, height);
+            format.set_max_resolution(width, height);
+        }
+    }
+
+    format.validate_for_decode(mime_type)?;
+
+    let mut media_engine = match media_engine {
+        Some(media_engine) => media_engine,
+        None => create_codec(decoder_name, mime_type)?,
+    };
+    media_engine.initialize(format, native_window, false)?;
+    Ok(media_engine)
+}
+
+/// Creates and starts a codec for the given output. `resolution` is used to size the codec.
+///
+/// `prewarmed_engine` is used instead of creating a codec if given. It must not have been
+/// configured yet. Without a `decoder_name` the platform's default decoder is used.
+fn configure_media_engine(
+    singleton: &NativeLibSingleton,
+    mime_type: MimeType,
+    decoder_name: Option<&str>,
+    prewarmed_engine: Option<MediaEngine>,
+    native_window: Option<&NativeWindow>,
+    resolution: Option<(i32, i32)>,
+) -> Result<MediaEngine, DecoderError> {
+    // Decoders that do not know the vendor key may refuse to be configured with it
+    let vendor_low_latency = decoder_name.map_or(false, |name| {
+        VENDOR_LOW_LATENCY_DECODER_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    });
+    let decoder_label = decoder_name.unwrap_or("The default decoder");
+    let initialize = |media_engine, vendor_low_latency| {
+        initialize_media_engine(
+            singleton,
+            mime_type,
+            decoder_name,
+            media_engine,
+            native_window,
+            resolution,
+            vendor_low_latency,
+        )
+    };
+    let media_engine = match initialize(prewarmed_engine, vendor_low_latency) {
+        Err(DecoderError::MediaEngine(e)) if vendor_low_latency => {
+            log::warn!("{decoder_label} failed with the vendor low latency key ({e}), retrying");
+            initialize(None, false)?
+        }
+        result => result?,
+    };
+
+    Ok(media_engine)
+}
+
+fn codec_creation_limit(singleton: &NativeLibSingleton) -> DecoderError {
+    log::error!("Recreated the codec too often, giving up");
+    report_connection_error(singleton, "The stream can't be decoded on this device");
+    DecoderError::CodecCreationLimit
+}
+
+/// Spaces rendered frames like their presentation timestamps, a few frame intervals after the
+/// first one, for `RenderPolicy::Smooth`. The timestamps are arrival times so this evens out the
+/// jitter added by the decoder.
+struct FramePacer {
+    render_delay: Duration,
+    // When the anchor frame was due and its timestamp in microseconds
+    anchor: Option<(Instant, u64)>,
+}
+
+impl FramePacer {
+    fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            render_delay: Duration::from_secs_f64(SMOOTH_FRAMES_HELD / frame_rate)
+                .min(SMOOTH_MAX_LATENESS),
+            anchor: None,
+        }
+    }
+
+    /// How long to hold the frame with the given timestamp before rendering it.
+    fn delay(&mut self, pts: u64) -> Duration {
+        let now = Instant::now();
+        if let Some((anchor_time, anchor_pts)) = self.anchor {
+            let due = anchor_time + Duration::from_micros(pts.saturating_sub(anchor_pts));
+            match due.checked_duration_since(now) {
+                Some(delay) if delay <= SMOOTH_MAX_LATENESS => return delay,
+                None if now - due <= SMOOTH_MAX_LATENESS => return Duration::ZERO,
+                // Too far off, e.g. after a stall or a jump in the timestamps
+                _ => (),
+            }
+        }
+        self.anchor = Some((now + self.render_delay, pts));
+        self.render_delay
+    }
+
+    fn reset(&mut self) {
+        self.anchor = None;
+    }
+}
+
+/// Bounds how many times something may happen within a sliding window of time.
+struct CreationBudget {
+    times: VecDeque<Instant>,
+    max: usize,
+    window: Duration,
+}
+
+impl CreationBudget {
+    fn new(max: usize, window: Duration) -> Self {
+        CreationBudget {
+            times: VecDeque::with_capacity(max),
+            max,
+            window,
+        }
+    }
+
+    /// Record one use. Returns `false` without recording if the budget is used up.
+    fn spend(&mut self) -> bool {
+        while self
+            .times
+            .front()
+            .map_or(false, |t| t.elapsed() >= self.window)
+        {
+            self.times.pop_front();
+        }
+        if self.times.len() >= self.max {
+            return false;
+        }
+        self.times.push_back(Instant::now());
+        true
+    }
+}
+
 struct DebugTimings(Vec<Instant>);
 
 impl DebugTimings {
@@ -400,3 +1585,108 @@ impl DebugTimings {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESYNC: RecoveryActions = RecoveryActions {
+        record_loss: true,
+        drop_reference_frame: true,
+        reset_reader: true,
+        request_keyframe: true,
+    };
+
+    #[test]
+    fn lost_packets_resync() {
+        for e in [
+            ReorderBufferError::HeaderParsingError,
+            ReorderBufferError::TrackRemoteReadError,
+            ReorderBufferError::BufferFull,
+            ReorderBufferError::UnableToMaintainReorderBuffer,
+        ] {
+            let recovery = ReorderRecovery::for_error(&e, &mut EmptyPayloadMeter::default());
+            assert_eq!(recovery, ReorderRecovery::Resync, "{e:?}");
+            assert_eq!(recovery.actions(0), RESYNC, "{e:?}");
+        }
+    }
+
+    #[test]
+    fn timeouts_stall() {
+        let recovery = ReorderRecovery::for_error(
+            &ReorderBufferError::TrackRemoteReadTimeout,
+            &mut EmptyPayloadMeter::default(),
+        );
+        assert_eq!(recovery, ReorderRecovery::Stall);
+    }
+
+    #[test]
+    fn stalls_resync_every_few_timeouts() {
+        let stall = ReorderRecovery::Stall;
+        for consecutive_timeouts in 1..STALL_TIMEOUTS_BEFORE_DEGRADED {
+            assert_eq!(
+                stall.actions(consecutive_timeouts),
+                RecoveryActions::default()
+            );
+        }
+        for multiple in 1..4 {
+            let actions = stall.actions(STALL_TIMEOUTS_BEFORE_DEGRADED * multiple);
+            // Nothing was lost, the keyframe is asked for again in case it was
+            assert_eq!(
+                actions,
+                RecoveryActions {
+                    record_loss: false,
+                    ..RESYNC
+                }
+            );
+        }
+        assert_eq!(
+            stall.actions(STALL_TIMEOUTS_BEFORE_DEGRADED + 1),
+            RecoveryActions::default()
+        );
+    }
+
+    #[test]
+    fn ignoring_does_nothing() {
+        assert_eq!(
+            ReorderRecovery::Ignore.actions(0),
+            RecoveryActions::default()
+        );
+    }
+
+    #[test]
+    fn empty_payloads_are_ignored_until_a_run() {
+        let mut meter = EmptyPayloadMeter::default();
+        for _ in 1..EMPTY_PAYLOADS_BEFORE_RESYNC {
+            let recovery =
+                ReorderRecovery::for_error(&ReorderBufferError::PacketTooShort, &mut meter);
+            assert_eq!(recovery, ReorderRecovery::Ignore);
+        }
+        let recovery = ReorderRecovery::for_error(&ReorderBufferError::PacketTooShort, &mut meter);
+        assert_eq!(recovery, ReorderRecovery::Resync);
+
+        // Counting starts over after a resync
+        assert!(!meter.record());
+    }
+
+    #[test]
+    fn media_between_empty_payloads_resets_the_run() {
+        let mut meter = EmptyPayloadMeter::default();
+        for _ in 0..EMPTY_PAYLOADS_BEFORE_RESYNC * 2 {
+            assert!(!meter.record());
+            meter.payload_received();
+        }
+    }
+
+    #[test]
+    fn slow_empty_payloads_reset_the_run() {
+        let mut meter = EmptyPayloadMeter::default();
+        for _ in 1..EMPTY_PAYLOADS_BEFORE_RESYNC {
+            assert!(!meter.record());
+        }
+        // As if the run had started before the window
+        meter.run_start = Instant::now().checked_sub(EMPTY_PAYLOAD_WINDOW * 2);
+        assert!(!meter.record());
+        assert_eq!(meter.run, 1);
+    }
+}
@@ -0,0 +1,388 @@
+// Audio tracks are not negotiated yet, this is what their decoder will be set up and fed with
+#![allow(dead_code)]
+
+use crate::media::{MediaEngine, MediaFormat, MediaStatus, MediaTimeout};
+use std::{collections::VecDeque, ffi::c_ulong};
+use webrtc::{rtp_transceiver::rtp_codec::RTCRtpCodecCapability, track::track_remote::TrackRemote};
+
+/// Opus always runs at 48 kHz on the wire (RFC 7587), decoders can resample to these.
+const OPUS_SAMPLE_RATES: [i32; 5] = [8000, 12000, 16000, 24000, 48000];
+const OPUS_CLOCK_RATE: i32 = 48000;
+/// Live streams start mid-way so there is no encoder delay to skip.
+const PRE_SKIP: u16 = 0;
+/// Audio to decode and discard after a discontinuity, recommended by RFC 7845.
+const SEEK_PRE_ROLL_NS: i64 = 80_000_000;
+
+/// Packets held back while waiting for a missing one, 100 ms at the usual 20 ms per packet.
+const REORDER_WINDOW: usize = 5;
+/// Longer losses are skipped instead of concealed, the decoder would only be guessing.
+const MAX_CONCEALED_PACKETS: u16 = 5;
+/// The usual WebRTC ptime, assumed until a packet says otherwise.
+const DEFAULT_PACKET_DURATION_US: u64 = 20_000;
+
+/// How the Opus decoder is configured, from the fmtp line negotiated in the SDP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpusConfig {
+    pub sample_rate: i32,
+    pub channels: i32,
+    /// Whether the sender includes forward error correction data for the previous packet.
+    pub inband_fec: bool,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        OpusConfig {
+            sample_rate: OPUS_CLOCK_RATE,
+            channels: 2,
+            inband_fec: false,
+        }
+    }
+}
+
+impl OpusConfig {
+    /// Read `maxplaybackrate`, `sprop-stereo`, `stereo` and `useinbandfec`. Anything missing or
+    /// invalid keeps the default of 48 kHz stereo without FEC.
+    pub fn from_capability(capability: &RTCRtpCodecCapability) -> OpusConfig {
+        let mut config = OpusConfig::default();
+        let mut max_playback_rate = None;
+        let mut sprop_stereo = None;
+        let mut stereo = None;
+        for param in capability.sdp_fmtp_line.split(';') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "maxplaybackrate" => max_playback_rate = value.parse::<i32>().ok(),
+                "sprop-stereo" => sprop_stereo = Some(value == "1"),
+                "stereo" => stereo = Some(value == "1"),
+                "useinbandfec" => config.inband_fec = value == "1",
+                _ => (),
+            }
+        }
+
+        // The lowest rate that still holds everything the sender encodes
+        let clock_rate = match capability.clock_rate {
+            0 => OPUS_CLOCK_RATE,
+            rate => (rate as i32).min(OPUS_CLOCK_RATE),
+        };
+        let max_rate = max_playback_rate.map_or(clock_rate, |rate| rate.min(clock_rate));
+        config.sample_rate = OPUS_SAMPLE_RATES
+            .into_iter()
+            .find(|&rate| rate >= max_rate)
+            .unwrap_or(OPUS_CLOCK_RATE);
+
+        // `sprop-stereo` describes what the sender sends, `stereo` only what we asked for
+        if !sprop_stereo.or(stereo).unwrap_or(true) {
+            config.channels = 1;
+        }
+        config
+    }
+
+    /// Set the keys the decoder needs on top of the MIME type.
+    pub fn apply_to(&self, format: &mut MediaFormat) {
+        format.set_sample_rate(self.sample_rate);
+        format.set_channel_count(self.channels);
+    }
+
+    /// Codec specific data in the order `MediaCodec` expects it: the identification header, then
+    /// the codec delay and seek pre-roll in nanoseconds.
+    pub fn codec_config(&self) -> [Vec<u8>; 3] {
+        // RFC 7845 section 5.1, channel mapping family 0 covers mono and stereo
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // Version
+        head.push(self.channels as u8);
+        head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+        head.extend_from_slice(&(self.sample_rate as u32).to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // Output gain
+        head.push(0); // Channel mapping family
+
+        let codec_delay_ns = PRE_SKIP as i64 * 1_000_000_000 / OPUS_CLOCK_RATE as i64;
+        [
+            head,
+            codec_delay_ns.to_le_bytes().to_vec(),
+            SEEK_PRE_ROLL_NS.to_le_bytes().to_vec(),
+        ]
+    }
+}
+
+/// An Opus packet in sequence order, or the gap left by packets that never arrived.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OpusPacket {
+    Payload(Vec<u8>),
+    /// This many consecutive packets were lost.
+    Lost(u16),
+}
+
+/// Puts the RTP packets of an Opus track back in order. `BufferedTrackRemote` doesn't tell where
+/// packets went missing, so the audio path reads the RTP packets itself. A missing packet holds
+/// back the ones after it until the window is full, then it is reported as lost.
+#[derive(Default)]
+pub struct OpusReorderBuffer {
+    // Packets from `next_seq` on, `None` for the ones that have not arrived
+    window: VecDeque<Option<Vec<u8>>>,
+    next_seq: Option<u16>,
+    ready: VecDeque<OpusPacket>,
+}
+
+impl OpusReorderBuffer {
+    /// Read the track until the next packet or gap in sequence order.
+    pub async fn recv(&mut self, track: &TrackRemote) -> Result<OpusPacket, webrtc::Error> {
+        loop {
+            if let Some(packet) = self.pop() {
+                return Ok(packet);
+            }
+            let (rtp_packet, _) = track.read_rtp().await?;
+            self.push(
+                rtp_packet.header.sequence_number,
+                rtp_packet.payload.to_vec(),
+            );
+        }
+    }
+
+    /// Add a packet by its RTP sequence number. Duplicates and packets that were already given up
+    /// on are dropped.
+    pub fn push(&mut self, seq: u16, payload: Vec<u8>) {
+        let mut next_seq = *self.next_seq.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next_seq);
+        // Behind the window
+        if offset >= 0x8000 {
+            return;
+        }
+
+        // Give up on the oldest packets until the new one fits
+        let mut offset = offset as usize;
+        while offset >= REORDER_WINDOW {
+            // Skip the whole gap at once if nothing is held back
+            let skipped = if self.window.is_empty() {
+                offset + 1 - REORDER_WINDOW
+            } else {
+                1
+            };
+            match self.window.pop_front().flatten() {
+                Some(payload) => self.ready.push_back(OpusPacket::Payload(payload)),
+                None => self.lost(skipped as u16),
+            }
+            next_seq = next_seq.wrapping_add(skipped as u16);
+            offset -= skipped;
+        }
+
+        if self.window.len() <= offset {
+            self.window.resize(offset + 1, None);
+        }
+        self.window[offset].get_or_insert(payload);
+
+        while let Some(Some(_)) = self.window.front() {
+            if let Some(Some(payload)) = self.window.pop_front() {
+                self.ready.push_back(OpusPacket::Payload(payload));
+            }
+            next_seq = next_seq.wrapping_add(1);
+        }
+        self.next_seq = Some(next_seq);
+    }
+
+    /// Take the next packet or gap that is no longer waiting on anything.
+    pub fn pop(&mut self) -> Option<OpusPacket> {
+        self.ready.pop_front()
+    }
+
+    fn lost(&mut self, count: u16) {
+        if let Some(OpusPacket::Lost(lost)) = self.ready.back_mut() {
+            *lost = lost.saturating_add(count);
+        } else {
+            self.ready.push_back(OpusPacket::Lost(count));
+        }
+    }
+}
+
+/// Queues the packets of an Opus track to the decoder, with an empty buffer in place of each lost
+/// one so that it can be concealed.
+pub struct OpusDecoderInput {
+    // Presentation time of the next packet in microseconds
+    next_pts: u64,
+    // Assumed for lost packets, like Opus' own concealment does
+    last_duration: u64,
+}
+
+impl Default for OpusDecoderInput {
+    fn default() -> Self {
+        OpusDecoderInput {
+            next_pts: 0,
+            last_duration: DEFAULT_PACKET_DURATION_US,
+        }
+    }
+}
+
+impl OpusDecoderInput {
+    /// Queue a packet, or conceal a gap of up to `MAX_CONCEALED_PACKETS`.
+    pub fn submit(
+        &mut self,
+        media_engine: &MediaEngine,
+        packet: OpusPacket,
+    ) -> Result<(), MediaStatus> {
+        match packet {
+            OpusPacket::Payload(payload) => {
+                let mut input_buffer = media_engine.dequeue_input_buffer(MediaTimeout::INFINITE)?;
+                let num_bytes = if payload.len() <= input_buffer.len() {
+                    input_buffer[..payload.len()].copy_from_slice(&payload);
+                    payload.len()
+                } else {
+                    log::warn!("Concealing an Opus packet of {} bytes", payload.len());
+                    0
+                };
+                media_engine.queue_input_buffer(
+                    input_buffer,
+                    num_bytes as c_ulong,
+                    self.next_pts,
+                    0,
+                )?;
+                self.last_duration = packet_duration_us(&payload).unwrap_or(self.last_duration);
+                self.next_pts += self.last_duration;
+            }
+            OpusPacket::Lost(count) => {
+                // MediaCodec has no flag for a lost packet. An empty buffer with no flags at the
+                // time the packet should have played keeps the timeline continuous, and decoders
+                // that conceal loss fill it in.
+                for _ in 0..count.min(MAX_CONCEALED_PACKETS) {
+                    let input_buffer = media_engine.dequeue_input_buffer(MediaTimeout::INFINITE)?;
+                    media_engine.queue_input_buffer(input_buffer, 0, self.next_pts, 0)?;
+                    self.next_pts += self.last_duration;
+                }
+                let skipped = count.saturating_sub(MAX_CONCEALED_PACKETS);
+                self.next_pts += skipped as u64 * self.last_duration;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Duration of an Opus packet in microseconds from its TOC byte, RFC 6716 section 3.1.
+fn packet_duration_us(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = (toc >> 3) as usize;
+    let frame_duration = match config {
+        // SILK-only
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        // Hybrid
+        12..=15 => [10_000, 20_000][config % 2],
+        // CELT-only
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    };
+    let frame_count = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (packet.get(1)? & 0x3f) as u64,
+    };
+    // A packet holds at most 120 ms
+    let duration = frame_duration * frame_count;
+    (frame_count > 0 && duration <= 120_000).then_some(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(buffer: &mut OpusReorderBuffer) -> Vec<OpusPacket> {
+        std::iter::from_fn(|| buffer.pop()).collect()
+    }
+
+    fn payload(byte: u8) -> OpusPacket {
+        OpusPacket::Payload(vec![byte])
+    }
+
+    #[test]
+    fn releases_in_order_packets_at_once() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(10, vec![10]);
+        buffer.push(11, vec![11]);
+        assert_eq!(drain(&mut buffer), [payload(10), payload(11)]);
+    }
+
+    #[test]
+    fn reorders_within_the_window() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(10, vec![10]);
+        buffer.push(12, vec![12]);
+        assert_eq!(drain(&mut buffer), [payload(10)]);
+        buffer.push(11, vec![11]);
+        assert_eq!(drain(&mut buffer), [payload(11), payload(12)]);
+    }
+
+    #[test]
+    fn reports_a_gap_once_the_window_is_full() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(10, vec![10]);
+        for seq in 12..16 {
+            buffer.push(seq, vec![seq as u8]);
+        }
+        assert_eq!(drain(&mut buffer), [payload(10)]);
+        buffer.push(16, vec![16]);
+        let expected = [
+            OpusPacket::Lost(1),
+            payload(12),
+            payload(13),
+            payload(14),
+            payload(15),
+            payload(16),
+        ];
+        assert_eq!(drain(&mut buffer), expected);
+        // Too late now
+        buffer.push(11, vec![11]);
+        assert!(drain(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn coalesces_a_long_gap() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(10, vec![10]);
+        buffer.push(110, vec![110]);
+        assert_eq!(drain(&mut buffer), [payload(10), OpusPacket::Lost(95)]);
+        // The packets in the window are still waited for
+        buffer.push(107, vec![107]);
+        assert!(drain(&mut buffer).is_empty());
+        buffer.push(106, vec![106]);
+        assert_eq!(drain(&mut buffer), [payload(106), payload(107)]);
+    }
+
+    #[test]
+    fn wraps_around_the_sequence_number() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(u16::MAX, vec![0xff]);
+        buffer.push(1, vec![1]);
+        buffer.push(0, vec![0]);
+        assert_eq!(drain(&mut buffer), [payload(0xff), payload(0), payload(1)]);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut buffer = OpusReorderBuffer::default();
+        buffer.push(10, vec![10]);
+        buffer.push(12, vec![12]);
+        buffer.push(12, vec![0]);
+        buffer.push(10, vec![0]);
+        buffer.push(11, vec![11]);
+        assert_eq!(drain(&mut buffer), [payload(10), payload(11), payload(12)]);
+    }
+
+    #[test]
+    fn packet_duration_from_the_toc() {
+        // CELT fullband 20 ms, one frame
+        assert_eq!(packet_duration_us(&[0xf8]), Some(20_000));
+        // Hybrid fullband 10 ms, two frames
+        assert_eq!(packet_duration_us(&[0x71]), Some(20_000));
+        // SILK narrowband 60 ms, two frames of different sizes
+        assert_eq!(packet_duration_us(&[0x1a]), Some(120_000));
+        // SILK narrowband 20 ms, three frames
+        assert_eq!(packet_duration_us(&[0x0b, 0x03]), Some(60_000));
+    }
+
+    #[test]
+    fn invalid_packet_durations() {
+        assert_eq!(packet_duration_us(&[]), None);
+        // Frame count missing, zero, or over 120 ms
+        assert_eq!(packet_duration_us(&[0x0b]), None);
+        assert_eq!(packet_duration_us(&[0x0b, 0x00]), None);
+        assert_eq!(packet_duration_us(&[0x0b, 0x07]), None);
+    }
+}
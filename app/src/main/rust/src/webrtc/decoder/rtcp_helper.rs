@@ -1,38 +1,340 @@
 use super::DecoderError;
+use crate::KeyframeStrategy;
 use std::{
-    sync::Arc,
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use webrtc::{
+    rtcp::{
+        self,
+        payload_feedbacks::{
+            full_intra_request::{FirEntry, FullIntraRequest},
+            picture_loss_indication::PictureLossIndication,
+        },
+        receiver_report::ReceiverReport,
+        reception_report::ReceptionReport,
+        sender_report::SenderReport,
+    },
+    rtp_transceiver::rtp_receiver::RTCRtpReceiver,
 };
-use webrtc::rtcp::{self, payload_feedbacks::picture_loss_indication::PictureLossIndication};
 use webrtc_helper::WebRtcPeer;
 
-pub struct RateLimitedPli {
+/// Lower bound of the PLI interval, used until the round trip has been measured.
+const PLI_INTERVAL: Duration = Duration::from_millis(50);
+/// Added to the round trip to cover the sender's time to encode the keyframe.
+const PLI_RTT_MARGIN: Duration = Duration::from_millis(20);
+const FIR_INTERVAL: Duration = Duration::from_secs(1);
+const FIR_AFTER: Duration = Duration::from_secs(2);
+/// Seconds from the NTP epoch, 1900, to the UNIX epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// RTCP feedback the client acts on or sends, as written after the payload type in `a=rtcp-fb`.
+/// The sender only honors feedback that was negotiated, so these go into the offer.
+pub const RTCP_FEEDBACK: &[&str] = &["nack", "nack pli", "ccm fir", "transport-cc"];
+
+/// Microseconds on a monotonic clock, so that a change of the wall clock can't stall or flood the
+/// requests. Never 0, which the atomics below use for "never".
+#[derive(Debug, Clone, Copy)]
+struct MonotonicClock(Instant);
+
+impl MonotonicClock {
+    fn now_micros(&self) -> u64 {
+        self.0.elapsed().as_micros() as u64 + 1
+    }
+}
+
+/// Sends PLIs at most once per interval. Shared between the decoder loops so that their combined
+/// rate is limited, not each loop's.
+struct RateLimitedPli {
     rtcp_packets: [Box<dyn rtcp::packet::Packet + Send + Sync>; 1],
-    last_pli_time: SystemTime,
-    pli_interval: Duration,
+    clock: MonotonicClock,
+    // Microseconds on `clock`, 0 if none was sent
+    last_pli_time: AtomicU64,
+    min_interval: Duration,
+    // Microseconds, follows the round trip so that a PLI isn't repeated before it can be answered
+    pli_interval: AtomicU64,
 }
 
 impl RateLimitedPli {
-    pub fn new(media_ssrc: u32, pli_interval: Duration) -> RateLimitedPli {
+    fn new(media_ssrc: u32, min_interval: Duration, clock: MonotonicClock) -> RateLimitedPli {
         let pli = PictureLossIndication {
             sender_ssrc: 0,
             media_ssrc,
         };
         RateLimitedPli {
             rtcp_packets: [Box::new(pli) as _],
-            last_pli_time: SystemTime::UNIX_EPOCH,
-            pli_interval,
+            clock,
+            last_pli_time: AtomicU64::new(0),
+            min_interval,
+            pli_interval: AtomicU64::new(min_interval.as_micros() as u64),
         }
     }
 
-    pub async fn send(&mut self, peer: &Arc<WebRtcPeer>) -> Result<(), DecoderError> {
-        let now = SystemTime::now();
-        if let Ok(duration) = now.duration_since(self.last_pli_time) {
-            if duration > self.pli_interval {
-                peer.write_rtcp(&self.rtcp_packets).await?;
-                self.last_pli_time = now;
-            }
+    /// Space PLIs by the round trip plus a margin, but no closer than the minimum interval.
+    fn set_rtt(&self, rtt: Duration) {
+        let interval = (rtt + PLI_RTT_MARGIN).clamp(self.min_interval, FIR_INTERVAL);
+        self.pli_interval
+            .store(interval.as_micros() as u64, Ordering::Release);
+    }
+
+    async fn send(&self, peer: &Arc<WebRtcPeer>) -> Result<(), DecoderError> {
+        let now = self.clock.now_micros();
+        let interval = self.pli_interval.load(Ordering::Acquire);
+
+        // Claim the slot first so that only one caller sends within the interval
+        let claimed = self
+            .last_pli_time
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                (last == 0 || now.saturating_sub(last) > interval).then_some(now)
+            })
+            .is_ok();
+        if claimed {
+            peer.write_rtcp(&self.rtcp_packets).await?;
         }
         Ok(())
     }
 }
+
+/// Sends FIRs for senders that ignore PLIs. Each request carries a new sequence number as
+/// required by RFC 5104.
+struct RateLimitedFir {
+    media_ssrc: u32,
+    sequence_number: AtomicU8,
+    clock: MonotonicClock,
+    // Microseconds on `clock`, 0 if none was sent
+    last_fir_time: AtomicU64,
+    fir_interval: Duration,
+}
+
+impl RateLimitedFir {
+    fn new(media_ssrc: u32, fir_interval: Duration, clock: MonotonicClock) -> RateLimitedFir {
+        RateLimitedFir {
+            media_ssrc,
+            sequence_number: AtomicU8::new(0),
+            clock,
+            last_fir_time: AtomicU64::new(0),
+            fir_interval,
+        }
+    }
+
+    async fn send(&self, peer: &Arc<WebRtcPeer>) -> Result<(), DecoderError> {
+        let now = self.clock.now_micros();
+        let interval = self.fir_interval.as_micros() as u64;
+
+        let claimed = self
+            .last_fir_time
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                (last == 0 || now.saturating_sub(last) > interval).then_some(now)
+            })
+            .is_ok();
+        if claimed {
+            let fir = FullIntraRequest {
+                sender_ssrc: 0,
+                media_ssrc: self.media_ssrc,
+                fir: vec![FirEntry {
+                    ssrc: self.media_ssrc,
+                    sequence_number: self.sequence_number.fetch_add(1, Ordering::AcqRel),
+                }],
+            };
+            peer.write_rtcp(&[Box::new(fir) as Box<dyn rtcp::packet::Packet + Send + Sync>])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The single place that decides how keyframes are asked for. Shared between the decoder loops
+/// so that escalation from PLI to FIR accounts for requests made by either.
+pub struct KeyframeRequester {
+    strategy: KeyframeStrategy,
+    clock: MonotonicClock,
+    pli: RateLimitedPli,
+    fir: RateLimitedFir,
+    // Microseconds on `clock` of the first request not yet answered by a keyframe, 0 if there is
+    // none
+    pending_since: AtomicU64,
+    // Smoothed round trip in microseconds, 0 until measured
+    rtt: AtomicU64,
+    // Set once the RTCP reports gave a round trip, which then replaces the keyframe timing
+    rtt_from_reports: AtomicBool,
+}
+
+impl KeyframeRequester {
+    pub fn new(media_ssrc: u32, strategy: KeyframeStrategy) -> KeyframeRequester {
+        let clock = MonotonicClock(Instant::now());
+        KeyframeRequester {
+            strategy,
+            clock,
+            pli: RateLimitedPli::new(media_ssrc, PLI_INTERVAL, clock),
+            fir: RateLimitedFir::new(media_ssrc, FIR_INTERVAL, clock),
+            pending_since: AtomicU64::new(0),
+            rtt: AtomicU64::new(0),
+            rtt_from_reports: AtomicBool::new(false),
+        }
+    }
+
+    /// Ask the sender for a keyframe according to the strategy.
+    pub async fn request(&self, peer: &Arc<WebRtcPeer>) -> Result<(), DecoderError> {
+        let now = self.clock.now_micros();
+        // Only the first unanswered request starts the escalation timer
+        let pending_since = self
+            .pending_since
+            .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire)
+            .err()
+            .unwrap_or(now);
+
+        match self.strategy {
+            KeyframeStrategy::Pli => self.pli.send(peer).await,
+            KeyframeStrategy::Fir => self.fir.send(peer).await,
+            KeyframeStrategy::PliThenFir => {
+                self.pli.send(peer).await?;
+                // Fall back to FIR if the sender keeps ignoring the PLIs
+                if now.saturating_sub(pending_since) >= FIR_AFTER.as_micros() as u64 {
+                    self.fir.send(peer).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Record that a keyframe arrived, ending the escalation of the pending requests.
+    ///
+    /// Until the RTCP reports give a round trip, the time from a request to its keyframe is used
+    /// instead, which also covers how long the sender takes to respond.
+    pub fn keyframe_received(&self) {
+        let pending_since = self.pending_since.swap(0, Ordering::AcqRel);
+        let sample = self.clock.now_micros().saturating_sub(pending_since);
+        // Keyframes that were not asked for say nothing about the round trip, nor do requests that
+        // the sender ignored until they were escalated
+        if pending_since == 0
+            || sample >= FIR_AFTER.as_micros() as u64
+            || self.rtt_from_reports.load(Ordering::Acquire)
+        {
+            return;
+        }
+        self.add_rtt_sample(sample);
+    }
+
+    /// Take the round trip from the sender's reception reports about us, RFC 3550 section 6.4.1.
+    /// They only carry one once the sender got a sender report from us.
+    pub fn rtcp_received(&self, packets: &[Box<dyn rtcp::packet::Packet + Send + Sync>]) {
+        let Some(arrival) = ntp_now() else {
+            return;
+        };
+        let reports = packets.iter().flat_map(|packet| {
+            let packet = packet.as_any();
+            if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                rr.reports.as_slice()
+            } else if let Some(sr) = packet.downcast_ref::<SenderReport>() {
+                sr.reports.as_slice()
+            } else {
+                &[]
+            }
+        });
+        for report in reports {
+            if let Some(rtt) = rtt_from_report(report, arrival) {
+                self.rtt_from_reports.store(true, Ordering::Release);
+                self.add_rtt_sample(rtt.as_micros() as u64);
+            }
+        }
+    }
+
+    /// Drop the pending requests without measuring the round trip, for when the wait included
+    /// something else, e.g. creating the codec.
+    pub fn forget_pending(&self) {
+        self.pending_since.store(0, Ordering::Release);
+    }
+
+    fn add_rtt_sample(&self, sample: u64) {
+        // Smoothed like TCP's round-trip time estimate, RFC 6298
+        let smooth = |rtt: u64| {
+            if rtt == 0 {
+                sample
+            } else {
+                rtt - rtt / 8 + sample / 8
+            }
+        };
+        let previous = self
+            .rtt
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |rtt| Some(smooth(rtt)))
+            .unwrap_or_else(|rtt| rtt);
+        self.pli.set_rtt(Duration::from_micros(smooth(previous)));
+    }
+}
+
+/// Read the RTCP packets of the track until the receiver stops, feeding the reports to `keyframes`.
+pub async fn read_rtcp(rtp_receiver: Arc<RTCRtpReceiver>, keyframes: Arc<KeyframeRequester>) {
+    while let Ok((packets, _)) = rtp_receiver.read_rtcp().await {
+        keyframes.rtcp_received(&packets);
+    }
+}
+
+/// Arrival time minus the report's LSR and DLSR. `arrival` is a 64-bit NTP timestamp, the rest are
+/// in 1/65536 s. `None` if the report doesn't refer to a sender report or the result is negative,
+/// which clock drift between the hosts can cause.
+fn rtt_from_report(report: &ReceptionReport, arrival: u64) -> Option<Duration> {
+    if report.last_sender_report == 0 {
+        return None;
+    }
+    let compact_arrival = (arrival >> 16) as u32;
+    let rtt = compact_arrival
+        .wrapping_sub(report.last_sender_report)
+        .wrapping_sub(report.delay);
+    // Anything past FIR_AFTER is a wrapped negative or a stale report
+    let rtt = Duration::from_micros(rtt as u64 * 1_000_000 / 65536);
+    (rtt < FIR_AFTER).then_some(rtt)
+}
+
+/// Now as a 64-bit NTP timestamp, the upper half seconds since 1900.
+fn ntp_now() -> Option<u64> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    Some(seconds << 32 | fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(last_sender_report: u32, delay: u32) -> ReceptionReport {
+        ReceptionReport {
+            last_sender_report,
+            delay,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rtt_is_arrival_minus_lsr_and_dlsr() {
+        // Arrival at 1000.5 s, LSR at 1000 s, held for 0.25 s by the sender
+        let arrival = 1000 << 32 | 1 << 31;
+        let rtt = rtt_from_report(&report(1000 << 16, 1 << 14), arrival);
+        assert_eq!(rtt, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn rtt_wraps_with_the_compact_timestamp() {
+        // LSR just before the middle 32 bits wrap, arrival just after
+        let arrival = 0x1_0000_0100_0000;
+        let rtt = rtt_from_report(&report(0xffff_ff00, 0), arrival);
+        assert_eq!(rtt, Some(Duration::from_micros(0x200 * 1_000_000 / 65536)));
+    }
+
+    #[test]
+    fn rtt_needs_a_sender_report() {
+        assert_eq!(rtt_from_report(&report(0, 0), 1000 << 32), None);
+    }
+
+    #[test]
+    fn negative_rtt_is_ignored() {
+        // The delay is longer than the time since the LSR
+        let arrival = 1000 << 32;
+        assert_eq!(rtt_from_report(&report(1000 << 16, 1), arrival), None);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::MediaPlayerEvent;
+use jni::objects::GlobalRef;
+use std::time::{Duration, Instant};
+
+/// What the decoder can do with its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceState {
+    /// There is no surface, or the latest one has not been applied yet.
+    NoSurface,
+    /// Output is rendered to the applied surface.
+    Rendering,
+    /// The surface is applied but nothing is shown, e.g. while the notification shade covers the
+    /// video.
+    Paused,
+}
+
+/// What a `MediaPlayerEvent` changed for the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceChange {
+    /// Nothing the decoder has to act on.
+    None,
+    /// The applied surface, if any, must no longer be rendered to.
+    Lost,
+    /// Rendering resumes after being paused for the given duration.
+    Resumed(Duration),
+}
+
+/// Tracks the surface and focus events from the Kotlin side. Kept for the whole session so that
+/// the render loop starts from where codec creation left off.
+pub struct SurfaceLifecycle {
+    applied: bool,
+    // Surfaces come and go in bursts during rotation so only the last one is kept
+    pending: Option<(GlobalRef, Instant)>,
+    // When output last stopped reaching a surface, cleared once one is applied
+    lost_at: Option<Instant>,
+    focus_lost_at: Option<Instant>,
+}
+
+impl SurfaceLifecycle {
+    /// Starts without a surface.
+    pub fn new() -> SurfaceLifecycle {
+        SurfaceLifecycle {
+            applied: false,
+            pending: None,
+            lost_at: Some(Instant::now()),
+            focus_lost_at: None,
+        }
+    }
+
+    /// What the decoder can do with its output right now.
+    pub fn state(&self) -> SurfaceState {
+        match (self.applied, self.focus_lost_at) {
+            (false, _) => SurfaceState::NoSurface,
+            (true, None) => SurfaceState::Rendering,
+            (true, Some(_)) => SurfaceState::Paused,
+        }
+    }
+
+    /// Transition on a surface or focus event. Other events change nothing.
+    pub fn on_event(&mut self, event: MediaPlayerEvent) -> SurfaceChange {
+        match event {
+            MediaPlayerEvent::SurfaceCreated(surface) => {
+                // Replaces the applied surface even before the new one is applied, the old one
+                // is about to go away
+                self.pending = Some((surface, Instant::now()));
+                self.lose();
+                SurfaceChange::Lost
+            }
+            MediaPlayerEvent::SurfaceDestroyed => {
+                self.pending = None;
+                self.lose();
+                SurfaceChange::Lost
+            }
+            MediaPlayerEvent::FocusChanged(false) => {
+                self.focus_lost_at.get_or_insert_with(Instant::now);
+                SurfaceChange::None
+            }
+            MediaPlayerEvent::FocusChanged(true) => match self.focus_lost_at.take() {
+                Some(t) => SurfaceChange::Resumed(t.elapsed()),
+                None => SurfaceChange::None,
+            },
+            MediaPlayerEvent::MainActivityDestroyed | MediaPlayerEvent::RotationChanged(_) => {
+                SurfaceChange::None
+            }
+        }
+    }
+
+    /// Take the pending surface if no other one arrived within `debounce`.
+    pub fn take_pending(&mut self, debounce: Duration) -> Option<GlobalRef> {
+        let settled = self
+            .pending
+            .as_ref()
+            .map_or(false, |(_, received)| received.elapsed() >= debounce);
+        if settled {
+            self.pending.take().map(|(surface, _)| surface)
+        } else {
+            None
+        }
+    }
+
+    /// Put back a surface that could not be applied for now, to be retried after another
+    /// debounce period.
+    pub fn retry(&mut self, surface: GlobalRef) {
+        self.pending.get_or_insert((surface, Instant::now()));
+    }
+
+    /// Record that the output goes to a surface again. Returns how long it did not.
+    pub fn applied(&mut self) -> Duration {
+        self.applied = true;
+        self.lost_at.take().map_or(Duration::ZERO, |t| t.elapsed())
+    }
+
+    /// How long the output has not reached a surface, `None` while it does.
+    pub fn without_surface_for(&self) -> Option<Duration> {
+        self.lost_at.map(|t| t.elapsed())
+    }
+
+    fn lose(&mut self) {
+        self.applied = false;
+        self.lost_at.get_or_insert_with(Instant::now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_without_surface() {
+        let lifecycle = SurfaceLifecycle::new();
+        assert_eq!(lifecycle.state(), SurfaceState::NoSurface);
+        assert!(lifecycle.without_surface_for().is_some());
+    }
+
+    #[test]
+    fn applied_surface_renders() {
+        let mut lifecycle = SurfaceLifecycle::new();
+        lifecycle.applied();
+        assert_eq!(lifecycle.state(), SurfaceState::Rendering);
+        assert_eq!(lifecycle.without_surface_for(), None);
+        // Nothing was lost so the next one starts counting from zero
+        assert_eq!(lifecycle.applied(), Duration::ZERO);
+    }
+
+    #[test]
+    fn focus_pauses_and_resumes() {
+        let mut lifecycle = SurfaceLifecycle::new();
+        lifecycle.applied();
+        let change = lifecycle.on_event(MediaPlayerEvent::FocusChanged(false));
+        assert_eq!(change, SurfaceChange::None);
+        assert_eq!(lifecycle.state(), SurfaceState::Paused);
+        // Repeated focus loss keeps the first time
+        lifecycle.on_event(MediaPlayerEvent::FocusChanged(false));
+        let change = lifecycle.on_event(MediaPlayerEvent::FocusChanged(true));
+        assert!(matches!(change, SurfaceChange::Resumed(_)));
+        assert_eq!(lifecycle.state(), SurfaceState::Rendering);
+        // Only the first regained focus resumes
+        let change = lifecycle.on_event(MediaPlayerEvent::FocusChanged(true));
+        assert_eq!(change, SurfaceChange::None);
+    }
+
+    #[test]
+    fn destroyed_surface_is_lost() {
+        let mut lifecycle = SurfaceLifecycle::new();
+        lifecycle.applied();
+        let change = lifecycle.on_event(MediaPlayerEvent::SurfaceDestroyed);
+        assert_eq!(change, SurfaceChange::Lost);
+        assert_eq!(lifecycle.state(), SurfaceState::NoSurface);
+        assert!(lifecycle.without_surface_for().is_some());
+        assert!(lifecycle.take_pending(Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn paused_without_surface_is_no_surface() {
+        let mut lifecycle = SurfaceLifecycle::new();
+        lifecycle.applied();
+        lifecycle.on_event(MediaPlayerEvent::FocusChanged(false));
+        lifecycle.on_event(MediaPlayerEvent::SurfaceDestroyed);
+        assert_eq!(lifecycle.state(), SurfaceState::NoSurface);
+        // Still paused once a surface is applied again
+        lifecycle.applied();
+        assert_eq!(lifecycle.state(), SurfaceState::Paused);
+    }
+
+    #[test]
+    fn other_events_change_nothing() {
+        let mut lifecycle = SurfaceLifecycle::new();
+        lifecycle.applied();
+        for event in [
+            MediaPlayerEvent::RotationChanged(90),
+            MediaPlayerEvent::MainActivityDestroyed,
+        ] {
+            assert_eq!(lifecycle.on_event(event), SurfaceChange::None);
+            assert_eq!(lifecycle.state(), SurfaceState::Rendering);
+        }
+    }
+}
@@ -1,25 +1,54 @@
+mod control;
 mod decoder;
 mod signaling;
 
 use crate::NativeLibSingleton;
 use futures_util::Future;
-use std::{pin::Pin, sync::Arc};
-use webrtc::data_channel::RTCDataChannel;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use webrtc::{
+    data_channel::RTCDataChannel, peer_connection::peer_connection_state::RTCPeerConnectionState,
+};
 use webrtc_helper::{peer::Role, WebRtcPeer};
 
+pub use self::signaling::{NegotiatedCodec, SignalingSecurity};
+
+/// Time allowed for the peer to reach `Connected` after signaling completes.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Interval between the health lines logged while a session is active.
+const HEALTH_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
 pub async fn start_webrtc(singleton: Arc<NativeLibSingleton>) {
+    let mut session = singleton.watch_session();
+    session.borrow_and_update();
+
     // TODO: Get from mDNS or something
     let addr = ([192, 168, 1, 253], 9090);
 
-    let signaler = match signaling::WebSocketSignaler::new(addr).await {
+    let policy = signaling::ReconnectPolicy::default();
+    let Some(security) = singleton.signaling_security() else {
+        report_connection_error(
+            &singleton,
+            "Encrypted signaling needs the host's certificate fingerprint",
+        );
+        return;
+    };
+    let mut signaler = match signaling::WebSocketSignaler::new(addr, &security, &policy).await {
         Ok(s) => s,
         Err(e) => {
             log::error!("Creation of WebSocket signaling channel failed: {e:?}");
+            if let signaling::WebSocketSignalerError::Certificate = e {
+                report_connection_error(&singleton, "The host's certificate was rejected");
+            }
             return;
         }
     };
 
-    let decoder_builder = match decoder::AndroidDecoderBuilder::new(singleton) {
+    let decoder_builder = match decoder::AndroidDecoderBuilder::new(singleton.clone()) {
         Ok(b) => b,
         Err(e) => {
             log::error!("Failed to initialize an Android decoder: {e:?}");
@@ -27,20 +56,201 @@ pub async fn start_webrtc(singleton: Arc<NativeLibSingleton>) {
         }
     };
 
+    signaler.set_rtcp_feedback(decoder_builder.rtcp_feedback());
+    let descriptions = signaler.session_descriptions();
+    let mut signaling_closed = signaler.closed_signal();
+
     let mut peer_builder = WebRtcPeer::builder(signaler, Role::Offerer);
+    let controls_singleton = singleton.clone();
     peer_builder
         .with_decoder(Box::new(decoder_builder))
-        .with_data_channel_handler(Box::new(controls_handler));
+        .with_data_channel_handler(Box::new(move |data_channel| {
+            controls_handler(data_channel, controls_singleton.clone())
+        }));
 
-    let Ok(peer) = peer_builder.build().await else {
-        log::error!("Failed to initialize a WebRTC connection");
-        return;
+    let negotiation = negotiate(
+        peer_builder.build(),
+        &mut signaling_closed,
+        CONNECTION_TIMEOUT,
+    );
+    let peer = match negotiation.await {
+        Ok(peer) => peer,
+        Err(NegotiationError::Failed) => {
+            log::error!("Failed to initialize a WebRTC connection");
+            return;
+        }
+        Err(NegotiationError::SignalingClosed) => {
+            log::error!("Signaling channel closed during negotiation");
+            report_connection_error(&singleton, "The host closed the connection");
+            return;
+        }
+        Err(NegotiationError::TimedOut) => {
+            log::error!("Timed out negotiating the WebRTC connection");
+            report_connection_error(&singleton, "Couldn't connect to the host");
+            return;
+        }
     };
-    peer.is_closed().await;
+
+    match tokio::time::timeout(CONNECTION_TIMEOUT, wait_until_connected(&peer)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(state)) => {
+            log::error!("WebRTC connection ended before connecting: {state}");
+            report_connection_error(&singleton, "Connection to the host failed");
+            return;
+        }
+        Err(_) => {
+            log::error!("Timed out waiting for the WebRTC connection");
+            report_connection_error(&singleton, "Couldn't connect to the host");
+            return;
+        }
+    }
+
+    if let Some(sdp) = descriptions.local() {
+        log::info!("Local SDP:\n{sdp}");
+    }
+    if let Some(sdp) = descriptions.remote() {
+        log::info!("Remote SDP:\n{sdp}");
+    }
+
+    wait_until_closed(&peer, &singleton, &mut session).await;
+}
+
+/// Why the peer couldn't be built.
+#[derive(Debug, PartialEq, Eq)]
+enum NegotiationError {
+    Failed,
+    SignalingClosed,
+    TimedOut,
+}
+
+/// Wait for the peer to be built. The builder waits on the remote's answer, don't let a dead
+/// signaling server hang it.
+async fn negotiate<T, E>(
+    build: impl Future<Output = Result<T, E>>,
+    signaling_closed: &mut signaling::ClosedSignal,
+    timeout: Duration,
+) -> Result<T, NegotiationError> {
+    tokio::select! {
+        result = build => result.map_err(|_| NegotiationError::Failed),
+        _ = signaling_closed.wait() => Err(NegotiationError::SignalingClosed),
+        _ = tokio::time::sleep(timeout) => Err(NegotiationError::TimedOut),
+    }
+}
+
+/// Wait for the peer to close or the session to be stopped, logging the connection state and
+/// decoder stats every `HEALTH_LOG_INTERVAL` so that a slowly degrading session leaves a trail.
+async fn wait_until_closed(
+    peer: &WebRtcPeer,
+    singleton: &NativeLibSingleton,
+    session: &mut tokio::sync::watch::Receiver<u64>,
+) {
+    let started = Instant::now();
+    let mut rendered = singleton.rendered_frames();
+    let mut health = tokio::time::interval(HEALTH_LOG_INTERVAL);
+    // The first tick completes immediately
+    health.tick().await;
+
+    // Pinned once so that a wakeup between ticks isn't lost by recreating the future
+    let closed = peer.is_closed();
+    tokio::pin!(closed);
+
+    loop {
+        tokio::select! {
+            _ = &mut closed => {
+                log::info!(
+                    "WebRTC connection closed after {}s: {}",
+                    started.elapsed().as_secs(),
+                    peer.connection_state()
+                );
+                return;
+            }
+            _ = session.changed() => {
+                log::info!("Session stopped");
+                return;
+            }
+            _ = health.tick() => {
+                let stats = singleton.stats();
+                let total = singleton.rendered_frames();
+                log::info!(
+                    "health: {}, {:.1} fps, {} in flight, latency {}ms, quality {}, {} stalls{}",
+                    peer.connection_state(),
+                    (total - rendered) as f64 / HEALTH_LOG_INTERVAL.as_secs_f64(),
+                    stats.frames_in_flight(),
+                    stats.decode_latency_us() / 1000,
+                    stats.quality(),
+                    stats.stalls(),
+                    if stats.is_degraded() { ", degraded" } else { "" },
+                );
+                rendered = total;
+            }
+        }
+    }
+}
+
+/// Wait for the peer to become `Connected`. Returns the offending state if the connection failed
+/// or was closed instead.
+pub async fn wait_until_connected(peer: &WebRtcPeer) -> Result<(), RTCPeerConnectionState> {
+    loop {
+        match peer.connection_state() {
+            RTCPeerConnectionState::Connected => return Ok(()),
+            state @ (RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) => {
+                return Err(state)
+            }
+            _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+fn report_connection_error(singleton: &NativeLibSingleton, message: &str) {
+    let result = singleton.with_env(|env| singleton.report_connection_error(env, message));
+    if let Err(e) = result {
+        log::error!("Failed to report connection error: {e}");
+    }
 }
 
 fn controls_handler(
-    _data_channel: Arc<RTCDataChannel>,
+    data_channel: Arc<RTCDataChannel>,
+    singleton: Arc<NativeLibSingleton>,
 ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
-    Box::pin(async {})
+    Box::pin(async move { control::handle_data_channel(data_channel, singleton) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc_helper::signaling::Signaler;
+
+    #[tokio::test]
+    async fn eof_during_negotiation_ends_it_early() {
+        // Hangs up right after the WebSocket handshake, before answering the offer
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            drop(ws_stream);
+        });
+
+        let signaler = signaling::WebSocketSignaler::new(
+            addr,
+            &SignalingSecurity::Plain,
+            &signaling::ReconnectPolicy::default(),
+        )
+        .await
+        .unwrap();
+        let mut signaling_closed = signaler.closed_signal();
+
+        // A builder that doesn't give up when its recv fails and keeps waiting for the answer
+        let build = async {
+            assert!(signaler.recv().await.is_err());
+            std::future::pending::<Result<(), ()>>().await
+        };
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            negotiate(build, &mut signaling_closed, CONNECTION_TIMEOUT),
+        )
+        .await;
+        assert_eq!(result, Ok(Err(NegotiationError::SignalingClosed)));
+    }
 }
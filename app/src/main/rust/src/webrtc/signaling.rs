@@ -1,46 +1,255 @@
+use crate::media::MimeType;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use std::net::SocketAddr;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ServerName,
+};
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::{
     net::{TcpSocket, TcpStream},
-    sync::Mutex,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch, Mutex,
+    },
 };
-use tokio_tungstenite::{tungstenite, WebSocketStream};
+use tokio_tungstenite::{tungstenite, Connector, MaybeTlsStream, WebSocketStream};
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecParameters;
 use webrtc_helper::signaling::{Message, Signaler};
 
 /// `Signaler` implementation using WebSocket. Mirrors the one in the server.
+///
+/// Outgoing messages are handed to a dedicated writer task through a channel so that a cancelled
+/// `send` can never leave the WebSocket sink locked.
 pub struct WebSocketSignaler {
-    tx: Mutex<SplitSink<WebSocketStream<TcpStream>, tungstenite::Message>>,
-    rx: Mutex<SplitStream<WebSocketStream<TcpStream>>>,
+    tx: UnboundedSender<tungstenite::Message>,
+    rx: Mutex<SplitStream<WsStream>>,
+    descriptions: Arc<SessionDescriptions>,
+    closed: Arc<watch::Sender<bool>>,
+    rtcp_feedback: Vec<(MimeType, &'static [&'static str])>,
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How the signaling WebSocket is secured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SignalingSecurity {
+    /// Plaintext `ws://`, only for trusted networks.
+    #[default]
+    Plain,
+    /// `wss://` with the server's certificate required to be exactly the one with this SHA-256
+    /// fingerprint. The host is addressed by its IP, which webpki can't validate a certificate
+    /// for, so there is no fallback to the public roots. Self-hosted servers usually have a
+    /// self-signed certificate anyway.
+    Tls { pinned_sha256: [u8; 32] },
+}
+
+impl SignalingSecurity {
+    fn scheme(&self) -> &'static str {
+        match self {
+            SignalingSecurity::Plain => "ws",
+            SignalingSecurity::Tls { .. } => "wss",
+        }
+    }
+
+    fn connector(&self) -> Connector {
+        match self {
+            SignalingSecurity::Plain => Connector::Plain,
+            SignalingSecurity::Tls { pinned_sha256 } => {
+                let config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier(*pinned_sha256)))
+                    .with_no_client_auth();
+                Connector::Rustls(Arc::new(config))
+            }
+        }
+    }
+
+    /// Parse a SHA-256 fingerprint written in hex, with or without the colons that `openssl` and
+    /// `keytool` print it with.
+    pub fn parse_fingerprint(s: &str) -> Option<[u8; 32]> {
+        let digits: Vec<u8> = s
+            .trim()
+            .chars()
+            .filter(|&c| c != ':')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()?;
+        if digits.len() != 64 {
+            return None;
+        }
+        let mut fingerprint = [0u8; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks_exact(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+        Some(fingerprint)
+    }
+}
+
+/// Accepts only the certificate with the pinned SHA-256 fingerprint. The handshake signatures are
+/// still checked against it so the server must hold its private key.
+struct PinnedCertVerifier([u8; 32]);
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        if digest.as_ref() == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "Certificate does not match the pinned fingerprint".to_owned(),
+            ))
+        }
+    }
+}
+
+/// How `WebSocketSignaler` retries connecting to the server.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Number of retries after the first failed attempt.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    /// Factor applied to the delay after each retry. Anything below 1, including NaN, is taken as
+    /// 1 so that the delay never shrinks.
+    pub multiplier: f64,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(250),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(4),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before each retry.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        // `f64::max` returns the other operand for NaN. `Duration::mul_f64` panics on negative or
+        // overflowing results, so the product is compared in seconds before converting back.
+        let multiplier = self.multiplier.max(1.0);
+        let max_delay = self.max_delay;
+        let mut delay = self.initial_delay.min(max_delay);
+        (0..self.max_attempts).map(move |_| {
+            let current = delay;
+            let next = delay.as_secs_f64() * multiplier;
+            delay = if next < max_delay.as_secs_f64() {
+                Duration::from_secs_f64(next)
+            } else {
+                max_delay
+            };
+            current
+        })
+    }
 }
 
 impl WebSocketSignaler {
-    /// Create a new `WebSocketSignaler`.
+    /// Create a new `WebSocketSignaler`, retrying the connection according to `policy`. A rejected
+    /// certificate is not retried. Fails with `Eof` once the retries run out.
     pub async fn new(
         addr: impl Into<SocketAddr> + 'static,
+        security: &SignalingSecurity,
+        policy: &ReconnectPolicy,
     ) -> Result<WebSocketSignaler, WebSocketSignalerError> {
         let addr: SocketAddr = addr.into();
-        let socket = TcpSocket::new_v4()?;
-        let tcp_stream = socket.connect(addr).await?;
+        let ws_stream =
+            connect_with_retries(addr, policy, || Self::connect(addr, security)).await?;
 
-        let (ws_stream, _response) =
-            tokio_tungstenite::client_async(format!("ws://{}", addr), tcp_stream).await?;
+        let (sink, rx) = ws_stream.split();
+        let (tx, msg_rx) = unbounded_channel();
+        let (closed, _) = watch::channel(false);
+        let closed = Arc::new(closed);
+        tokio::spawn(writer_task(sink, msg_rx, closed.clone()));
 
-        let (tx, rx) = ws_stream.split();
         Ok(WebSocketSignaler {
-            tx: Mutex::new(tx),
+            tx,
             rx: Mutex::new(rx),
+            descriptions: Arc::new(SessionDescriptions::default()),
+            closed,
+            rtcp_feedback: Vec::new(),
         })
     }
 
+    async fn connect(
+        addr: SocketAddr,
+        security: &SignalingSecurity,
+    ) -> Result<WsStream, WebSocketSignalerError> {
+        let socket = TcpSocket::new_v4()?;
+        let tcp_stream = socket.connect(addr).await?;
+
+        let url = format!("{}://{}", security.scheme(), addr);
+        let (ws_stream, _response) = tokio_tungstenite::client_async_tls_with_config(
+            url,
+            tcp_stream,
+            None,
+            Some(security.connector()),
+        )
+        .await
+        .map_err(handshake_error)?;
+        Ok(ws_stream)
+    }
+
+    /// Returns a handle that resolves once the WebSocket is closed. Remains valid after the
+    /// signaler has been moved into the peer.
+    pub fn closed_signal(&self) -> ClosedSignal {
+        ClosedSignal(self.closed.subscribe())
+    }
+
+    /// Returns a handle to the SDPs exchanged through this signaler. Remains valid after the
+    /// signaler has been moved into the peer.
+    pub fn session_descriptions(&self) -> Arc<SessionDescriptions> {
+        self.descriptions.clone()
+    }
+
+    /// Declare the RTCP feedback of each codec in the offers sent through this signaler, in case
+    /// the peer connection did not.
+    pub fn set_rtcp_feedback(&mut self, rtcp_feedback: Vec<(MimeType, &'static [&'static str])>) {
+        self.rtcp_feedback = rtcp_feedback;
+    }
+
     async fn recv_impl(&self) -> Result<Message, WebSocketSignalerError> {
+        let result = self.recv_message().await;
+        if let Err(WebSocketSignalerError::Eof | WebSocketSignalerError::Tungstenite) = result {
+            self.closed.send_replace(true);
+        }
+        result
+    }
+
+    async fn recv_message(&self) -> Result<Message, WebSocketSignalerError> {
+        let value = self.recv_value().await?;
+        let msg = serde_json::from_value::<Message>(value)?;
+        Ok(msg)
+    }
+
+    async fn recv_value(&self) -> Result<serde_json::Value, WebSocketSignalerError> {
         match self.rx.lock().await.next().await {
             Some(ws_msg) => match ws_msg?.to_text() {
                 Ok(s) => {
-                    let msg = serde_json::from_str::<Message>(s)?;
-                    Ok(msg)
+                    let value = serde_json::from_str::<serde_json::Value>(s)?;
+                    if let Some(sdp) = find_sdp(&value) {
+                        SessionDescriptions::store(&self.descriptions.remote, sdp);
+                    }
+                    Ok(value)
                 }
                 Err(_) => Err(WebSocketSignalerError::Serde),
             },
@@ -49,13 +258,311 @@ impl WebSocketSignaler {
     }
 
     async fn send_impl(&self, msg: Message) -> Result<(), WebSocketSignalerError> {
-        let s = serde_json::to_string(&msg)?;
+        self.send_value(serde_json::to_value(&msg)?)
+    }
+
+    fn send_value(&self, mut value: serde_json::Value) -> Result<(), WebSocketSignalerError> {
+        if let Some(sdp) = find_offer_sdp_mut(&mut value) {
+            *sdp = add_rtcp_feedback(sdp, &self.rtcp_feedback);
+        }
+        if let Some(sdp) = find_sdp(&value) {
+            SessionDescriptions::store(&self.descriptions.local, sdp);
+        }
+        let s = serde_json::to_string(&value)?;
         let ws_msg = tungstenite::Message::text(s);
-        self.tx.lock().await.send(ws_msg).await?;
+        // Fails only if the writer task has exited, i.e., the WebSocket is no longer usable
+        self.tx
+            .send(ws_msg)
+            .map_err(|_| WebSocketSignalerError::Eof)
+    }
+}
+
+/// Call `connect` until it succeeds, sleeping between attempts according to `policy`.
+async fn connect_with_retries<T, F, Fut>(
+    addr: SocketAddr,
+    policy: &ReconnectPolicy,
+    mut connect: F,
+) -> Result<T, WebSocketSignalerError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, WebSocketSignalerError>>,
+{
+    let mut delays = policy.delays();
+    let mut attempt = 1;
+    loop {
+        match connect().await {
+            Ok(stream) => return Ok(stream),
+            Err(WebSocketSignalerError::Certificate) => {
+                log::error!("The certificate of {addr} was rejected");
+                return Err(WebSocketSignalerError::Certificate);
+            }
+            Err(e) => match delays.next() {
+                Some(delay) => {
+                    log::info!(
+                        "Connection attempt {attempt} to {addr} failed ({e}), retrying in {}ms",
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => {
+                    log::error!("Giving up connecting to {addr} after {attempt} attempts: {e}");
+                    // The caller only needs to know the server is unreachable, the cause is logged
+                    return Err(WebSocketSignalerError::Eof);
+                }
+            },
+        }
+    }
+}
+
+/// Forwards queued messages to the WebSocket. Exits when the signaler is dropped or when the
+/// connection fails.
+async fn writer_task(
+    mut sink: SplitSink<WsStream, tungstenite::Message>,
+    mut msg_rx: UnboundedReceiver<tungstenite::Message>,
+    closed: Arc<watch::Sender<bool>>,
+) {
+    while let Some(ws_msg) = msg_rx.recv().await {
+        if let Err(e) = sink.send(ws_msg).await {
+            log::error!("Failed to send signaling message: {e}");
+            closed.send_replace(true);
+            break;
+        }
+    }
+    if let Err(e) = sink.close().await {
+        log::info!("Error closing the WebSocket: {e}");
+    }
+}
+
+/// The most recent local and remote SDPs that passed through the signaling channel.
+#[derive(Default)]
+pub struct SessionDescriptions {
+    local: std::sync::Mutex<Option<String>>,
+    remote: std::sync::Mutex<Option<String>>,
+}
+
+impl SessionDescriptions {
+    /// The last SDP sent to the remote peer.
+    pub fn local(&self) -> Option<String> {
+        self.local.lock().ok()?.clone()
+    }
+
+    /// The last SDP received from the remote peer.
+    pub fn remote(&self) -> Option<String> {
+        self.remote.lock().ok()?.clone()
+    }
+
+    fn store(slot: &std::sync::Mutex<Option<String>>, sdp: &str) {
+        if let Ok(mut lock_guard) = slot.lock() {
+            *lock_guard = Some(sdp.to_owned());
+        }
+    }
+}
+
+/// What was negotiated for a track. Read once from its codec parameters when decoding starts so
+/// that nothing else parses the SDP again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegotiatedCodec {
+    pub mime_type: MimeType,
+    pub payload_type: u8,
+    pub clock_rate: u32,
+    /// `profile_idc`, constraint flags and `level_idc` from the `profile-level-id` of H.264.
+    pub profile_level_id: Option<[u8; 3]>,
+    /// `packetization-mode` of H.264, 0 if absent as RFC 6184 specifies.
+    pub packetization_mode: Option<u8>,
+    pub resolution_hint: Option<(i32, i32)>,
+    pub frame_rate_hint: Option<f64>,
+}
+
+impl NegotiatedCodec {
+    /// Returns `None` if the MIME type is not one the client knows.
+    pub fn from_parameters(parameters: &RTCRtpCodecParameters) -> Option<NegotiatedCodec> {
+        let capability = &parameters.capability;
+        let mime_type = MimeType::from_str(&capability.mime_type).ok()?;
+        let fmtp_line = capability.sdp_fmtp_line.as_str();
+
+        let (profile_level_id, packetization_mode) = if mime_type == MimeType::VideoH264 {
+            let profile_level_id =
+                fmtp_param(fmtp_line, "profile-level-id").and_then(parse_profile_level_id);
+            let packetization_mode = fmtp_param(fmtp_line, "packetization-mode")
+                .and_then(|mode| mode.parse().ok())
+                .unwrap_or(0);
+            (profile_level_id, Some(packetization_mode))
+        } else {
+            (None, None)
+        };
+
+        Some(NegotiatedCodec {
+            mime_type,
+            payload_type: parameters.payload_type,
+            clock_rate: capability.clock_rate,
+            profile_level_id,
+            packetization_mode,
+            resolution_hint: resolution_hint(fmtp_line),
+            frame_rate_hint: frame_rate_hint(fmtp_line),
+        })
+    }
+}
+
+impl std::fmt::Display for NegotiatedCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} pt={} clock={}",
+            self.mime_type.to_android_str(),
+            self.payload_type,
+            self.clock_rate
+        )?;
+        if let Some([profile_idc, constraints, level_idc]) = self.profile_level_id {
+            write!(
+                f,
+                " profile-level-id={profile_idc:02x}{constraints:02x}{level_idc:02x}"
+            )?;
+        }
+        if let Some(mode) = self.packetization_mode {
+            write!(f, " packetization-mode={mode}")?;
+        }
+        if let Some((width, height)) = self.resolution_hint {
+            write!(f, " {width}x{height}")?;
+        }
+        if let Some(frame_rate) = self.frame_rate_hint {
+            write!(f, " {frame_rate}fps")?;
+        }
         Ok(())
     }
 }
 
+/// Value of `key` in an fmtp line of `key=value` pairs separated by semicolons.
+fn fmtp_param<'a>(fmtp_line: &'a str, key: &str) -> Option<&'a str> {
+    fmtp_line.split(';').find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        (name.trim() == key).then(|| value.trim())
+    })
+}
+
+/// Three bytes written as six hex digits, e.g. `42e01f`.
+fn parse_profile_level_id(value: &str) -> Option<[u8; 3]> {
+    if value.len() != 6 {
+        return None;
+    }
+    let mut bytes = [0; 3];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Reads the `x-resolution=<width>x<height>` parameter the host adds to the codec's fmtp line.
+fn resolution_hint(fmtp_line: &str) -> Option<(i32, i32)> {
+    fmtp_line.split(';').find_map(|param| {
+        let value = param.trim().strip_prefix("x-resolution=")?;
+        let (width, height) = value.split_once('x')?;
+        let width = width.parse().ok().filter(|&w: &i32| w > 0)?;
+        let height = height.parse().ok().filter(|&h: &i32| h > 0)?;
+        Some((width, height))
+    })
+}
+
+/// Reads the `max-fr=<frames per second>` parameter of the codec's fmtp line.
+fn frame_rate_hint(fmtp_line: &str) -> Option<f64> {
+    fmtp_line.split(';').find_map(|param| {
+        let value = param.trim().strip_prefix("max-fr=")?;
+        value
+            .parse()
+            .ok()
+            .filter(|&fr: &f64| fr.is_finite() && fr > 0.0)
+    })
+}
+
+/// Resolves once the signaling WebSocket has closed.
+pub struct ClosedSignal(watch::Receiver<bool>);
+
+impl ClosedSignal {
+    /// Wait until the WebSocket is closed or the signaler is dropped.
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Search the serialized message for a session description's `sdp` field.
+fn find_sdp(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(serde_json::Value::String(sdp)) = map.get("sdp") {
+                return Some(sdp);
+            }
+            map.values().find_map(find_sdp)
+        }
+        serde_json::Value::Array(array) => array.iter().find_map(find_sdp),
+        _ => None,
+    }
+}
+
+/// Search the serialized message for an offer's `sdp` field.
+fn find_offer_sdp_mut(value: &mut serde_json::Value) -> Option<&mut String> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let is_offer =
+                matches!(map.get("type"), Some(serde_json::Value::String(t)) if t == "offer");
+            // Checked before borrowing mutably so the borrow doesn't extend to the search below
+            if is_offer && matches!(map.get("sdp"), Some(serde_json::Value::String(_))) {
+                return match map.get_mut("sdp") {
+                    Some(serde_json::Value::String(sdp)) => Some(sdp),
+                    _ => None,
+                };
+            }
+            map.values_mut().find_map(find_offer_sdp_mut)
+        }
+        serde_json::Value::Array(array) => array.iter_mut().find_map(find_offer_sdp_mut),
+        _ => None,
+    }
+}
+
+/// Add an `a=rtcp-fb` line after each matching codec's `a=rtpmap` line for every feedback type
+/// that the SDP doesn't already declare.
+fn add_rtcp_feedback(sdp: &str, rtcp_feedback: &[(MimeType, &[&str])]) -> String {
+    let eol = if sdp.contains("\r\n") { "\r\n" } else { "\n" };
+    let existing: HashSet<&str> = sdp
+        .lines()
+        .filter(|line| line.starts_with("a=rtcp-fb:"))
+        .collect();
+
+    let mut out = String::with_capacity(sdp.len());
+    for line in sdp.lines() {
+        out.push_str(line);
+        out.push_str(eol);
+
+        // a=rtpmap:<payload type> <encoding name>/<clock rate>[/<channels>]
+        let Some((payload_type, encoding)) = line
+            .strip_prefix("a=rtpmap:")
+            .and_then(|rtpmap| rtpmap.split_once(' '))
+        else {
+            continue;
+        };
+        let encoding_name = encoding.split('/').next().unwrap_or_default();
+        let feedback = rtcp_feedback.iter().find(|(mime_type, _)| {
+            mime_type
+                .to_sdp_str()
+                .split_once('/')
+                .map_or(false, |(_, name)| name.eq_ignore_ascii_case(encoding_name))
+        });
+        if let Some((_, feedback)) = feedback {
+            for fb in feedback.iter() {
+                let fb_line = format!("a=rtcp-fb:{payload_type} {fb}");
+                if !existing.contains(fb_line.as_str()) {
+                    out.push_str(&fb_line);
+                    out.push_str(eol);
+                }
+            }
+        }
+    }
+    out
+}
+
 /// Errors that WebSocketSignaler can emit
 #[derive(Debug)]
 pub enum WebSocketSignalerError {
@@ -63,6 +570,32 @@ pub enum WebSocketSignalerError {
     Serde,
     StdIo,
     Eof,
+    Tls,
+    Certificate,
+}
+
+/// Tell TLS failures apart from other ones since a rejected certificate won't go away by retrying.
+fn handshake_error(e: tungstenite::Error) -> WebSocketSignalerError {
+    match e {
+        tungstenite::Error::Tls(_) => WebSocketSignalerError::Tls,
+        // tokio-rustls reports handshake failures as I/O errors
+        tungstenite::Error::Io(e) => match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        {
+            Some(
+                rustls::Error::InvalidCertificateData(_)
+                | rustls::Error::InvalidCertificateEncoding
+                | rustls::Error::InvalidCertificateSignature
+                | rustls::Error::InvalidCertificateSignatureType
+                | rustls::Error::UnsupportedNameType
+                | rustls::Error::NoCertificatesPresented,
+            ) => WebSocketSignalerError::Certificate,
+            Some(_) => WebSocketSignalerError::Tls,
+            None => WebSocketSignalerError::StdIo,
+        },
+        _ => WebSocketSignalerError::Tungstenite,
+    }
 }
 
 // The conversion only cares about the error type and discards the error details.
@@ -96,6 +629,12 @@ impl std::fmt::Display for WebSocketSignalerError {
             WebSocketSignalerError::Eof => {
                 write!(f, "WebSocket connection has been closed")
             }
+            WebSocketSignalerError::Tls => {
+                write!(f, "TLS handshake with the signaling server failed")
+            }
+            WebSocketSignalerError::Certificate => {
+                write!(f, "The signaling server's certificate was rejected")
+            }
         }
     }
 }
@@ -118,3 +657,184 @@ impl Signaler for WebSocketSignaler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delays_back_off_up_to_the_cap() {
+        let policy = ReconnectPolicy::default();
+        let delays: Vec<u64> = policy.delays().map(|d| d.as_millis() as u64).collect();
+        assert_eq!(delays, [250, 500, 1000, 2000, 4000]);
+
+        let policy = ReconnectPolicy {
+            max_attempts: 4,
+            initial_delay: Duration::from_secs(3),
+            multiplier: 3.0,
+            max_delay: Duration::from_secs(5),
+        };
+        let delays: Vec<u64> = policy.delays().map(|d| d.as_millis() as u64).collect();
+        assert_eq!(delays, [3000, 5000, 5000, 5000]);
+    }
+
+    #[test]
+    fn no_retries_without_attempts() {
+        let policy = ReconnectPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delays().count(), 0);
+    }
+
+    /// Accepts one WebSocket and sends every text message back.
+    async fn echo_server() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(ws_msg)) = ws_stream.next().await {
+                if ws_msg.is_text() && ws_stream.send(ws_msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn cancelled_sends_and_recvs_leave_the_signaler_usable() {
+        let addr = echo_server().await;
+        let signaler = Arc::new(
+            WebSocketSignaler::new(addr, &SignalingSecurity::Plain, &ReconnectPolicy::default())
+                .await
+                .unwrap(),
+        );
+
+        // Nothing has been sent, so this is dropped while waiting with the stream locked
+        let pending_recv = tokio::time::timeout(Duration::from_millis(50), signaler.recv_value());
+        assert!(pending_recv.await.is_err());
+
+        // Aborted wherever they happen to be, some before queueing and some after
+        for i in 0..16 {
+            let signaler = signaler.clone();
+            tokio::spawn(async move { signaler.send_value(serde_json::json!({ "cancelled": i })) })
+                .abort();
+        }
+
+        // A send while a recv is waiting must not need the recv's lock
+        let echoed = async {
+            loop {
+                let value = signaler.recv_value().await.unwrap();
+                if value.get("after").is_some() {
+                    break value;
+                }
+            }
+        };
+        let send = async { signaler.send_value(serde_json::json!({ "after": 1 })) };
+        let (received, sent) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(echoed, send) })
+                .await
+                .unwrap();
+        assert!(sent.is_ok());
+        assert_eq!(received, serde_json::json!({ "after": 1 }));
+
+        signaler
+            .send_value(serde_json::json!({ "again": 2 }))
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), signaler.recv_value())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, serde_json::json!({ "again": 2 }));
+    }
+
+    #[test]
+    fn multipliers_below_one_keep_the_delay() {
+        for multiplier in [0.5, 0.0, -2.0, f64::NAN, f64::NEG_INFINITY] {
+            let policy = ReconnectPolicy {
+                multiplier,
+                ..Default::default()
+            };
+            let delays: Vec<u64> = policy.delays().map(|d| d.as_millis() as u64).collect();
+            assert_eq!(delays, [250; 5], "multiplier {multiplier}");
+        }
+
+        let policy = ReconnectPolicy {
+            multiplier: f64::INFINITY,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = policy.delays().map(|d| d.as_millis() as u64).collect();
+        assert_eq!(delays, [250, 4000, 4000, 4000, 4000]);
+    }
+
+    fn test_addr() -> SocketAddr {
+        ([127, 0, 0, 1], 9090).into()
+    }
+
+    /// A connector that fails `failures` times before succeeding, noting when it was called.
+    fn flaky_connector(
+        failures: usize,
+        attempts: &std::sync::Mutex<Vec<tokio::time::Instant>>,
+    ) -> impl FnMut() -> std::future::Ready<Result<(), WebSocketSignalerError>> + '_ {
+        move || {
+            let mut attempts = attempts.lock().unwrap();
+            attempts.push(tokio::time::Instant::now());
+            if attempts.len() > failures {
+                std::future::ready(Ok(()))
+            } else {
+                std::future::ready(Err(WebSocketSignalerError::StdIo))
+            }
+        }
+    }
+
+    fn gaps_in_millis(attempts: &[tokio::time::Instant]) -> Vec<u64> {
+        attempts
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_millis() as u64)
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_follow_the_backoff_schedule() {
+        let attempts = std::sync::Mutex::new(Vec::new());
+        let result = connect_with_retries(
+            test_addr(),
+            &ReconnectPolicy::default(),
+            flaky_connector(3, &attempts),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(gaps_in_millis(&attempts.lock().unwrap()), [250, 500, 1000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_with_eof_after_the_last_retry() {
+        let attempts = std::sync::Mutex::new(Vec::new());
+        let result = connect_with_retries(
+            test_addr(),
+            &ReconnectPolicy::default(),
+            flaky_connector(usize::MAX, &attempts),
+        )
+        .await;
+        assert!(matches!(result, Err(WebSocketSignalerError::Eof)));
+        assert_eq!(
+            gaps_in_millis(&attempts.lock().unwrap()),
+            [250, 500, 1000, 2000, 4000]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_certificate_is_not_retried() {
+        let mut attempts = 0;
+        let result: Result<(), _> =
+            connect_with_retries(test_addr(), &ReconnectPolicy::default(), || {
+                attempts += 1;
+                std::future::ready(Err(WebSocketSignalerError::Certificate))
+            })
+            .await;
+        assert!(matches!(result, Err(WebSocketSignalerError::Certificate)));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,12 +1,16 @@
 use jni::{objects::JObject, JNIEnv};
-use ndk_sys::{ANativeWindow, ANativeWindow_fromSurface, ANativeWindow_release};
+use ndk_sys::{
+    ANativeWindow, ANativeWindow_acquire, ANativeWindow_fromSurface, ANativeWindow_getHeight,
+    ANativeWindow_getWidth, ANativeWindow_release,
+};
 use std::ptr::NonNull;
 
 /// RAII wrapper around [ANativeWindow].
 #[repr(transparent)]
 pub struct NativeWindow(NonNull<ANativeWindow>);
 
-// FIXME: Is this safe?
+// SAFETY: `ANativeWindow` is reference counted and its functions can be called from any thread.
+// Only the acquire/release of the reference is done here.
 unsafe impl Send for NativeWindow {}
 
 impl Drop for NativeWindow {
@@ -17,8 +21,21 @@ impl Drop for NativeWindow {
     }
 }
 
+/// Takes another reference to the same window.
+impl Clone for NativeWindow {
+    fn clone(&self) -> Self {
+        unsafe {
+            ANativeWindow_acquire(self.0.as_ptr());
+        }
+        NativeWindow(self.0)
+    }
+}
+
 impl NativeWindow {
-    /// Create a `NativeWindow` from the surface received from the `surfaceCreated` event.
+    /// Create a `NativeWindow` from an `android.view.Surface`.
+    ///
+    /// Nothing here depends on what produced the surface. It can come from a `SurfaceView` or wrap
+    /// a `SurfaceTexture` so that the video is available as an OpenGL texture.
     pub fn new(env: &JNIEnv, surface: &JObject) -> Option<Self> {
         NonNull::new(unsafe {
             ANativeWindow_fromSurface(env.get_native_interface(), surface.into_raw())
@@ -30,4 +47,16 @@ impl NativeWindow {
     pub fn as_inner(&self) -> *mut ANativeWindow {
         self.0.as_ptr()
     }
+
+    /// Width of the window buffers in pixels. Returns `None` if the window is not ready yet.
+    pub fn width(&self) -> Option<i32> {
+        let width = unsafe { ANativeWindow_getWidth(self.as_inner()) };
+        (width > 0).then_some(width)
+    }
+
+    /// Height of the window buffers in pixels. Returns `None` if the window is not ready yet.
+    pub fn height(&self) -> Option<i32> {
+        let height = unsafe { ANativeWindow_getHeight(self.as_inner()) };
+        (height > 0).then_some(height)
+    }
 }